@@ -0,0 +1,26 @@
+// `CertReloader` is an actor polling the TLS certificate files and swapping
+// in the new certificate once they change, no restart needed.
+
+use actix::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tls::CertificateStore;
+
+pub struct CertReloader {
+    pub store: Arc<CertificateStore>,
+    pub interval: Duration,
+}
+
+impl Actor for CertReloader {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.interval, |act, _| {
+            match act.store.reload_if_changed() {
+                Ok(true) => info!("Reloaded TLS certificate"),
+                Ok(false) => (),
+                Err(err) => error!("Cannot reload TLS certificate: {}", err),
+            }
+        });
+    }
+}
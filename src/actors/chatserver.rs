@@ -3,19 +3,77 @@
 // room through `ChatServer`.
 
 use actix::prelude::*;
+use actix_web::ws::CloseCode;
+use attachments::{Attachment, AttachmentError, Blob, BlobStore};
+use audit::{AuditAction, AuditEntry, AuditLog, AuditQuery};
+use automod::{self, AutoMod, AutomodError, Rule};
+use bots::{Bot, BotEvent, BotPost, BotRegistry};
+use chrono::Local;
+use commands::{self, Caller, CommandHost, CommandRegistry, Line, Permission, UserInfo};
+use config::Config;
+use controller::Controller;
+use external_data_source::UserDataInterface;
+use invites::{InviteError, InviteStore};
+use join_requests::{JoinOutcome, JoinRequest, JoinRequestError, JoinRequests};
+use mentions::{self, Mention};
+use message::{ChatMessage, MessageError};
+use mock_data::{MockRoomDataImpl, MockUserDataImpl};
+use protocol::{ErrorCode, ServerEvent};
 use rand::{self, Rng, ThreadRng};
+use ratelimit::{Penalty, RateLimiter, Verdict};
+use role::Role;
+use room::{RoomError, RoomSettings};
+use sanctions::{self, Sanction, SanctionKind, Sanctions};
+use search::{SearchIndex, SearchQuery, SearchResult};
+use server_bans::{ServerBan, ServerBans};
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use storage::Storage;
+use unread::{ReadMarkers, UnreadCount};
+use user::User;
+use uuid::Uuid;
+
+// How often pending changes are written to the storage backend, in seconds
+const FLUSH_INTERVAL: u64 = 5;
+
+// Mentions kept per offline user, the oldest are dropped first
+const MAX_UNREAD_MENTIONS: usize = 100;
+
+// Longest lifetime of an invite, in seconds
+const MAX_INVITE_LIFETIME: u64 = 30 * 24 * 60 * 60;
 
 // Message for chat server communications
 #[derive(Message)]
 pub struct SessionMessage(pub String);
 
+// Close the websocket of a session
+#[derive(Message)]
+pub struct SessionClose {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+// The room of a session got a new name
+#[derive(Message)]
+pub struct RoomRenamed {
+    pub from: String,
+    pub to: String,
+}
+
 // New chat session is created
 #[derive(Message)]
 #[rtype(usize)]
 pub struct Connect {
     pub addr: Recipient<Syn, SessionMessage>,
+    pub close: Recipient<Syn, SessionClose>,
+    pub rename: Recipient<Syn, RoomRenamed>,
+    // authenticated user behind the session
+    pub user_id: Uuid,
+    pub username: String,
+    pub ip: Option<IpAddr>,
 }
 
 // Session is disconnected
@@ -33,107 +91,2787 @@ pub struct Message {
     pub msg: String,
     // Room name
     pub room: String,
+    // Message the peer replies to
+    pub parent: Option<Uuid>,
+}
+
+// Replace the text of a message, only its author may
+#[derive(Message)]
+pub struct EditMessage {
+    // Id of the client session
+    pub session: usize,
+    pub id: Uuid,
+    pub text: String,
+}
+
+// Delete a message, allowed for its author and the moderators of the room
+#[derive(Message)]
+pub struct DeleteMessage {
+    // Id of the client session
+    pub session: usize,
+    pub id: Uuid,
+}
+
+// Send a thread with all its replies to the session
+#[derive(Message)]
+pub struct FetchThread {
+    // Id of the client session
+    pub session: usize,
+    pub id: Uuid,
+}
+
+// Follow or unfollow the replies of a thread
+#[derive(Message)]
+pub struct SubscribeThread {
+    // Id of the client session
+    pub session: usize,
+    pub id: Uuid,
+    // false unsubscribes
+    pub subscribe: bool,
+}
+
+// Change the settings of a room, allowed for its owner and moderators
+#[derive(Message)]
+pub struct UpdateRoom {
+    // Id of the client session
+    pub session: usize,
+    pub room: String,
+    // only the given settings change
+    pub topic: Option<String>,
+    pub name: Option<String>,
+    pub private: Option<bool>,
+    pub hidden: Option<bool>,
+}
+
+pub enum RoomAction {
+    // by username
+    AddModerator(String),
+    RemoveModerator(String),
+    TransferOwnership(String),
+    Delete,
+}
+
+// Owner or admin only operations on a room
+#[derive(Message)]
+pub struct ManageRoom {
+    // Id of the client session
+    pub session: usize,
+    pub room: String,
+    pub action: RoomAction,
+}
+
+pub enum InviteAction {
+    // zero uses means no limit
+    Create {
+        expires_in: Option<u64>,
+        max_uses: Option<u32>,
+    },
+    List,
+    // by code
+    Revoke(String),
+}
+
+// Moderator only operations on the invites of a room
+#[derive(Message)]
+pub struct ManageInvites {
+    // Id of the client session
+    pub session: usize,
+    pub room: String,
+    pub action: InviteAction,
+}
+
+pub enum AutomodAction {
+    Add(automod::Filter, automod::Action),
+    // by rule id
+    Remove(Uuid),
+    List,
+}
+
+// Moderator only operations on the automod rules of a room
+#[derive(Message)]
+pub struct ManageAutomod {
+    // Id of the client session
+    pub session: usize,
+    pub room: String,
+    pub action: AutomodAction,
+}
+
+// Join the room of an invite
+#[derive(Message)]
+pub struct RedeemInvite {
+    // Id of the client session
+    pub session: usize,
+    pub code: String,
+}
+
+// Ask the moderators of a private room to let the user in
+#[derive(Message)]
+pub struct RequestJoin {
+    // Id of the client session
+    pub session: usize,
+    pub room: String,
+}
+
+// Send the pending join requests of a room to a moderator
+#[derive(Message)]
+pub struct ListJoinRequests {
+    // Id of the client session
+    pub session: usize,
+    pub room: String,
+}
+
+// Approve or deny a join request, allowed for the moderators of the room
+#[derive(Message)]
+pub struct AnswerJoinRequest {
+    // Id of the client session
+    pub session: usize,
+    pub id: Uuid,
+    pub approve: bool,
+}
+
+// Ban or mute a member of a room, allowed for its moderators
+#[derive(Message)]
+pub struct SanctionMember {
+    // Id of the client session
+    pub session: usize,
+    pub room: String,
+    pub username: String,
+    pub kind: SanctionKind,
+    // seconds, none lasts until lifted
+    pub duration: Option<u64>,
+    pub reason: Option<String>,
+}
+
+// Unban or unmute a member of a room before the sanction expires
+#[derive(Message)]
+pub struct LiftSanction {
+    // Id of the client session
+    pub session: usize,
+    pub room: String,
+    pub username: String,
+    pub kind: SanctionKind,
+}
+
+// Remove a user from a room without a ban, allowed for its moderators
+#[derive(Message)]
+pub struct KickMember {
+    // Id of the client session
+    pub session: usize,
+    pub room: String,
+    pub username: String,
+    pub reason: Option<String>,
+}
+
+// Ban a user from the whole server, allowed for admins
+#[derive(Message)]
+pub struct BanFromServer {
+    // Id of the client session
+    pub session: usize,
+    pub username: String,
+    // seconds, none lasts until lifted
+    pub duration: Option<u64>,
+    pub reason: Option<String>,
+    // also block the addresses of the user's open sessions
+    pub block_ip: bool,
+}
+
+// Lift a server ban before it expires
+#[derive(Message)]
+pub struct LiftServerBan {
+    // Id of the client session
+    pub session: usize,
+    pub username: String,
+}
+
+// List the server bans, allowed for admins
+#[derive(Message)]
+pub struct ListServerBans {
+    // Id of the client session
+    pub session: usize,
+}
+
+// Advance the read marker of the session's user in a room
+#[derive(Message)]
+pub struct MarkRead {
+    // Id of the client session
+    pub session: usize,
+    pub room: String,
+    pub id: Uuid,
+    // send a read receipt to the room
+    pub receipt: bool,
+}
+
+// Send the unread counts of all rooms of the user to the session
+#[derive(Message)]
+pub struct UnreadCounts {
+    // Id of the client session
+    pub session: usize,
+}
+
+// Add or remove an emoji reaction of the session's user
+#[derive(Message)]
+pub struct React {
+    // Id of the client session
+    pub session: usize,
+    pub id: Uuid,
+    pub emoji: String,
+    // false removes the reaction
+    pub add: bool,
+}
+
+// A file was written to the blob store and is shared with a room
+pub struct AttachmentUploaded {
+    // Id of the client session, none for http uploads
+    pub session: Option<usize>,
+    pub user_id: Uuid,
+    pub username: String,
+    pub room: String,
+    // id chosen by the client for websocket uploads
+    pub upload_id: Option<Uuid>,
+    pub filename: String,
+    pub blob: Blob,
+}
+
+impl actix::Message for AttachmentUploaded {
+    type Result = Result<Attachment, AttachmentError>;
+}
+
+// Look up an attachment for download, only members of its room may see it
+pub struct FetchAttachment {
+    pub user_id: Uuid,
+    pub id: Uuid,
+}
+
+impl actix::Message for FetchAttachment {
+    type Result = Result<Attachment, AttachmentError>;
+}
+
+// Search the messages of all rooms the user is a member of
+pub struct Search {
+    pub user_id: Uuid,
+    pub query: SearchQuery,
+}
+
+impl actix::Message for Search {
+    type Result = Result<Vec<SearchResult>, ()>;
+}
+
+// Query the audit log, admins see everything, moderators the entries of their rooms
+pub struct QueryAudit {
+    pub user_id: Uuid,
+    pub query: AuditQuery,
+}
+
+impl actix::Message for QueryAudit {
+    type Result = Result<Vec<AuditEntry>, RoomError>;
+}
+
+// Server goes down, close all sessions and flush pending writes
+#[derive(Message)]
+pub struct Shutdown;
+
+// Check user credentials, answers with the user on success
+pub struct Authenticate {
+    pub username: String,
+    pub password: String,
+}
+
+impl actix::Message for Authenticate {
+    type Result = Option<User>;
+}
+
+pub type ChatController = Controller<MockUserDataImpl, MockRoomDataImpl>;
+
+// A connected client session
+struct Session {
+    addr: Recipient<Syn, SessionMessage>,
+    close: Recipient<Syn, SessionClose>,
+    rename: Recipient<Syn, RoomRenamed>,
+    user_id: Uuid,
+    username: String,
+    ip: Option<IpAddr>,
 }
 
 // `ChatServer` manages chat rooms and responsible for coordinating chat session.
 // implementation is super primitive
 pub struct ChatServer {
-    sessions: HashMap<usize, Recipient<Syn, SessionMessage>>,
+    config: Arc<Config>,
+    sessions: HashMap<usize, Session>,
     rooms: HashMap<String, HashSet<usize>>,
+    // sessions following a thread, by id of its first message
+    threads: HashMap<Uuid, HashSet<usize>>,
+    // last messages per room, replayed to joining sessions
+    history: HashMap<String, VecDeque<ChatMessage>>,
+    // mentions of users without a session, delivered on their next connect
+    unread_mentions: HashMap<Uuid, VecDeque<Mention>>,
+    // last read message per user and room
+    read_markers: ReadMarkers,
+    // full text index of all messages, also those dropped from the history
+    search: SearchIndex,
+    // files shared in rooms, the content lives in the blob store
+    attachments: HashMap<Uuid, Attachment>,
+    blobs: BlobStore,
+    invites: InviteStore,
+    // knocks on private rooms waiting for a moderator
+    join_requests: JoinRequests,
+    // bans and mutes with their reason and expiry
+    sanctions: Sanctions,
+    // moderation actions, append only
+    audit: AuditLog,
+    // filters run on messages before they are broadcast
+    automod: AutoMod,
+    // in-process bots, each posting as a user of its own
+    bots: BotRegistry,
+    // slash commands, shared with the commands while they run
+    commands: Arc<CommandRegistry>,
+    // shared with the http workers, which turn banned users away
+    bans: Arc<ServerBans>,
+    controller: ChatController,
+    rate_limiter: RateLimiter,
+    storage: Storage,
+    // storage keys with changes not yet written
+    dirty: HashSet<&'static str>,
+    // no new sessions are accepted once the server shuts down
+    draining: bool,
     rng: RefCell<ThreadRng>,
 }
 
-impl Default for ChatServer {
-    fn default() -> ChatServer {
+impl ChatServer {
+    pub fn new(
+        config: Arc<Config>,
+        storage: Storage,
+        bans: Arc<ServerBans>,
+        blobs: BlobStore,
+    ) -> ChatServer {
         // default room
         let mut rooms = HashMap::new();
-        rooms.insert("Main".to_owned(), HashSet::new());
+        rooms.insert(config.default_room.clone(), HashSet::new());
+
+        let history = storage.load("messages").unwrap_or_else(HashMap::new);
+        let attachments = storage.load("attachments").unwrap_or_else(HashMap::new);
+        let unread_mentions = storage.load("mentions").unwrap_or_else(HashMap::new);
+        let read_markers = storage.load("read_markers").unwrap_or_else(ReadMarkers::new);
+        let mut invites: InviteStore = storage.load("invites").unwrap_or_else(InviteStore::new);
+        let mut join_requests: JoinRequests =
+            storage.load("join_requests").unwrap_or_else(JoinRequests::new);
+        let mut sanctions: Sanctions = storage.load("sanctions").unwrap_or_else(Sanctions::new);
+        let audit = storage.load("audit").unwrap_or_else(AuditLog::new);
+        let automod_rules: HashMap<String, Vec<Rule>> =
+            storage.load("automod").unwrap_or_else(HashMap::new);
+        let search = storage
+            .load("search")
+            .map(SearchIndex::from_documents)
+            .unwrap_or_else(SearchIndex::new);
+
+        let mut user_data = MockUserDataImpl::new();
+        let users = user_data.provide_user_data();
+        let room_data = MockRoomDataImpl::new(&users);
+        let mut controller = Controller::new(user_data, room_data);
+        if controller.find_room_by_name(&config.default_room).is_none() {
+            // the default room belongs to nobody
+            controller.generate_room(config.default_room.clone(), Uuid::nil());
+        }
+        // the only admin is the one configured, it is created again on every start
+        if let (Some(username), Some(password)) = (
+            config.auth.admin_username.clone(),
+            config.auth.admin_password.clone(),
+        ) {
+            let mut admin = User::new(String::new(), username.clone(), username.clone(), password);
+            admin.grant_role(&Role::generate_admin());
+            if !controller.add_user(admin) {
+                error!("Cannot create admin {}, the username is taken", username);
+            }
+        }
+        controller.restore_sanctions(&mut sanctions);
+        bans.replace(storage.load("server_bans").unwrap_or_else(Vec::new));
+        controller.restore_server_bans(&bans);
+        // invites, join requests and automod rules find their room by name
+        invites.rebind(|name| controller.find_room_by_name(name).map(|room| room.copy_id()));
+        join_requests.rebind(|room, username| {
+            let room = controller.find_room_by_name(room)?.copy_id();
+            let user = controller.find_user_by_username(username)?.copy_id();
+            Some((room, user))
+        });
+        let automod = AutoMod::from_named_rules(automod_rules, |name| {
+            controller.find_room_by_name(name).map(|room| room.copy_id())
+        });
+        let rate_limiter = RateLimiter::new(config.rate_limit.clone());
 
         ChatServer {
+            config: config,
             sessions: HashMap::new(),
             rooms: rooms,
+            threads: HashMap::new(),
+            history: history,
+            unread_mentions: unread_mentions,
+            read_markers: read_markers,
+            search: search,
+            attachments: attachments,
+            blobs: blobs,
+            invites: invites,
+            join_requests: join_requests,
+            sanctions: sanctions,
+            audit: audit,
+            automod: automod,
+            bots: BotRegistry::new(),
+            commands: Arc::new(CommandRegistry::with_builtins()),
+            bans: bans,
+            controller: controller,
+            rate_limiter: rate_limiter,
+            storage: storage,
+            dirty: HashSet::new(),
+            draining: false,
             rng: RefCell::new(rand::thread_rng()),
         }
     }
-}
 
-impl ChatServer {
+    // Add an in-process bot, its user is created on the fly
+    pub fn register_bot(&mut self, bot: Box<dyn Bot>) {
+        if self.controller.find_user_by_username(bot.name()).is_some() {
+            error!("Cannot register bot {}, the username is taken", bot.name());
+            return;
+        }
+        let user = self.bots.register(bot);
+        info!("Registered bot {}", user.get_username());
+        self.controller.add_user(user);
+    }
+
+    // Run a slash command sent to `room` and answer the session
+    fn run_command(
+        &mut self,
+        session: usize,
+        room: &str,
+        name: &str,
+        args: &str,
+        ctx: &mut Context<Self>,
+    ) {
+        let (user_id, username) = match self.sessions.get(&session) {
+            Some(session) => (session.user_id, session.username.clone()),
+            None => return,
+        };
+        let room_id = self.controller
+            .find_room_by_name(room)
+            .map(|room| room.copy_id());
+        let permission = if self.controller.is_admin(&user_id) {
+            Permission::Admin
+        } else if room_id.map_or(false, |room_id| {
+            self.controller.check_room_manager(&room_id, &user_id).is_ok()
+        }) {
+            Permission::Moderator
+        } else if self.is_room_member(room, &user_id) {
+            Permission::Member
+        } else {
+            Permission::Guest
+        };
+        let caller = Caller {
+            session: session,
+            user_id: user_id,
+            username: username,
+            room: room.to_string(),
+            permission: permission,
+        };
+        let registry = Arc::clone(&self.commands);
+        let result = registry.dispatch(
+            &mut CommandSession {
+                server: self,
+                ctx: ctx,
+            },
+            &caller,
+            name,
+            args,
+        );
+        let event = match result {
+            Ok(Some(text)) => ServerEvent::CommandReply {
+                command: name.to_string(),
+                text: text,
+            },
+            Ok(None) => return,
+            Err(err) => ServerEvent::error(err.code(), &err.message()),
+        };
+        self.send_to_session(session, &event.to_json());
+    }
+
+    // Hand a room event to the subscribed bots and post their answers
+    fn notify_bots(&mut self, event: BotEvent) {
+        for (user_id, username, post) in self.bots.dispatch(&event) {
+            self.post_as_bot(user_id, username, post);
+        }
+    }
+
+    // Bots join public rooms when they first post there, banned or muted bots stay silent
+    fn post_as_bot(&mut self, user_id: Uuid, username: String, post: BotPost) {
+        let public = self.controller
+            .find_room_by_name(&post.room)
+            .map_or(false, |room| !room.is_private());
+        if public {
+            self.ensure_member(&post.room, user_id);
+        }
+        let allowed = self.controller
+            .find_room_by_name(&post.room)
+            .map_or(false, |room| {
+                room.has_member(&user_id) && !room.is_member_muted(&user_id)
+            });
+        if !allowed {
+            warn!("Bot {} may not post in {}", username, post.room);
+            return;
+        }
+        // bots go through the automod rules too, they have no session to mute
+        let (text, flags) = match self.automod_verdict(&post.room, &user_id, &post.text) {
+            None => (post.text, Vec::new()),
+            Some((_, automod::Verdict::Pass { text, flags })) => (text, flags),
+            Some(_) => {
+                warn!("Automod dropped a post of bot {} in {}", username, post.room);
+                return;
+            }
+        };
+        let message = ChatMessage::new(post.room, user_id, username, text);
+        self.flag_message(&message, flags);
+        self.post_message(message);
+    }
+
+    // Whether any session of the user is in the room
+    fn user_present(&self, room: &str, user_id: &Uuid) -> bool {
+        self.rooms.get(room).map_or(false, |sessions| {
+            sessions.iter().any(|id| {
+                self.sessions
+                    .get(id)
+                    .map_or(false, |session| session.user_id == *user_id)
+            })
+        })
+    }
+
     // Send message to all users in the room
     fn send_message(&self, room: &str, message: &str) {
         if let Some(sessions) = self.rooms.get(room) {
             for id in sessions {
-                if let Some(addr) = self.sessions.get(id) {
-                    let _ = addr.do_send(SessionMessage(message.to_owned()));
+                if let Some(session) = self.sessions.get(id) {
+                    let _ = session.addr.do_send(SessionMessage(message.to_owned()));
                 }
             }
         }
     }
-}
 
-// Make actor from `ChatServer`
-impl Actor for ChatServer {
-    // We are going to use simple Context, we just need ability to communicate
-    // with other actors.
-    type Context = Context<Self>;
-}
+    // Send a message to a single session
+    fn send_to_session(&self, id: usize, message: &str) {
+        if let Some(session) = self.sessions.get(&id) {
+            let _ = session.addr.do_send(SessionMessage(message.to_owned()));
+        }
+    }
 
-// Handler for Connect message.
-//
-// Register new session and assign unique id to this session
-impl Handler<Connect> for ChatServer {
-    type Result = usize;
+    // Apply the flood limits to a message of the session, false if it must be dropped
+    fn check_rate_limit(&mut self, id: usize) -> bool {
+        let user_id = match self.sessions.get(&id) {
+            Some(session) => session.user_id,
+            None => return false,
+        };
 
-    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
-        println!("Someone joined");
+        match self.rate_limiter.check(id, user_id, Instant::now()) {
+            Verdict::Allowed => return true,
+            Verdict::Limited => {
+                let event = ServerEvent::error(
+                    ErrorCode::RateLimited,
+                    "You are sending messages too fast, message dropped",
+                );
+                self.send_to_session(id, &event.to_json());
+            }
+            Verdict::Muted(remaining) => {
+                let event = ServerEvent::error(
+                    ErrorCode::Muted,
+                    &format!("You are muted for {} more seconds", remaining.as_secs() + 1),
+                );
+                self.send_to_session(id, &event.to_json());
+            }
+            Verdict::Penalized(Penalty::Mute) => {
+                let event = ServerEvent::error(
+                    ErrorCode::Muted,
+                    &format!(
+                        "You are muted for {} seconds for flooding",
+                        self.config.rate_limit.mute_duration
+                    ),
+                );
+                self.send_to_session(id, &event.to_json());
+            }
+            Verdict::Penalized(Penalty::Disconnect) => {
+                if let Some(session) = self.sessions.get(&id) {
+                    let _ = session.close.do_send(SessionClose {
+                        code: CloseCode::Policy,
+                        reason: "disconnected for flooding".to_owned(),
+                    });
+                }
+            }
+        }
+        false
+    }
 
-        let joinmsg = "{\"message\": \"someone joined\", \"name\":\"server\"}";
-        //let joinmsg = json::to_string(&msg.0).unwrap();
-        // notify all users in same room
-        self.send_message(&"Main".to_owned(), &joinmsg);
+    // Remember a message of the room, only the last `history_size` are kept
+    fn record_history(&mut self, message: ChatMessage) {
+        self.search.index(&message);
+        self.dirty.insert("search");
 
-        // register session with random id
-        let id = self.rng.borrow_mut().gen::<usize>();
-        self.sessions.insert(id, msg.addr);
+        let limit = self.config.history_size;
+        let history = self.history
+            .entry(message.room.clone())
+            .or_insert_with(VecDeque::new);
+        history.push_back(message);
+        while history.len() > limit {
+            history.pop_front();
+        }
+        self.dirty.insert("messages");
+    }
 
-        // auto join session to Main room
-        self.rooms.get_mut(&"Main".to_owned()).unwrap().insert(id);
+    // Post a message that starts a thread, after the checks for the room
+    fn post_top_level(
+        &mut self,
+        session: usize,
+        mut message: ChatMessage,
+        ctx: &mut Context<Self>,
+    ) {
+        // kicked users still have the room in their session
+        if !self.is_room_member(&message.room, &message.author) {
+            self.send_message_error(session, MessageError::NotAllowed)
+        } else if self.check_sanctions(session, &message.room, &message.author) {
+            let room = message.room.clone();
+            if let Some((text, flags)) =
+                self.moderate(session, &room, message.author, &message.text, ctx)
+            {
+                message.text = text;
+                self.flag_message(&message, flags);
+                self.post_message(message)
+            }
+        }
+    }
 
-        // send id back
-        id
+    // Record a new message and send it to the room
+    fn post_message(&mut self, mut message: ChatMessage) {
+        message.mentions = self.resolve_mentions(&message);
+        let event = ServerEvent::Message {
+            message: message.clone(),
+        }.to_json();
+        self.send_message(&message.room, &event);
+        self.notify_mentions(&message);
+        self.record_history(message.clone());
+        self.notify_bots(BotEvent::Message(message));
     }
-}
 
-// Handler for Disconnect message.
-impl Handler<Disconnect> for ChatServer {
-    type Result = ();
+    // Members of the room mentioned in the message, except its author
+    fn resolve_mentions(&mut self, message: &ChatMessage) -> Vec<Uuid> {
+        let mut mentioned = Vec::new();
+        for name in mentions::parse_mentions(&message.text) {
+            let user_id = match self.controller.find_user_by_username(&name) {
+                Some(user) => user.copy_id(),
+                None => continue,
+            };
+            if user_id != message.author && !mentioned.contains(&user_id)
+                && self.is_room_member(&message.room, &user_id)
+            {
+                mentioned.push(user_id);
+            }
+        }
+        mentioned
+    }
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        println!("Someone disconnected");
+    // Tell mentioned users about the message in whatever room they are,
+    // users without a session get it on their next connect
+    fn notify_mentions(&mut self, message: &ChatMessage) {
+        if message.mentions.is_empty() {
+            return;
+        }
+        let event = ServerEvent::Mention {
+            message: message.clone(),
+        }.to_json();
 
-        let dcmsg = "{\"message\": \"someone disconnected\", \"name\":\"server\"}";
-        let mut rooms: Vec<String> = Vec::new();
+        for user_id in &message.mentions {
+            let mut online = false;
+            for session in self.sessions.values() {
+                if session.user_id == *user_id {
+                    let _ = session.addr.do_send(SessionMessage(event.clone()));
+                    online = true;
+                }
+            }
+            if online {
+                continue;
+            }
+            let unread = self.unread_mentions
+                .entry(*user_id)
+                .or_insert_with(VecDeque::new);
+            unread.push_back(Mention::new(message));
+            while unread.len() > MAX_UNREAD_MENTIONS {
+                unread.pop_front();
+            }
+            self.dirty.insert("mentions");
+        }
+    }
 
-        // remove address
-        if self.sessions.remove(&msg.id).is_some() {
-            // remove session from all rooms
-            for (name, sessions) in &mut self.rooms {
-                if sessions.remove(&msg.id) {
-                    rooms.push(name.to_owned());
+    // Run the automod rules of the room on a text, none if it must not be sent.
+    // Answers the text to send and the matching flag rules, the moderators of
+    // the room are exempt.
+    fn moderate(
+        &mut self,
+        session: usize,
+        room: &str,
+        user_id: Uuid,
+        text: &str,
+        ctx: &mut Context<Self>,
+    ) -> Option<(String, Vec<Uuid>)> {
+        let (room_id, verdict) = match self.automod_verdict(room, &user_id, text) {
+            Some(checked) => checked,
+            None => return Some((text.to_owned(), Vec::new())),
+        };
+        match verdict {
+            automod::Verdict::Pass { text, flags } => Some((text, flags)),
+            automod::Verdict::Block(_) => {
+                let event = ServerEvent::error(
+                    ErrorCode::MessageBlocked,
+                    "Message blocked by the rules of the room",
+                );
+                self.send_to_session(session, &event.to_json());
+                None
+            }
+            automod::Verdict::Mute(rule, seconds) => {
+                self.auto_mute(room_id, room, user_id, rule, seconds, ctx);
+                None
+            }
+        }
+    }
+
+    // The verdict of the automod rules of the room on a text with the id of the
+    // room, none if the room has no rules or the author manages it
+    fn automod_verdict(
+        &mut self,
+        room: &str,
+        user_id: &Uuid,
+        text: &str,
+    ) -> Option<(Uuid, automod::Verdict)> {
+        let room_id = self.controller.find_room_by_name(room)?.copy_id();
+        if self.automod.rules(&room_id).is_empty()
+            || self.controller.check_room_manager(&room_id, user_id).is_ok()
+        {
+            return None;
+        }
+        Some((room_id, self.automod.check(&room_id, text)))
+    }
+
+    // Mute a member whose message an automod rule caught
+    fn auto_mute(
+        &mut self,
+        room_id: Uuid,
+        room: &str,
+        user_id: Uuid,
+        rule: Uuid,
+        seconds: u64,
+        ctx: &mut Context<Self>,
+    ) {
+        self.controller.mute_member(&room_id, user_id);
+        // issued by nobody, the rule names its creator
+        let sanction = Sanction::new(
+            room_id,
+            user_id,
+            SanctionKind::Mute,
+            Uuid::nil(),
+            Some(seconds),
+            Some("caught by an automod rule".to_string()),
+            Local::now(),
+        );
+        self.schedule_sanction_expiry(&sanction, ctx);
+        let event = ServerEvent::Sanctioned {
+            room: room.to_owned(),
+            sanction: sanction.clone(),
+        };
+        self.send_to_members(room, &event.to_json());
+
+        let mut entry = AuditEntry::for_room(
+            AuditAction::Mute,
+            None,
+            room_id,
+            room.to_owned(),
+            Some(user_id),
+            sanction.issued_at,
+        );
+        entry.reason = sanction.reason.clone();
+        entry.details = Some(format!("automod rule {}", rule));
+        self.record_audit(entry);
+        self.sanctions.add(sanction);
+        self.dirty.insert("sanctions");
+    }
+
+    // Show a message that matched flag rules to the moderators of its room
+    fn flag_message(&mut self, message: &ChatMessage, rules: Vec<Uuid>) {
+        if rules.is_empty() {
+            return;
+        }
+        let room_id = match self.controller.find_room_by_name(&message.room) {
+            Some(room) => room.copy_id(),
+            None => return,
+        };
+        let event = ServerEvent::MessageFlagged {
+            message: message.clone(),
+            rules: rules,
+        };
+        self.send_to_managers(&room_id, &event.to_json());
+    }
+
+    // Record a reply, its content only goes to the sessions following the thread
+    fn post_reply(
+        &mut self,
+        session: usize,
+        parent: Uuid,
+        mut message: ChatMessage,
+        ctx: &mut Context<Self>,
+    ) {
+        // replies to replies join the thread of their parent
+        let (thread, room) = match self.find_message(&parent) {
+            Some(found) if found.deleted => {
+                return self.send_message_error(session, MessageError::Deleted)
+            }
+            Some(found) => (found.thread_id(), found.room.clone()),
+            None => return self.send_message_error(session, MessageError::UnknownMessage),
+        };
+        if !self.is_room_member(&room, &message.author) {
+            return self.send_message_error(session, MessageError::NotAllowed);
+        }
+        if !self.check_sanctions(session, &room, &message.author) {
+            return;
+        }
+        let (text, flags) = match self.moderate(session, &room, message.author, &message.text, ctx)
+        {
+            Some(moderated) => moderated,
+            None => return,
+        };
+        message.text = text;
+        message.room = room.clone();
+        message.parent = Some(thread);
+        message.mentions = self.resolve_mentions(&message);
+
+        let updated = match self.find_message_mut(&thread) {
+            Some(first) => {
+                first.add_reply(&message);
+                ServerEvent::ThreadUpdated {
+                    id: thread,
+                    room: room.clone(),
+                    reply_count: first.reply_count,
+                    last_reply_at: first.last_reply_at,
                 }
             }
+            None => return self.send_message_error(session, MessageError::UnknownMessage),
+        };
+        self.record_history(message.clone());
+        self.notify_mentions(&message);
+        self.flag_message(&message, flags);
+
+        // writing to a thread follows it
+        self.threads
+            .entry(thread)
+            .or_insert_with(HashSet::new)
+            .insert(session);
+        // followers that were kicked or banned since stop getting replies
+        let followers: Vec<usize> = self.threads[&thread]
+            .iter()
+            .filter(|&id| {
+                self.sessions
+                    .get(id)
+                    .map_or(false, |session| self.is_room_member(&room, &session.user_id))
+            })
+            .cloned()
+            .collect();
+        self.threads.insert(thread, followers.iter().cloned().collect());
+        let event = ServerEvent::Message {
+            message: message.clone(),
+        }.to_json();
+        for id in followers {
+            self.send_to_session(id, &event);
         }
-        // send message to other users
-        for room in rooms {
-            self.send_message(&room, &dcmsg);
+        self.send_message(&room, &updated.to_json());
+        self.notify_bots(BotEvent::Message(message));
+    }
+
+    // Unread counts of the user in all rooms with history the user is a member of
+    fn unread_counts(&self, user_id: &Uuid) -> Vec<UnreadCount> {
+        let mut counts: Vec<UnreadCount> = self.history
+            .iter()
+            .filter(|&(room, _)| self.is_room_member(room, user_id))
+            .map(|(room, history)| self.read_markers.count(user_id, room, history))
+            .collect();
+        counts.sort_by(|a, b| a.room.cmp(&b.room));
+        counts
+    }
+
+    // Send a message to all sessions of members of the room, whatever room they are in
+    fn send_to_members(&self, room: &str, message: &str) {
+        for session in self.sessions.values() {
+            if self.is_room_member(room, &session.user_id) {
+                let _ = session.addr.do_send(SessionMessage(message.to_owned()));
+            }
         }
     }
-}
 
-// Handler for Message message.
-impl Handler<Message> for ChatServer {
-    type Result = ();
+    fn send_room_error(&self, session: usize, err: RoomError) {
+        let event = ServerEvent::error(err.code(), &err.message());
+        self.send_to_session(session, &event.to_json());
+    }
+
+    fn send_invite_error(&self, session: usize, err: InviteError) {
+        let event = ServerEvent::error(err.code(), &err.message());
+        self.send_to_session(session, &event.to_json());
+    }
+
+    fn send_automod_error(&self, session: usize, err: AutomodError) {
+        let event = ServerEvent::error(err.code(), &err.message());
+        self.send_to_session(session, &event.to_json());
+    }
+
+    fn send_join_request_error(&self, session: usize, err: JoinRequestError) {
+        let event = ServerEvent::error(err.code(), &err.message());
+        self.send_to_session(session, &event.to_json());
+    }
+
+    // Send a message to all sessions of a user
+    fn send_to_user(&self, user_id: &Uuid, message: &str) {
+        for session in self.sessions.values() {
+            if session.user_id == *user_id {
+                let _ = session.addr.do_send(SessionMessage(message.to_owned()));
+            }
+        }
+    }
 
-    fn handle(&mut self, msg: Message, _: &mut Context<Self>) {
-        self.send_message(&msg.room, msg.msg.as_str());
+    // Send a message to all sessions of users that may manage the room, admins included
+    fn send_to_managers(&mut self, room_id: &Uuid, message: &str) {
+        let users: HashSet<Uuid> = self.sessions
+            .values()
+            .map(|session| session.user_id)
+            .collect();
+        for user_id in users {
+            if self.controller.check_room_manager(room_id, &user_id).is_ok() {
+                self.send_to_user(&user_id, message);
+            }
+        }
+    }
+
+    // Tell the requester and the moderators how a join request ended
+    fn announce_join_answer(
+        &mut self,
+        request: &JoinRequest,
+        outcome: JoinOutcome,
+        answered_by: Option<Uuid>,
+    ) {
+        let room = match self.controller.find_room(&request.room) {
+            Some(room) => room.get_name().to_string(),
+            None => return,
+        };
+        let event = ServerEvent::JoinRequestAnswered {
+            room: room,
+            id: request.id,
+            user: request.user,
+            outcome: outcome,
+            answered_by: answered_by,
+        }.to_json();
+        self.send_to_user(&request.user, &event);
+        self.send_to_managers(&request.room, &event);
+    }
+
+    // Banned and muted members can not post to the room, false if the message must be dropped
+    fn check_sanctions(&self, session: usize, room: &str, user_id: &Uuid) -> bool {
+        let (room_id, banned, muted) = match self.controller.find_room_by_name(room) {
+            Some(room) => (
+                room.copy_id(),
+                room.is_member_banned(user_id),
+                room.is_member_muted(user_id),
+            ),
+            None => return true,
+        };
+        if banned {
+            self.send_room_error(session, RoomError::NotAllowed);
+            return false;
+        }
+        if muted {
+            let message = match self.sanctions
+                .get(&room_id, user_id, SanctionKind::Mute)
+                .and_then(|sanction| sanction.expires_at)
+            {
+                Some(expires_at) => format!("You are muted in this room until {}", expires_at),
+                None => "You are muted in this room".to_string(),
+            };
+            let event = ServerEvent::error(ErrorCode::Muted, &message);
+            self.send_to_session(session, &event.to_json());
+            return false;
+        }
+        true
+    }
+
+    // Sessions of the user stop or start getting the messages of the room
+    fn set_room_sessions(&mut self, room: &str, user_id: &Uuid, present: bool) {
+        let was_present = self.user_present(room, user_id);
+        let ids: Vec<usize> = self.sessions
+            .iter()
+            .filter(|&(_, session)| session.user_id == *user_id)
+            .map(|(id, _)| *id)
+            .collect();
+        if let Some(sessions) = self.rooms.get_mut(room) {
+            for id in ids {
+                if present {
+                    sessions.insert(id);
+                } else {
+                    sessions.remove(&id);
+                }
+            }
+        }
+
+        let username = match self.sessions
+            .values()
+            .find(|session| session.user_id == *user_id)
+        {
+            Some(session) if self.user_present(room, user_id) != was_present => {
+                session.username.clone()
+            }
+            _ => return,
+        };
+        let event = if present {
+            BotEvent::Join {
+                room: room.to_owned(),
+                user: *user_id,
+                username: username,
+            }
+        } else {
+            BotEvent::Leave {
+                room: room.to_owned(),
+                user: *user_id,
+                username: username,
+            }
+        };
+        self.notify_bots(event);
+    }
+
+    // Entries keep the usernames, the ids are new after a restart
+    fn record_audit(&mut self, mut entry: AuditEntry) {
+        let controller = &mut self.controller;
+        let mut username = |id: Option<Uuid>| {
+            id.and_then(|id| controller.find_user(&id))
+                .map(|user| user.get_username().to_string())
+        };
+        entry.actor_name = username(entry.actor);
+        entry.target_name = username(entry.target);
+        self.audit.record(entry);
+        self.dirty.insert("audit");
+    }
+
+    // Lift a ban or mute and tell the room, unbanned users are members again
+    fn lift_sanction(&mut self, sanction: &Sanction, lifted_by: Option<Uuid>) {
+        let lifted = match sanction.kind {
+            SanctionKind::Ban => self.controller.unban_member(&sanction.room, sanction.user),
+            SanctionKind::Mute => self.controller
+                .unmute_member(&sanction.room, &sanction.user),
+        };
+        let room = match self.controller.find_room(&sanction.room) {
+            Some(room) if lifted => room.get_name().to_string(),
+            _ => return,
+        };
+        if sanction.kind == SanctionKind::Ban {
+            self.set_room_sessions(&room, &sanction.user, true);
+        }
+        let action = match sanction.kind {
+            SanctionKind::Ban => AuditAction::Unban,
+            SanctionKind::Mute => AuditAction::Unmute,
+        };
+        self.record_audit(AuditEntry::for_room(
+            action,
+            lifted_by,
+            sanction.room,
+            room.clone(),
+            Some(sanction.user),
+            Local::now(),
+        ));
+        let event = ServerEvent::SanctionLifted {
+            room: room.clone(),
+            user: sanction.user,
+            kind: sanction.kind,
+            lifted_by: lifted_by,
+        };
+        self.send_to_members(&room, &event.to_json());
+    }
+
+    // Lift the bans and mutes that ran out
+    fn lift_expired_sanctions(&mut self) {
+        let expired = self.sanctions.expire(Local::now());
+        if expired.is_empty() {
+            return;
+        }
+        self.dirty.insert("sanctions");
+        for sanction in &expired {
+            self.lift_sanction(sanction, None);
+        }
+    }
+
+    // Timed sanctions are lifted by the actor system once they expire
+    fn schedule_sanction_expiry(&self, sanction: &Sanction, ctx: &mut Context<Self>) {
+        if let Some(expires_at) = sanction.expires_at {
+            let remaining = (expires_at - Local::now())
+                .to_std()
+                .unwrap_or_else(|_| Duration::from_secs(0));
+            ctx.run_later(remaining, |act, _| act.lift_expired_sanctions());
+        }
+    }
+
+    // Record the end of a server ban, `lifted_by` is none when it expired
+    fn lift_server_ban(&mut self, ban: &ServerBan, lifted_by: Option<Uuid>) {
+        let mut entry = AuditEntry::new(AuditAction::ServerUnban, lifted_by, Local::now());
+        entry.target = Some(ban.user);
+        self.record_audit(entry);
+        self.dirty.insert("server_bans");
+    }
+
+    fn lift_expired_server_bans(&mut self) {
+        for ban in self.bans.expire(Local::now()) {
+            self.lift_server_ban(&ban, None);
+        }
+    }
+
+    fn schedule_server_ban_expiry(&self, ban: &ServerBan, ctx: &mut Context<Self>) {
+        if let Some(expires_at) = ban.expires_at {
+            let remaining = (expires_at - Local::now())
+                .to_std()
+                .unwrap_or_else(|_| Duration::from_secs(0));
+            ctx.run_later(remaining, |act, _| act.lift_expired_server_bans());
+        }
+    }
+
+    // Answer join requests nobody decided on in time
+    fn expire_join_requests(&mut self) {
+        let expired = self.join_requests.expire(Local::now());
+        if expired.is_empty() {
+            return;
+        }
+        self.dirty.insert("join_requests");
+        for request in &expired {
+            self.announce_join_answer(request, JoinOutcome::Expired, None);
+        }
+    }
+
+    fn find_user_id(&mut self, username: &str) -> Result<Uuid, RoomError> {
+        self.controller
+            .find_user_by_username(username)
+            .map(|user| user.copy_id())
+            .ok_or(RoomError::UnknownUser)
+    }
+
+    // Tell the members about changed settings of the room
+    fn announce_room_settings(&self, room_id: &Uuid, before: RoomSettings, changed_by: Uuid) {
+        let settings = match self.controller.find_room(room_id) {
+            Some(room) => room.settings(),
+            None => return,
+        };
+        if settings == before {
+            return;
+        }
+        let previous_name = if settings.name != before.name {
+            Some(before.name)
+        } else {
+            None
+        };
+        let event = ServerEvent::RoomSettingsChanged {
+            settings: settings.clone(),
+            previous_name: previous_name,
+            changed_by: changed_by,
+        };
+        self.send_to_members(&settings.name, &event.to_json());
+    }
+
+    // Owners and admins delete rooms, except the default room
+    fn delete_room(&mut self, session: usize, room_id: &Uuid, user_id: Uuid, name: &str) {
+        if name == self.config.default_room {
+            return self.send_room_error(session, RoomError::NotAllowed);
+        }
+        // the members are looked up while the room still exists
+        let members: Vec<usize> = self.sessions
+            .iter()
+            .filter(|&(_, connected)| self.is_room_member(name, &connected.user_id))
+            .map(|(id, _)| *id)
+            .collect();
+        if let Err(err) = self.controller.remove_room(room_id, &user_id) {
+            return self.send_room_error(session, err);
+        }
+        self.record_audit(AuditEntry::for_room(
+            AuditAction::DeleteRoom,
+            Some(user_id),
+            *room_id,
+            name.to_owned(),
+            None,
+            Local::now(),
+        ));
+
+        let event = ServerEvent::RoomDeleted {
+            room: name.to_owned(),
+            deleted_by: user_id,
+        }.to_json();
+        for id in members {
+            self.send_to_session(id, &event);
+        }
+        self.rooms.remove(name);
+        // everything kept by room name has to go too, a new room could take the name
+        if let Some(history) = self.history.remove(name) {
+            for message in &history {
+                self.threads.remove(&message.id);
+            }
+            self.dirty.insert("messages");
+        }
+        let attachments: Vec<Uuid> = self.attachments
+            .values()
+            .filter(|attachment| attachment.room == name)
+            .map(|attachment| attachment.id)
+            .collect();
+        for id in &attachments {
+            self.remove_attachment(id);
+        }
+        for mentions in self.unread_mentions.values_mut() {
+            mentions.retain(|mention| mention.room != name);
+        }
+        self.unread_mentions.retain(|_, mentions| !mentions.is_empty());
+        self.dirty.insert("mentions");
+        if self.read_markers.remove_room(name) {
+            self.dirty.insert("read_markers");
+        }
+        if self.search.remove_room(name) {
+            self.dirty.insert("search");
+        }
+        if self.invites.remove_room(room_id) {
+            self.dirty.insert("invites");
+        }
+        if self.join_requests.remove_room(room_id) {
+            self.dirty.insert("join_requests");
+        }
+        if self.sanctions.remove_room(room_id) {
+            self.dirty.insert("sanctions");
+        }
+        if self.automod.remove_room(room_id) {
+            self.dirty.insert("automod");
+        }
+    }
+
+    // Apply the requested settings in order, stops at the first failure
+    fn update_room(
+        &mut self,
+        room_id: &Uuid,
+        user_id: &Uuid,
+        msg: &UpdateRoom,
+    ) -> Result<(), RoomError> {
+        if let Some(ref topic) = msg.topic {
+            self.controller
+                .set_room_topic(room_id, user_id, topic.trim().to_string())?;
+        }
+        if let Some(private) = msg.private {
+            self.controller.set_room_private(room_id, user_id, private)?;
+        }
+        if let Some(hidden) = msg.hidden {
+            self.controller.set_room_hidden(room_id, user_id, hidden)?;
+        }
+        if let Some(ref name) = msg.name {
+            let name = name.trim();
+            if name != msg.room {
+                // new sessions join the default room by its configured name
+                if msg.room == self.config.default_room {
+                    return Err(RoomError::NotAllowed);
+                }
+                self.controller
+                    .rename_room(room_id, user_id, name.to_string())?;
+                self.rename_room_data(&msg.room, name);
+            }
+        }
+        Ok(())
+    }
+
+    // Everything the chat server keeps by room name moves to the new name
+    fn rename_room_data(&mut self, from: &str, to: &str) {
+        // stored sanctions, invites, join requests and automod rules find their room by name
+        for key in &["sanctions", "invites", "join_requests", "automod"] {
+            self.dirty.insert(*key);
+        }
+        if let Some(sessions) = self.rooms.remove(from) {
+            for id in &sessions {
+                if let Some(session) = self.sessions.get(id) {
+                    let _ = session.rename.do_send(RoomRenamed {
+                        from: from.to_owned(),
+                        to: to.to_owned(),
+                    });
+                }
+            }
+            self.rooms.insert(to.to_owned(), sessions);
+        }
+        if let Some(mut history) = self.history.remove(from) {
+            for message in history.iter_mut() {
+                message.room = to.to_owned();
+            }
+            self.history.insert(to.to_owned(), history);
+        }
+        for attachment in self.attachments.values_mut() {
+            if attachment.room == from {
+                attachment.room = to.to_owned();
+            }
+        }
+        for mentions in self.unread_mentions.values_mut() {
+            for mention in mentions.iter_mut() {
+                if mention.room == from {
+                    mention.room = to.to_owned();
+                }
+            }
+        }
+        self.read_markers.rename_room(from, to);
+        self.search.rename_room(from, to);
+        for key in &["messages", "attachments", "mentions", "read_markers", "search"] {
+            self.dirty.insert(*key);
+        }
+    }
+
+    fn find_message(&self, id: &Uuid) -> Option<&ChatMessage> {
+        self.history
+            .values()
+            .flat_map(|messages| messages.iter())
+            .find(|message| message.id == *id)
+    }
+
+    fn find_message_mut(&mut self, id: &Uuid) -> Option<&mut ChatMessage> {
+        self.history
+            .values_mut()
+            .flat_map(|messages| messages.iter_mut())
+            .find(|message| message.id == *id)
+    }
+
+    fn send_message_error(&self, session: usize, err: MessageError) {
+        let event = ServerEvent::error(err.code(), &err.message());
+        self.send_to_session(session, &event.to_json());
+    }
+
+    fn is_room_member(&self, room: &str, user_id: &Uuid) -> bool {
+        self.controller
+            .find_room_by_name(room)
+            .map_or(false, |room| room.has_member(user_id))
+    }
+
+    // Joining a room makes the user a member, unless banned from it
+    fn ensure_member(&mut self, room: &str, user_id: Uuid) {
+        let room_id = match self.controller.find_room_by_name(room) {
+            Some(room) if !room.has_member(&user_id) && !room.is_member_banned(&user_id) => {
+                room.copy_id()
+            }
+            _ => return,
+        };
+        self.controller.add_member_to_room(&room_id, user_id);
+    }
+
+    // write everything not yet persisted to the storage backend
+    fn flush(&mut self) {
+        let keys: Vec<&'static str> = self.dirty.drain().collect();
+        for key in keys {
+            let result = match key {
+                "messages" => self.storage.store(key, &self.history),
+                "attachments" => self.storage.store(key, &self.attachments),
+                "mentions" => self.storage.store(key, &self.unread_mentions),
+                "read_markers" => self.storage.store(key, &self.read_markers),
+                "search" => self.storage.store(key, &self.search.documents()),
+                "invites" => {
+                    let controller = &self.controller;
+                    self.invites.set_names(|id| room_name(controller, id));
+                    self.storage.store(key, &self.invites)
+                }
+                "join_requests" => {
+                    let controller = &self.controller;
+                    self.join_requests.set_names(|id| room_name(controller, id));
+                    self.storage.store(key, &self.join_requests)
+                }
+                "sanctions" => {
+                    self.controller.name_sanctions(&mut self.sanctions);
+                    self.storage.store(key, &self.sanctions)
+                }
+                "audit" => self.storage.store(key, &self.audit),
+                "automod" => {
+                    let controller = &self.controller;
+                    let rules = self.automod.named_rules(|id| room_name(controller, id));
+                    self.storage.store(key, &rules)
+                }
+                "server_bans" => {
+                    self.controller.name_server_bans(&self.bans);
+                    self.storage.store(key, &self.bans.all())
+                }
+                _ => Ok(()),
+            };
+            if let Err(err) = result {
+                error!("Cannot store {}: {}", key, err);
+                self.dirty.insert(key);
+            }
+        }
+    }
+}
+
+fn server_ban_notice() -> SessionClose {
+    SessionClose {
+        code: CloseCode::Policy,
+        reason: "banned from the server".to_owned(),
+    }
+}
+
+// Current name of a room, stored next to its id which changes on restart
+fn room_name(controller: &ChatController, id: &Uuid) -> Option<String> {
+    controller.find_room(id).map(|room| room.get_name().to_owned())
+}
+
+fn shutdown_notice() -> SessionClose {
+    SessionClose {
+        code: CloseCode::Away,
+        reason: "server is shutting down".to_owned(),
+    }
+}
+
+// Make actor from `ChatServer`
+impl Actor for ChatServer {
+    // We are going to use simple Context, we just need ability to communicate
+    // with other actors.
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // sanctions that ran out while the server was down are lifted right away
+        self.lift_expired_sanctions();
+        for sanction in self.sanctions.all() {
+            self.schedule_sanction_expiry(sanction, ctx);
+        }
+        self.lift_expired_server_bans();
+        for ban in self.bans.all() {
+            self.schedule_server_ban_expiry(&ban, ctx);
+        }
+        ctx.run_interval(Duration::from_secs(FLUSH_INTERVAL), |act, _| {
+            if act.invites.purge(Local::now()) {
+                act.dirty.insert("invites");
+            }
+            act.expire_join_requests();
+            act.flush()
+        });
+    }
+}
+
+// Handler for Connect message.
+//
+// Register new session and assign unique id to this session
+impl Handler<Connect> for ChatServer {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
+        if self.draining {
+            let _ = msg.close.do_send(shutdown_notice());
+            return 0;
+        }
+        // banned after the websocket was accepted
+        if self.bans
+            .check(Some(&msg.user_id), msg.ip, Local::now())
+            .is_some()
+        {
+            let _ = msg.close.do_send(server_ban_notice());
+            return 0;
+        }
+        debug!("Someone joined");
+
+        let joinmsg = "{\"message\": \"someone joined\", \"name\":\"server\"}";
+        let room = self.config.default_room.clone();
+        //let joinmsg = json::to_string(&msg.0).unwrap();
+        // notify all users in same room
+        self.send_message(&room, &joinmsg);
+
+        // register session with random id
+        let id = self.rng.borrow_mut().gen::<usize>();
+        self.sessions.insert(
+            id,
+            Session {
+                addr: msg.addr,
+                close: msg.close,
+                rename: msg.rename,
+                user_id: msg.user_id,
+                username: msg.username.clone(),
+                ip: msg.ip,
+            },
+        );
+
+        // auto join session to the default room, unless banned from it
+        self.ensure_member(&room, msg.user_id);
+        if self.is_room_member(&room, &msg.user_id) {
+            let joined = !self.user_present(&room, &msg.user_id);
+            self.rooms.get_mut(&room).unwrap().insert(id);
+
+            // catch the new member up with the room history, replies are fetched by thread
+            if let Some(history) = self.history.get(&room) {
+                for message in history.iter().filter(|message| !message.is_reply()) {
+                    let event = ServerEvent::Message {
+                        message: message.clone(),
+                    };
+                    self.send_to_session(id, &event.to_json());
+                }
+            }
+            if joined {
+                self.notify_bots(BotEvent::Join {
+                    room: room.clone(),
+                    user: msg.user_id,
+                    username: msg.username,
+                });
+            }
+        }
+
+        if let Some(mentions) = self.unread_mentions.remove(&msg.user_id) {
+            let event = ServerEvent::UnreadMentions {
+                mentions: mentions.into_iter().collect(),
+            };
+            self.send_to_session(id, &event.to_json());
+            self.dirty.insert("mentions");
+        }
+        let event = ServerEvent::UnreadCounts {
+            rooms: self.unread_counts(&msg.user_id),
+        };
+        self.send_to_session(id, &event.to_json());
+
+        // send id back
+        id
+    }
+}
+
+// Handler for Disconnect message.
+impl Handler<Disconnect> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
+        debug!("Someone disconnected");
+
+        let dcmsg = "{\"message\": \"someone disconnected\", \"name\":\"server\"}";
+        let mut rooms: Vec<String> = Vec::new();
+
+        self.rate_limiter.remove_session(msg.id);
+        for subscribers in self.threads.values_mut() {
+            subscribers.remove(&msg.id);
+        }
+        self.threads.retain(|_, subscribers| !subscribers.is_empty());
+
+        // remove address
+        let session = self.sessions.remove(&msg.id);
+        if session.is_some() {
+            // remove session from all rooms
+            for (name, sessions) in &mut self.rooms {
+                if sessions.remove(&msg.id) {
+                    rooms.push(name.to_owned());
+                }
+            }
+        }
+        // send message to other users
+        for room in rooms {
+            self.send_message(&room, &dcmsg);
+            if let Some(ref session) = session {
+                if !self.user_present(&room, &session.user_id) {
+                    self.notify_bots(BotEvent::Leave {
+                        room: room.clone(),
+                        user: session.user_id,
+                        username: session.username.clone(),
+                    });
+                }
+            }
+        }
+        if let Some(session) = session {
+            let user_id = session.user_id;
+            if !self.sessions.values().any(|other| other.user_id == user_id) {
+                self.rate_limiter.remove_user(&user_id, Instant::now());
+            }
+        }
+    }
+}
+
+// Handler for Message message.
+impl Handler<Message> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, ctx: &mut Context<Self>) {
+        if !self.check_rate_limit(msg.id) {
+            return;
+        }
+        // replies are never commands
+        let text = match msg.parent {
+            Some(_) => msg.msg,
+            None => match commands::parse(&msg.msg) {
+                Line::Command(name, args) => {
+                    return self.run_command(msg.id, &msg.room, &name, args, ctx)
+                }
+                Line::Text(text) => text.to_string(),
+            },
+        };
+        let message = match self.sessions.get(&msg.id) {
+            Some(session) => ChatMessage::new(
+                msg.room,
+                session.user_id,
+                session.username.clone(),
+                text,
+            ),
+            None => return,
+        };
+        match msg.parent {
+            Some(parent) => self.post_reply(msg.id, parent, message, ctx),
+            None => self.post_top_level(msg.id, message, ctx),
+        }
+    }
+}
+
+// The chat server as seen by a running slash command
+struct CommandSession<'a> {
+    server: &'a mut ChatServer,
+    ctx: &'a mut Context<ChatServer>,
+}
+
+impl<'a> CommandHost for CommandSession<'a> {
+    fn post(&mut self, caller: &Caller, text: String, emote: bool) {
+        let mut message = ChatMessage::new(
+            caller.room.clone(),
+            caller.user_id,
+            caller.username.clone(),
+            text,
+        );
+        message.emote = emote;
+        self.server.post_top_level(caller.session, message, self.ctx);
+    }
+
+    fn topic(&mut self, room: &str) -> Option<String> {
+        self.server
+            .controller
+            .find_room_by_name(room)
+            .map(|room| room.get_topic().to_string())
+    }
+
+    fn set_topic(&mut self, caller: &Caller, topic: String) {
+        let update = UpdateRoom {
+            session: caller.session,
+            room: caller.room.clone(),
+            topic: Some(topic),
+            name: None,
+            private: None,
+            hidden: None,
+        };
+        <ChatServer as Handler<UpdateRoom>>::handle(self.server, update, self.ctx);
+    }
+
+    fn who(&mut self, room: &str) -> Vec<String> {
+        let server = &self.server;
+        let mut usernames: Vec<String> = server
+            .rooms
+            .get(room)
+            .map(|sessions| {
+                sessions
+                    .iter()
+                    .filter_map(|id| server.sessions.get(id))
+                    .map(|session| session.username.clone())
+                    .collect()
+            })
+            .unwrap_or_else(Vec::new);
+        usernames.sort();
+        usernames.dedup();
+        usernames
+    }
+
+    fn whois(&mut self, username: &str) -> Option<UserInfo> {
+        let user = self.server.controller.find_user_by_username(username)?;
+        let user_id = user.copy_id();
+        Some(UserInfo {
+            username: user.get_username().to_string(),
+            display_name: user.get_display_name().to_string(),
+            admin: self.server.controller.is_admin(&user_id),
+            bot: self.server.bots.is_bot(&user_id),
+            online: self.server
+                .sessions
+                .values()
+                .any(|session| session.user_id == user_id),
+        })
+    }
+}
+
+// Handler for FetchThread message.
+impl Handler<FetchThread> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: FetchThread, _: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        let event = match self.find_message(&msg.id) {
+            Some(message) if !self.is_room_member(&message.room, &user_id) => None,
+            Some(message) => {
+                let thread = message.thread_id();
+                let first = self.find_message(&thread).unwrap_or(message).clone();
+                let replies = self.history
+                    .get(&first.room)
+                    .map(|history| {
+                        history
+                            .iter()
+                            .filter(|reply| reply.parent == Some(thread))
+                            .cloned()
+                            .collect()
+                    })
+                    .unwrap_or_else(Vec::new);
+                Some(ServerEvent::Thread {
+                    message: first,
+                    replies: replies,
+                })
+            }
+            None => None,
+        };
+        match event {
+            Some(event) => self.send_to_session(msg.session, &event.to_json()),
+            None => self.send_message_error(msg.session, MessageError::UnknownMessage),
+        }
+    }
+}
+
+// Handler for SubscribeThread message.
+impl Handler<SubscribeThread> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeThread, _: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        if !msg.subscribe {
+            if let Some(subscribers) = self.threads.get_mut(&msg.id) {
+                subscribers.remove(&msg.session);
+            }
+            return;
+        }
+
+        let thread = match self.find_message(&msg.id) {
+            Some(message) if self.is_room_member(&message.room, &user_id) => message.thread_id(),
+            _ => return self.send_message_error(msg.session, MessageError::UnknownMessage),
+        };
+        self.threads
+            .entry(thread)
+            .or_insert_with(HashSet::new)
+            .insert(msg.session);
+    }
+}
+
+// Handler for EditMessage message.
+impl Handler<EditMessage> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: EditMessage, ctx: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        if !self.check_rate_limit(msg.session) {
+            return;
+        }
+
+        // edits go through the rules of the room like new messages
+        let room = match self.find_message(&msg.id) {
+            Some(message) if message.author == user_id => Some(message.room.clone()),
+            _ => None,
+        };
+        let (text, flags) = match room {
+            Some(room) => {
+                // banned and muted members can not change old messages either
+                if !self.check_sanctions(msg.session, &room, &user_id) {
+                    return;
+                }
+                match self.moderate(msg.session, &room, user_id, &msg.text, ctx) {
+                    Some(moderated) => moderated,
+                    None => return,
+                }
+            }
+            // `edit` answers with the error
+            None => (msg.text, Vec::new()),
+        };
+
+        let result = match self.find_message_mut(&msg.id) {
+            Some(message) => message
+                .edit(&user_id, text, Local::now())
+                .map(|_| message.clone()),
+            None => Err(MessageError::UnknownMessage),
+        };
+        match result {
+            Ok(message) => {
+                self.flag_message(&message, flags);
+                self.dirty.insert("messages");
+                self.search.index(&message);
+                self.dirty.insert("search");
+                let room = message.room.clone();
+                let event = ServerEvent::MessageEdited { message: message };
+                self.send_message(&room, &event.to_json());
+            }
+            Err(err) => self.send_message_error(msg.session, err),
+        }
+    }
+}
+
+// Handler for DeleteMessage message.
+impl Handler<DeleteMessage> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeleteMessage, _: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        let (room, author) = match self.find_message(&msg.id) {
+            Some(message) => (message.room.clone(), message.author),
+            None => return self.send_message_error(msg.session, MessageError::UnknownMessage),
+        };
+        // owners and admins moderate the room too
+        let room_id = self.controller
+            .find_room_by_name(&room)
+            .map(|room| room.copy_id());
+        let is_moderator = room_id.map_or(false, |room_id| {
+            self.controller.check_room_manager(&room_id, &user_id).is_ok()
+        });
+
+        let result = match self.find_message_mut(&msg.id) {
+            Some(message) => {
+                let attachment = message.attachment.as_ref().map(|attachment| attachment.id);
+                message
+                    .delete(&user_id, is_moderator)
+                    .map(|()| attachment)
+            }
+            None => Err(MessageError::UnknownMessage),
+        };
+        match result {
+            Ok(attachment) => {
+                self.dirty.insert("messages");
+                self.search.remove(&msg.id);
+                self.dirty.insert("search");
+                // the file of a deleted message is not served anymore
+                if let Some(id) = attachment {
+                    self.remove_attachment(&id);
+                }
+                // authors deleting their own messages do not moderate
+                if author != user_id {
+                    if let Some(room_id) = room_id {
+                        let mut entry = AuditEntry::for_room(
+                            AuditAction::DeleteMessage,
+                            Some(user_id),
+                            room_id,
+                            room.clone(),
+                            Some(author),
+                            Local::now(),
+                        );
+                        entry.details = Some(msg.id.to_string());
+                        self.record_audit(entry);
+                    }
+                }
+                let event = ServerEvent::MessageDeleted {
+                    id: msg.id,
+                    room: room.clone(),
+                    deleted_by: user_id,
+                };
+                self.send_message(&room, &event.to_json());
+            }
+            Err(err) => self.send_message_error(msg.session, err),
+        }
+    }
+}
+
+// Handler for UpdateRoom message.
+impl Handler<UpdateRoom> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdateRoom, _: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        let (room_id, before) = match self.controller.find_room_by_name(&msg.room) {
+            Some(room) => (room.copy_id(), room.settings()),
+            None => return self.send_room_error(msg.session, RoomError::UnknownRoom),
+        };
+
+        if let Err(err) = self.update_room(&room_id, &user_id, &msg) {
+            self.send_room_error(msg.session, err);
+        }
+        let after = match self.controller.find_room(&room_id) {
+            Some(room) => room.settings(),
+            None => return,
+        };
+        let changes = after.changes(&before);
+        if !changes.is_empty() {
+            let mut entry = AuditEntry::for_room(
+                AuditAction::UpdateRoom,
+                Some(user_id),
+                room_id,
+                after.name.clone(),
+                None,
+                Local::now(),
+            );
+            entry.details = Some(changes.join(", "));
+            self.record_audit(entry);
+        }
+        // settings applied before a failure are still announced
+        self.announce_room_settings(&room_id, before, user_id);
+    }
+}
+
+// Handler for ManageRoom message.
+impl Handler<ManageRoom> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ManageRoom, _: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        let (room_id, before) = match self.controller.find_room_by_name(&msg.room) {
+            Some(room) => (room.copy_id(), room.settings()),
+            None => return self.send_room_error(msg.session, RoomError::UnknownRoom),
+        };
+
+        let result = match msg.action {
+            RoomAction::Delete => {
+                return self.delete_room(msg.session, &room_id, user_id, &msg.room)
+            }
+            RoomAction::AddModerator(ref username) => self.find_user_id(username)
+                .and_then(|target| {
+                    self.controller
+                        .add_moderator_to_room(&room_id, &user_id, target)
+                        .map(|_| (AuditAction::AddModerator, target))
+                }),
+            RoomAction::RemoveModerator(ref username) => self.find_user_id(username)
+                .and_then(|target| {
+                    self.controller
+                        .remove_moderator_from_room(&room_id, &user_id, &target)
+                        .map(|_| (AuditAction::RemoveModerator, target))
+                }),
+            RoomAction::TransferOwnership(ref username) => self.find_user_id(username)
+                .and_then(|target| {
+                    self.controller
+                        .transfer_ownership(&room_id, &user_id, target)
+                        .map(|_| (AuditAction::TransferOwnership, target))
+                }),
+        };
+        match result {
+            Ok((action, target)) => {
+                self.record_audit(AuditEntry::for_room(
+                    action,
+                    Some(user_id),
+                    room_id,
+                    msg.room.clone(),
+                    Some(target),
+                    Local::now(),
+                ));
+                self.announce_room_settings(&room_id, before, user_id)
+            }
+            Err(err) => self.send_room_error(msg.session, err),
+        }
+    }
+}
+
+// Handler for ManageInvites message.
+impl Handler<ManageInvites> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ManageInvites, _: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        let room_id = match self.controller.find_room_by_name(&msg.room) {
+            Some(room) => room.copy_id(),
+            None => return self.send_room_error(msg.session, RoomError::UnknownRoom),
+        };
+        if let Err(err) = self.controller.check_room_manager(&room_id, &user_id) {
+            return self.send_room_error(msg.session, err);
+        }
+
+        let now = Local::now();
+        let event = match msg.action {
+            InviteAction::Create {
+                expires_in,
+                max_uses,
+            } => {
+                let expires_at = expires_in.map(|seconds| {
+                    now + ::chrono::Duration::seconds(seconds.min(MAX_INVITE_LIFETIME) as i64)
+                });
+                let max_uses = match max_uses {
+                    Some(0) => None,
+                    other => other,
+                };
+                let invite = self.invites
+                    .create(room_id, user_id, expires_at, max_uses, now);
+                self.dirty.insert("invites");
+                ServerEvent::InviteCreated {
+                    room: msg.room,
+                    invite: invite,
+                }
+            }
+            InviteAction::List => ServerEvent::Invites {
+                invites: self.invites.list(&room_id, now),
+                room: msg.room,
+            },
+            InviteAction::Revoke(code) => {
+                // invites of other rooms are not revealed
+                if self.invites
+                    .get(&code)
+                    .map_or(true, |invite| invite.room != room_id)
+                {
+                    return self.send_invite_error(msg.session, InviteError::UnknownInvite);
+                }
+                self.invites.revoke(&code);
+                self.dirty.insert("invites");
+                ServerEvent::InviteRevoked {
+                    room: msg.room,
+                    code: code,
+                }
+            }
+        };
+        self.send_to_session(msg.session, &event.to_json());
+    }
+}
+
+// Handler for ManageAutomod message.
+impl Handler<ManageAutomod> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ManageAutomod, _: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        let room_id = match self.controller.find_room_by_name(&msg.room) {
+            Some(room) => room.copy_id(),
+            None => return self.send_room_error(msg.session, RoomError::UnknownRoom),
+        };
+        if let Err(err) = self.controller.check_room_manager(&room_id, &user_id) {
+            return self.send_room_error(msg.session, err);
+        }
+
+        let (event, details) = match msg.action {
+            AutomodAction::Add(filter, action) => {
+                let result = Rule::new(filter, action, user_id, Local::now()).and_then(|rule| {
+                    self.automod.add(room_id, rule.clone())?;
+                    Ok(rule)
+                });
+                let rule = match result {
+                    Ok(rule) => rule,
+                    Err(err) => return self.send_automod_error(msg.session, err),
+                };
+                let details = format!("added rule {}", rule.id);
+                let event = ServerEvent::AutomodRuleAdded {
+                    room: msg.room.clone(),
+                    rule: rule,
+                };
+                (event, details)
+            }
+            AutomodAction::Remove(id) => {
+                if let Err(err) = self.automod.remove(&room_id, &id) {
+                    return self.send_automod_error(msg.session, err);
+                }
+                let event = ServerEvent::AutomodRuleRemoved {
+                    room: msg.room.clone(),
+                    id: id,
+                };
+                (event, format!("removed rule {}", id))
+            }
+            AutomodAction::List => {
+                let event = ServerEvent::AutomodRules {
+                    rules: self.automod.rules(&room_id).to_vec(),
+                    room: msg.room,
+                };
+                return self.send_to_session(msg.session, &event.to_json());
+            }
+        };
+        self.dirty.insert("automod");
+        let mut entry = AuditEntry::for_room(
+            AuditAction::UpdateAutomod,
+            Some(user_id),
+            room_id,
+            msg.room,
+            None,
+            Local::now(),
+        );
+        entry.details = Some(details);
+        self.record_audit(entry);
+        self.send_to_session(msg.session, &event.to_json());
+    }
+}
+
+// Handler for RedeemInvite message.
+impl Handler<RedeemInvite> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RedeemInvite, _: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        let now = Local::now();
+        let room_id = match self.invites.check(&msg.code, now) {
+            Ok(room_id) => room_id,
+            Err(err) => return self.send_invite_error(msg.session, err),
+        };
+
+        // members do not use up the invite
+        match self.controller.join_room_by_invite(&room_id, user_id) {
+            Ok(true) => {
+                let _ = self.invites.redeem(&msg.code, now);
+                self.dirty.insert("invites");
+            }
+            Ok(false) => (),
+            Err(err) => return self.send_room_error(msg.session, err),
+        }
+        if let Some(room) = self.controller.find_room(&room_id) {
+            let event = ServerEvent::RoomJoined {
+                settings: room.settings(),
+            };
+            self.send_to_session(msg.session, &event.to_json());
+        }
+    }
+}
+
+// Handler for RequestJoin message.
+impl Handler<RequestJoin> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RequestJoin, _: &mut Context<Self>) {
+        let (user_id, username) = match self.sessions.get(&msg.session) {
+            Some(session) => (session.user_id, session.username.clone()),
+            None => return,
+        };
+        // hidden rooms are not revealed by their name
+        let room_id = match self.controller.find_room_by_name(&msg.room) {
+            Some(room) if !room.is_hidden() => room.copy_id(),
+            _ => return self.send_room_error(msg.session, RoomError::UnknownRoom),
+        };
+        if let Err(err) = self.controller.check_join_request(&room_id, &user_id) {
+            return self.send_room_error(msg.session, err);
+        }
+        if !self.check_rate_limit(msg.session) {
+            return;
+        }
+
+        let now = Local::now();
+        let expires_at =
+            now + ::chrono::Duration::seconds(self.config.join_request_lifetime as i64);
+        let request = match self.join_requests
+            .request(room_id, user_id, username, now, expires_at)
+        {
+            Ok(request) => request,
+            Err(err) => return self.send_join_request_error(msg.session, err),
+        };
+        self.dirty.insert("join_requests");
+
+        let event = ServerEvent::JoinRequested {
+            room: msg.room,
+            request: request,
+        }.to_json();
+        self.send_to_session(msg.session, &event);
+        self.send_to_managers(&room_id, &event);
+    }
+}
+
+// Handler for ListJoinRequests message.
+impl Handler<ListJoinRequests> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ListJoinRequests, _: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        let room_id = match self.controller.find_room_by_name(&msg.room) {
+            Some(room) => room.copy_id(),
+            None => return self.send_room_error(msg.session, RoomError::UnknownRoom),
+        };
+        if let Err(err) = self.controller.check_room_manager(&room_id, &user_id) {
+            return self.send_room_error(msg.session, err);
+        }
+        let event = ServerEvent::JoinRequests {
+            room: msg.room,
+            requests: self.join_requests.pending(&room_id, Local::now()),
+        };
+        self.send_to_session(msg.session, &event.to_json());
+    }
+}
+
+// Handler for AnswerJoinRequest message.
+impl Handler<AnswerJoinRequest> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: AnswerJoinRequest, _: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        let (room_id, requester) = match self.join_requests.get(&msg.id) {
+            Some(request) => (request.room, request.user),
+            None => {
+                return self.send_join_request_error(msg.session, JoinRequestError::UnknownRequest)
+            }
+        };
+
+        // a requester banned in the meantime stays out, the request is denied
+        let approved = msg.approve
+            && self.controller
+                .approve_join_request(&room_id, &user_id, requester)
+                .is_ok();
+        let outcome = if approved {
+            JoinOutcome::Approved
+        } else {
+            if let Err(err) = self.controller.deny_join_request(&room_id, &user_id) {
+                return self.send_room_error(msg.session, err);
+            }
+            JoinOutcome::Denied
+        };
+
+        let request = match self.join_requests.take(&msg.id) {
+            Some(request) => request,
+            None => return,
+        };
+        self.dirty.insert("join_requests");
+        self.announce_join_answer(&request, outcome, Some(user_id));
+        if outcome == JoinOutcome::Approved {
+            if let Some(room) = self.controller.find_room(&room_id) {
+                let event = ServerEvent::RoomJoined {
+                    settings: room.settings(),
+                };
+                self.send_to_user(&requester, &event.to_json());
+            }
+        }
+    }
+}
+
+// Handler for SanctionMember message.
+impl Handler<SanctionMember> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SanctionMember, ctx: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        let room_id = match self.controller.find_room_by_name(&msg.room) {
+            Some(room) => room.copy_id(),
+            None => return self.send_room_error(msg.session, RoomError::UnknownRoom),
+        };
+        let target = match self.find_user_id(&msg.username) {
+            Ok(target) => target,
+            Err(err) => return self.send_room_error(msg.session, err),
+        };
+        if let Err(err) = self.controller
+            .sanction_member(&room_id, &user_id, target, msg.kind)
+        {
+            return self.send_room_error(msg.session, err);
+        }
+
+        let sanction = Sanction::new(
+            room_id,
+            target,
+            msg.kind,
+            user_id,
+            msg.duration,
+            msg.reason,
+            Local::now(),
+        );
+        self.schedule_sanction_expiry(&sanction, ctx);
+        let event = ServerEvent::Sanctioned {
+            room: msg.room.clone(),
+            sanction: sanction.clone(),
+        }.to_json();
+        self.send_to_members(&msg.room, &event);
+        // banned users are no members anymore
+        if msg.kind == SanctionKind::Ban {
+            self.send_to_user(&target, &event);
+            self.set_room_sessions(&msg.room, &target, false);
+        }
+        let action = match msg.kind {
+            SanctionKind::Ban => AuditAction::Ban,
+            SanctionKind::Mute => AuditAction::Mute,
+        };
+        let mut entry = AuditEntry::for_room(
+            action,
+            Some(user_id),
+            room_id,
+            msg.room.clone(),
+            Some(target),
+            sanction.issued_at,
+        );
+        entry.reason = sanction.reason.clone();
+        entry.details = sanction
+            .expires_at
+            .map(|expires_at| format!("until {}", expires_at.to_rfc3339()));
+        self.record_audit(entry);
+        self.sanctions.add(sanction);
+        self.dirty.insert("sanctions");
+    }
+}
+
+// Handler for KickMember message.
+impl Handler<KickMember> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: KickMember, _: &mut Context<Self>) {
+        let (user_id, username) = match self.sessions.get(&msg.session) {
+            Some(session) => (session.user_id, session.username.clone()),
+            None => return,
+        };
+        let room_id = match self.controller.find_room_by_name(&msg.room) {
+            Some(room) => room.copy_id(),
+            None => return self.send_room_error(msg.session, RoomError::UnknownRoom),
+        };
+        let target = match self.find_user_id(&msg.username) {
+            Ok(target) => target,
+            Err(err) => return self.send_room_error(msg.session, err),
+        };
+        if let Err(err) = self.controller.kick_member(&room_id, &user_id, &target) {
+            return self.send_room_error(msg.session, err);
+        }
+
+        let reason = sanctions::normalize_reason(msg.reason);
+        self.set_room_sessions(&msg.room, &target, false);
+        let kicked = ServerEvent::Kicked {
+            room: msg.room.clone(),
+            kicked_by: user_id,
+            reason: reason.clone(),
+        };
+        self.send_to_user(&target, &kicked.to_json());
+        let event = ServerEvent::MemberKicked {
+            room: msg.room.clone(),
+            user: target,
+            username: msg.username,
+            kicked_by: user_id,
+            kicked_by_username: username,
+            reason: reason.clone(),
+        };
+        self.send_to_members(&msg.room, &event.to_json());
+
+        let mut entry = AuditEntry::for_room(
+            AuditAction::Kick,
+            Some(user_id),
+            room_id,
+            msg.room,
+            Some(target),
+            Local::now(),
+        );
+        entry.reason = reason;
+        self.record_audit(entry);
+    }
+}
+
+// Handler for LiftSanction message.
+impl Handler<LiftSanction> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: LiftSanction, _: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        let room_id = match self.controller.find_room_by_name(&msg.room) {
+            Some(room) => room.copy_id(),
+            None => return self.send_room_error(msg.session, RoomError::UnknownRoom),
+        };
+        if let Err(err) = self.controller.check_room_manager(&room_id, &user_id) {
+            return self.send_room_error(msg.session, err);
+        }
+        let target = match self.find_user_id(&msg.username) {
+            Ok(target) => target,
+            Err(err) => return self.send_room_error(msg.session, err),
+        };
+
+        // bans from before sanctions were recorded have no entry
+        let sanction = match self.sanctions.remove(&room_id, &target, msg.kind) {
+            Some(sanction) => {
+                self.dirty.insert("sanctions");
+                sanction
+            }
+            None => Sanction::new(room_id, target, msg.kind, user_id, None, None, Local::now()),
+        };
+        self.lift_sanction(&sanction, Some(user_id));
+    }
+}
+
+// Handler for BanFromServer message.
+impl Handler<BanFromServer> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: BanFromServer, ctx: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        if !self.controller.is_admin(&user_id) {
+            return self.send_room_error(msg.session, RoomError::NotAllowed);
+        }
+        let target = match self.find_user_id(&msg.username) {
+            Ok(target) => target,
+            Err(err) => return self.send_room_error(msg.session, err),
+        };
+        // admins cannot lock each other out
+        if target == user_id || self.controller.is_admin(&target) {
+            return self.send_room_error(msg.session, RoomError::NotAllowed);
+        }
+
+        let mut ips: Vec<IpAddr> = Vec::new();
+        if msg.block_ip {
+            for session in self.sessions.values() {
+                match session.ip {
+                    Some(ip) if session.user_id == target && !ips.contains(&ip) => ips.push(ip),
+                    _ => (),
+                }
+            }
+        }
+        let ban = ServerBan::new(target, ips, user_id, msg.duration, msg.reason, Local::now());
+        self.schedule_server_ban_expiry(&ban, ctx);
+
+        // every session of the user or from a blocked address is closed
+        let event = ServerEvent::ServerBanned { ban: ban.clone() }.to_json();
+        self.send_to_session(msg.session, &event);
+        let banned: Vec<usize> = self.sessions
+            .iter()
+            .filter(|&(_, session)| ban.applies_to(Some(&session.user_id), session.ip))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in banned {
+            if let Some(session) = self.sessions.get(&id) {
+                let _ = session.addr.do_send(SessionMessage(event.clone()));
+                let _ = session.close.do_send(server_ban_notice());
+            }
+        }
+
+        let mut entry = AuditEntry::new(AuditAction::ServerBan, Some(user_id), ban.issued_at);
+        entry.target = Some(target);
+        entry.reason = ban.reason.clone();
+        let mut details: Vec<String> = ban.ips.iter().map(|ip| ip.to_string()).collect();
+        if let Some(expires_at) = ban.expires_at {
+            details.push(format!("until {}", expires_at.to_rfc3339()));
+        }
+        if !details.is_empty() {
+            entry.details = Some(details.join(", "));
+        }
+        self.record_audit(entry);
+        self.bans.add(ban);
+        self.dirty.insert("server_bans");
+    }
+}
+
+// Handler for LiftServerBan message.
+impl Handler<LiftServerBan> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: LiftServerBan, _: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        if !self.controller.is_admin(&user_id) {
+            return self.send_room_error(msg.session, RoomError::NotAllowed);
+        }
+        let target = match self.find_user_id(&msg.username) {
+            Ok(target) => target,
+            Err(err) => return self.send_room_error(msg.session, err),
+        };
+        let ban = match self.bans.remove(&target) {
+            Some(ban) => ban,
+            None => {
+                let event = ServerEvent::error(
+                    ErrorCode::NotBanned,
+                    "User is not banned from the server",
+                );
+                return self.send_to_session(msg.session, &event.to_json());
+            }
+        };
+        self.lift_server_ban(&ban, Some(user_id));
+        let event = ServerEvent::ServerBanLifted {
+            user: target,
+            lifted_by: user_id,
+        };
+        self.send_to_session(msg.session, &event.to_json());
+    }
+}
+
+// Handler for ListServerBans message.
+impl Handler<ListServerBans> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ListServerBans, _: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        if !self.controller.is_admin(&user_id) {
+            return self.send_room_error(msg.session, RoomError::NotAllowed);
+        }
+        let now = Local::now();
+        let event = ServerEvent::ServerBans {
+            bans: self.bans
+                .all()
+                .into_iter()
+                .filter(|ban| !ban.is_expired(now))
+                .collect(),
+        };
+        self.send_to_session(msg.session, &event.to_json());
+    }
+}
+
+// Handler for MarkRead message.
+impl Handler<MarkRead> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: MarkRead, _: &mut Context<Self>) {
+        let (user_id, username) = match self.sessions.get(&msg.session) {
+            Some(session) => (session.user_id, session.username.clone()),
+            None => return,
+        };
+        if !self.is_room_member(&msg.room, &user_id) {
+            return self.send_message_error(msg.session, MessageError::NotAllowed);
+        }
+
+        let advanced = match self.history.get(&msg.room) {
+            Some(history) => self.read_markers
+                .advance(user_id, &msg.room, msg.id, history),
+            None => false,
+        };
+        if !advanced {
+            return;
+        }
+        self.dirty.insert("read_markers");
+
+        if msg.receipt {
+            let event = ServerEvent::ReadReceipt {
+                room: msg.room.clone(),
+                user: user_id,
+                username: username,
+                id: msg.id,
+            };
+            self.send_message(&msg.room, &event.to_json());
+        }
+    }
+}
+
+// Handler for UnreadCounts message.
+impl Handler<UnreadCounts> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: UnreadCounts, _: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        let event = ServerEvent::UnreadCounts {
+            rooms: self.unread_counts(&user_id),
+        };
+        self.send_to_session(msg.session, &event.to_json());
+    }
+}
+
+// Handler for React message.
+impl Handler<React> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: React, _: &mut Context<Self>) {
+        let user_id = match self.sessions.get(&msg.session) {
+            Some(session) => session.user_id,
+            None => return,
+        };
+        let room = match self.find_message(&msg.id) {
+            Some(message) => message.room.clone(),
+            None => return self.send_message_error(msg.session, MessageError::UnknownMessage),
+        };
+        if !self.is_room_member(&room, &user_id) {
+            return self.send_message_error(msg.session, MessageError::NotAllowed);
+        }
+        if !self.check_sanctions(msg.session, &room, &user_id) {
+            return;
+        }
+        if !self.check_rate_limit(msg.session) {
+            return;
+        }
+
+        let add = msg.add;
+        let result = match self.find_message_mut(&msg.id) {
+            Some(message) => if add {
+                message.add_reaction(&msg.emoji, user_id)
+            } else {
+                Ok(message.remove_reaction(&msg.emoji, &user_id))
+            },
+            None => Err(MessageError::UnknownMessage),
+        };
+        match result {
+            // only changes are broadcast
+            Ok(Some(count)) => {
+                self.dirty.insert("messages");
+                let event = ServerEvent::Reaction {
+                    id: msg.id,
+                    room: room.clone(),
+                    emoji: msg.emoji,
+                    user: user_id,
+                    added: add,
+                    count: count,
+                };
+                self.send_message(&room, &event.to_json());
+            }
+            Ok(None) => (),
+            Err(err) => self.send_message_error(msg.session, err),
+        }
+    }
+}
+
+// Handler for AttachmentUploaded message.
+impl Handler<AttachmentUploaded> for ChatServer {
+    type Result = Result<Attachment, AttachmentError>;
+
+    fn handle(&mut self, msg: AttachmentUploaded, _: &mut Context<Self>) -> Self::Result {
+        // the blob is already written, a rejected upload must not leave it behind
+        let hash = msg.blob.hash.clone();
+        let result = self.share_attachment(msg);
+        if result.is_err() {
+            self.release_blob(&hash);
+        }
+        result
+    }
+}
+
+impl ChatServer {
+    fn share_attachment(&mut self, msg: AttachmentUploaded) -> Result<Attachment, AttachmentError> {
+        if let Some(session) = msg.session {
+            if !self.check_rate_limit(session) {
+                return Err(AttachmentError::RateLimited);
+            }
+        }
+        if self.controller.find_room_by_name(&msg.room).is_none() {
+            return Err(AttachmentError::UnknownRoom);
+        }
+        if !self.is_room_member(&msg.room, &msg.user_id) {
+            if let Some(session) = msg.session {
+                let event = ServerEvent::error(
+                    ErrorCode::NotMember,
+                    "Only members of the room can share files",
+                );
+                self.send_to_session(session, &event.to_json());
+            }
+            return Err(AttachmentError::NotMember);
+        }
+        let muted = self.controller
+            .find_room_by_name(&msg.room)
+            .map_or(false, |room| room.is_member_muted(&msg.user_id));
+        if muted {
+            if let Some(session) = msg.session {
+                self.check_sanctions(session, &msg.room, &msg.user_id);
+            }
+            return Err(AttachmentError::Muted);
+        }
+
+        // the upload id is chosen by the client, a clash must not replace another file
+        let id = match msg.upload_id {
+            Some(id) if !self.attachments.contains_key(&id) => id,
+            _ => Uuid::new_v4(),
+        };
+        let attachment = Attachment {
+            id,
+            filename: msg.filename,
+            size: msg.blob.size,
+            content_type: msg.blob.content_type,
+            hash: msg.blob.hash,
+            uploader: msg.user_id,
+            room: msg.room.clone(),
+            uploaded_at: Local::now(),
+        };
+        self.attachments.insert(id, attachment.clone());
+        self.dirty.insert("attachments");
+
+        let mut message = ChatMessage::new(msg.room, msg.user_id, msg.username, String::new());
+        message.attachment = Some(attachment.clone());
+        self.post_message(message);
+        Ok(attachment)
+    }
+
+    fn remove_attachment(&mut self, id: &Uuid) {
+        if let Some(attachment) = self.attachments.remove(id) {
+            self.dirty.insert("attachments");
+            self.release_blob(&attachment.hash);
+        }
+    }
+
+    // Remove a blob from disk unless an attachment still uses its content
+    fn release_blob(&self, hash: &str) {
+        if self.attachments.values().any(|attachment| attachment.hash == hash) {
+            return;
+        }
+        if let Err(err) = self.blobs.remove(hash) {
+            error!("Cannot remove attachment {}: {}", hash, err);
+        }
+    }
+}
+
+// Handler for FetchAttachment message.
+impl Handler<FetchAttachment> for ChatServer {
+    type Result = Result<Attachment, AttachmentError>;
+
+    fn handle(&mut self, msg: FetchAttachment, _: &mut Context<Self>) -> Self::Result {
+        let attachment = self.attachments
+            .get(&msg.id)
+            .cloned()
+            .ok_or(AttachmentError::UnknownAttachment)?;
+        if !self.is_room_member(&attachment.room, &msg.user_id) {
+            return Err(AttachmentError::NotMember);
+        }
+        Ok(attachment)
+    }
+}
+
+// Handler for Search message.
+impl Handler<Search> for ChatServer {
+    type Result = Result<Vec<SearchResult>, ()>;
+
+    fn handle(&mut self, msg: Search, _: &mut Context<Self>) -> Self::Result {
+        let user_id = msg.user_id;
+        Ok(self.search.search(&msg.query, |room| {
+            self.is_room_member(room, &user_id)
+        }))
+    }
+}
+
+// Handler for QueryAudit message.
+impl Handler<QueryAudit> for ChatServer {
+    type Result = Result<Vec<AuditEntry>, RoomError>;
+
+    fn handle(&mut self, msg: QueryAudit, _: &mut Context<Self>) -> Self::Result {
+        // moderators have to ask for one of their rooms, admins also for deleted ones
+        if !self.controller.is_admin(&msg.user_id) {
+            let room_id = match msg.query.room {
+                Some(ref name) => self.controller
+                    .find_room_by_name(name)
+                    .map(|room| room.copy_id())
+                    .ok_or(RoomError::UnknownRoom)?,
+                None => return Err(RoomError::NotAllowed),
+            };
+            self.controller
+                .check_room_manager(&room_id, &msg.user_id)?;
+        }
+        Ok(self.audit.query(&msg.query, |_| true))
+    }
+}
+
+// Handler for Shutdown message.
+impl Handler<Shutdown> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, _: Shutdown, _: &mut Context<Self>) {
+        self.draining = true;
+        let notice = "{\"message\": \"server is shutting down\", \"name\":\"server\"}";
+
+        for session in self.sessions.values() {
+            let _ = session.addr.do_send(SessionMessage(notice.to_owned()));
+            let _ = session.close.do_send(shutdown_notice());
+        }
+        self.flush();
+    }
+}
+
+// Handler for Authenticate message.
+impl Handler<Authenticate> for ChatServer {
+    type Result = Option<User>;
+
+    fn handle(&mut self, msg: Authenticate, _: &mut Context<Self>) -> Self::Result {
+        self.controller
+            .find_user_by_username(&msg.username)
+            .and_then(|user| {
+                if user.verify_password(&msg.password) {
+                    Some(user)
+                } else {
+                    None
+                }
+            })
     }
 }
@@ -1,2 +1,4 @@
+pub mod certreloader;
 pub mod chatserver;
+pub mod shutdown;
 pub mod websocket;
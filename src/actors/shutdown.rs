@@ -0,0 +1,91 @@
+// `ShutdownCoordinator` is an actor listening for process signals. On
+// SIGINT, SIGTERM or SIGQUIT the http servers stop accepting connections,
+// the chat server closes all sessions and flushes pending writes, then the
+// system exits. It exits anyway once the grace period has passed.
+
+use actix::actors::signal;
+use actix::prelude::*;
+use actix_web::server::StopServer;
+use actors::chatserver;
+use futures::future::join_all;
+use futures::Future;
+use std::time::Duration;
+
+pub struct ShutdownCoordinator {
+    pub chat_server: Addr<Syn, chatserver::ChatServer>,
+    pub http_servers: Vec<Recipient<Syn, StopServer>>,
+    pub grace_period: Duration,
+    stopping: bool,
+}
+
+impl ShutdownCoordinator {
+    pub fn new(
+        chat_server: Addr<Syn, chatserver::ChatServer>,
+        http_servers: Vec<Recipient<Syn, StopServer>>,
+        grace_period: Duration,
+    ) -> ShutdownCoordinator {
+        ShutdownCoordinator {
+            chat_server,
+            http_servers,
+            grace_period,
+            stopping: false,
+        }
+    }
+
+    fn shutdown(&mut self, ctx: &mut Context<Self>) {
+        if self.stopping {
+            return;
+        }
+        self.stopping = true;
+        info!("Shutting down, draining connections");
+
+        let servers: Vec<_> = self.http_servers
+            .iter()
+            .map(|server| server.send(StopServer { graceful: true }))
+            .collect();
+
+        self.chat_server
+            .send(chatserver::Shutdown)
+            .join(join_all(servers))
+            .into_actor(self)
+            .then(|_, _, _| {
+                info!("All connections drained");
+                exit();
+                actix::fut::ok(())
+            })
+            .spawn(ctx);
+
+        ctx.run_later(self.grace_period, |_, _| {
+            warn!("Grace period exceeded, closing remaining connections");
+            exit();
+        });
+    }
+}
+
+fn exit() {
+    Arbiter::system().do_send(actix::msgs::SystemExit(0));
+}
+
+impl Actor for ShutdownCoordinator {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let addr: Addr<Syn, _> = ctx.address();
+        Arbiter::system_registry()
+            .get::<signal::ProcessSignals>()
+            .do_send(signal::Subscribe(addr.recipient()));
+    }
+}
+
+impl Handler<signal::Signal> for ShutdownCoordinator {
+    type Result = ();
+
+    fn handle(&mut self, msg: signal::Signal, ctx: &mut Context<Self>) {
+        match msg.0 {
+            signal::SignalType::Int | signal::SignalType::Term | signal::SignalType::Quit => {
+                self.shutdown(ctx)
+            }
+            _ => (),
+        }
+    }
+}
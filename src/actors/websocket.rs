@@ -3,36 +3,72 @@ extern crate actix;
 use actix::prelude::*;
 use actix_web::{ws, Error, HttpRequest, HttpResponse};
 use actors::chatserver;
-use std::time::Instant;
+use attachments::{BlobStore, UploadAssembler, UploadError};
+use auth::TokenService;
+use config::Config;
+use protocol::{ClientCommand, ErrorCode, ServerEvent};
+use routes;
+use sanctions::SanctionKind;
+use server_bans::ServerBans;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use validation;
+
+// How often the client is pinged, in seconds
+const HEARTBEAT_INTERVAL: u64 = 5;
 
 // This is our websocket route state, this state is shared with all route instances
 // via `HttpContext::state()`
 pub struct WsChatSessionState {
     pub addr: Addr<Syn, chatserver::ChatServer>,
+    pub tokens: Arc<TokenService>,
+    pub bans: Arc<ServerBans>,
+    pub config: Arc<Config>,
+    pub blobs: BlobStore,
 }
 
 // Entry point for our route
 pub fn chat_route(req: HttpRequest<WsChatSessionState>) -> Result<HttpResponse, Error> {
-    ws::start(
-        req,
-        WsChatSession {
-            id: 0,
-            hb: Instant::now(),
-            room: "Main".to_owned(),
-            name: None,
-        },
-    )
+    let claims = routes::auth::authenticate(&req)?;
+    let ip = routes::auth::peer_ip(&req);
+    let room = req.state().config.default_room.clone();
+    let limits = req.state().config.limits.clone();
+
+    let session = WsChatSession {
+        id: 0,
+        user_id: claims.user_id,
+        ip: ip,
+        hb: Instant::now(),
+        room: room,
+        name: Some(claims.username),
+        uploads: UploadAssembler::new(limits.max_upload_size, limits.max_concurrent_uploads),
+    };
+
+    // same as `ws::start`, but with our frame size limit
+    let mut resp = ws::handshake(&req)?;
+    let stream = ws::WsStream::new(req.clone()).max_size(req.state().config.limits.max_frame_size);
+    let mut ctx = ws::WebsocketContext::new(req, session);
+    ctx.add_stream(stream);
+    Ok(resp.body(ctx))
 }
 
 pub struct WsChatSession {
     // unique session id
     id: usize,
-    // Client must send ping at least once per 10 seconds, otherwise we drop connection.
+    // authenticated user
+    user_id: Uuid,
+    // peer address, blocked along with the user by server bans
+    ip: Option<IpAddr>,
+    // Client must answer our pings within the heartbeat timeout, otherwise we drop connection.
     hb: Instant,
     // joined room
     room: String,
     // peer name
     name: Option<String>,
+    // attachment uploads in progress
+    uploads: UploadAssembler,
 }
 
 impl Actor for WsChatSession {
@@ -41,6 +77,8 @@ impl Actor for WsChatSession {
     /// Method is called on actor start.
     /// We register ws session with ChatServer
     fn started(&mut self, ctx: &mut Self::Context) {
+        self.heartbeat(ctx);
+
         // register self in chat server. `AsyncContext::wait` register
         // future within context, but context waits until this future resolves
         // before processing any other events.
@@ -50,7 +88,12 @@ impl Actor for WsChatSession {
         ctx.state()
             .addr
             .send(chatserver::Connect {
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                close: addr.clone().recipient(),
+                rename: addr.recipient(),
+                user_id: self.user_id,
+                username: self.name.clone().unwrap_or_default(),
+                ip: self.ip,
             })
             .into_actor(self)
             .then(|res, act, ctx| {
@@ -73,6 +116,328 @@ impl Actor for WsChatSession {
     }
 }
 
+impl WsChatSession {
+    // ping the client every few seconds and drop it once it stays silent for too long
+    fn heartbeat(&self, ctx: &mut <Self as Actor>::Context) {
+        ctx.run_interval(Duration::from_secs(HEARTBEAT_INTERVAL), |act, ctx| {
+            let timeout = Duration::from_secs(ctx.state().config.heartbeat_timeout);
+            if Instant::now().duration_since(act.hb) > timeout {
+                info!("Websocket client heartbeat failed, disconnecting!");
+                ctx.stop();
+                return;
+            }
+            ctx.ping("");
+        });
+    }
+
+    // validated message text, errors are reported to the client
+    fn normalize(&self, text: &str, ctx: &mut <Self as Actor>::Context) -> Option<String> {
+        let result = {
+            let limits = &ctx.state().config.limits;
+            validation::normalize_message(text, limits.max_message_length, limits.control_characters)
+        };
+        match result {
+            Ok(text) => Some(text),
+            Err(err) => {
+                ctx.text(ServerEvent::error(err.code(), &err.message()).to_json());
+                None
+            }
+        }
+    }
+
+    fn manage_room(
+        &self,
+        room: String,
+        action: chatserver::RoomAction,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        ctx.state().addr.do_send(chatserver::ManageRoom {
+            session: self.id,
+            room: room,
+            action: action,
+        });
+    }
+
+    fn manage_invites(
+        &self,
+        room: String,
+        action: chatserver::InviteAction,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        ctx.state().addr.do_send(chatserver::ManageInvites {
+            session: self.id,
+            room: room,
+            action: action,
+        });
+    }
+
+    fn manage_automod(
+        &self,
+        room: String,
+        action: chatserver::AutomodAction,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        ctx.state().addr.do_send(chatserver::ManageAutomod {
+            session: self.id,
+            room: room,
+            action: action,
+        });
+    }
+
+    fn sanction(
+        &self,
+        room: String,
+        user: String,
+        kind: SanctionKind,
+        duration: Option<u64>,
+        reason: Option<String>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        ctx.state().addr.do_send(chatserver::SanctionMember {
+            session: self.id,
+            room: room,
+            username: user,
+            kind: kind,
+            duration: duration,
+            reason: reason,
+        });
+    }
+
+    fn lift_sanction(
+        &self,
+        room: String,
+        user: String,
+        kind: SanctionKind,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        ctx.state().addr.do_send(chatserver::LiftSanction {
+            session: self.id,
+            room: room,
+            username: user,
+            kind: kind,
+        });
+    }
+
+    fn handle_command(&mut self, command: ClientCommand, ctx: &mut <Self as Actor>::Context) {
+        match command {
+            ClientCommand::Edit { id, text } => {
+                if let Some(text) = self.normalize(&text, ctx) {
+                    ctx.state().addr.do_send(chatserver::EditMessage {
+                        session: self.id,
+                        id: id,
+                        text: text,
+                    });
+                }
+            }
+            ClientCommand::Delete { id } => {
+                ctx.state().addr.do_send(chatserver::DeleteMessage {
+                    session: self.id,
+                    id: id,
+                });
+            }
+            ClientCommand::Reply { parent, text } => {
+                if let Some(text) = self.normalize(&text, ctx) {
+                    ctx.state().addr.do_send(chatserver::Message {
+                        id: self.id,
+                        msg: text,
+                        room: self.room.clone(),
+                        parent: Some(parent),
+                    });
+                }
+            }
+            ClientCommand::FetchThread { id } => {
+                ctx.state().addr.do_send(chatserver::FetchThread {
+                    session: self.id,
+                    id: id,
+                });
+            }
+            ClientCommand::Subscribe { id } => {
+                ctx.state().addr.do_send(chatserver::SubscribeThread {
+                    session: self.id,
+                    id: id,
+                    subscribe: true,
+                });
+            }
+            ClientCommand::Unsubscribe { id } => {
+                ctx.state().addr.do_send(chatserver::SubscribeThread {
+                    session: self.id,
+                    id: id,
+                    subscribe: false,
+                });
+            }
+            ClientCommand::MarkRead { room, id, receipt } => {
+                ctx.state().addr.do_send(chatserver::MarkRead {
+                    session: self.id,
+                    room: room,
+                    id: id,
+                    receipt: receipt,
+                });
+            }
+            ClientCommand::UnreadCounts => {
+                ctx.state()
+                    .addr
+                    .do_send(chatserver::UnreadCounts { session: self.id });
+            }
+            ClientCommand::UpdateRoom {
+                room,
+                topic,
+                name,
+                private,
+                hidden,
+            } => {
+                ctx.state().addr.do_send(chatserver::UpdateRoom {
+                    session: self.id,
+                    room: room,
+                    topic: topic,
+                    name: name,
+                    private: private,
+                    hidden: hidden,
+                });
+            }
+            ClientCommand::AddModerator { room, user } => {
+                self.manage_room(room, chatserver::RoomAction::AddModerator(user), ctx)
+            }
+            ClientCommand::RemoveModerator { room, user } => {
+                self.manage_room(room, chatserver::RoomAction::RemoveModerator(user), ctx)
+            }
+            ClientCommand::TransferOwnership { room, user } => {
+                self.manage_room(room, chatserver::RoomAction::TransferOwnership(user), ctx)
+            }
+            ClientCommand::DeleteRoom { room } => {
+                self.manage_room(room, chatserver::RoomAction::Delete, ctx)
+            }
+            ClientCommand::CreateInvite {
+                room,
+                expires_in,
+                max_uses,
+            } => self.manage_invites(
+                room,
+                chatserver::InviteAction::Create {
+                    expires_in: expires_in,
+                    max_uses: max_uses,
+                },
+                ctx,
+            ),
+            ClientCommand::ListInvites { room } => {
+                self.manage_invites(room, chatserver::InviteAction::List, ctx)
+            }
+            ClientCommand::RevokeInvite { room, code } => {
+                self.manage_invites(room, chatserver::InviteAction::Revoke(code), ctx)
+            }
+            ClientCommand::RedeemInvite { code } => {
+                ctx.state().addr.do_send(chatserver::RedeemInvite {
+                    session: self.id,
+                    code: code.trim().to_string(),
+                });
+            }
+            ClientCommand::RequestJoin { room } => {
+                ctx.state().addr.do_send(chatserver::RequestJoin {
+                    session: self.id,
+                    room: room,
+                });
+            }
+            ClientCommand::ListJoinRequests { room } => {
+                ctx.state().addr.do_send(chatserver::ListJoinRequests {
+                    session: self.id,
+                    room: room,
+                });
+            }
+            ClientCommand::ApproveJoin { id } => {
+                ctx.state().addr.do_send(chatserver::AnswerJoinRequest {
+                    session: self.id,
+                    id: id,
+                    approve: true,
+                });
+            }
+            ClientCommand::DenyJoin { id } => {
+                ctx.state().addr.do_send(chatserver::AnswerJoinRequest {
+                    session: self.id,
+                    id: id,
+                    approve: false,
+                });
+            }
+            ClientCommand::Ban {
+                room,
+                user,
+                duration,
+                reason,
+            } => self.sanction(room, user, SanctionKind::Ban, duration, reason, ctx),
+            ClientCommand::Unban { room, user } => {
+                self.lift_sanction(room, user, SanctionKind::Ban, ctx)
+            }
+            ClientCommand::Mute {
+                room,
+                user,
+                duration,
+                reason,
+            } => self.sanction(room, user, SanctionKind::Mute, duration, reason, ctx),
+            ClientCommand::Unmute { room, user } => {
+                self.lift_sanction(room, user, SanctionKind::Mute, ctx)
+            }
+            ClientCommand::Kick { room, user, reason } => {
+                ctx.state().addr.do_send(chatserver::KickMember {
+                    session: self.id,
+                    room: room,
+                    username: user,
+                    reason: reason,
+                });
+            }
+            ClientCommand::ServerBan {
+                user,
+                duration,
+                reason,
+                block_ip,
+            } => {
+                ctx.state().addr.do_send(chatserver::BanFromServer {
+                    session: self.id,
+                    username: user,
+                    duration: duration,
+                    reason: reason,
+                    block_ip: block_ip,
+                });
+            }
+            ClientCommand::ServerUnban { user } => {
+                ctx.state().addr.do_send(chatserver::LiftServerBan {
+                    session: self.id,
+                    username: user,
+                });
+            }
+            ClientCommand::ListServerBans => {
+                ctx.state()
+                    .addr
+                    .do_send(chatserver::ListServerBans { session: self.id });
+            }
+            ClientCommand::AddAutomodRule {
+                room,
+                filter,
+                action,
+            } => self.manage_automod(room, chatserver::AutomodAction::Add(filter, action), ctx),
+            ClientCommand::RemoveAutomodRule { room, id } => {
+                self.manage_automod(room, chatserver::AutomodAction::Remove(id), ctx)
+            }
+            ClientCommand::ListAutomodRules { room } => {
+                self.manage_automod(room, chatserver::AutomodAction::List, ctx)
+            }
+            ClientCommand::React { id, emoji } => {
+                ctx.state().addr.do_send(chatserver::React {
+                    session: self.id,
+                    id: id,
+                    emoji: emoji,
+                    add: true,
+                });
+            }
+            ClientCommand::Unreact { id, emoji } => {
+                ctx.state().addr.do_send(chatserver::React {
+                    session: self.id,
+                    id: id,
+                    emoji: emoji,
+                    add: false,
+                });
+            }
+        }
+    }
+}
+
 /// Handle messages from chat server, we simply send it to peer websocket
 impl Handler<chatserver::SessionMessage> for WsChatSession {
     type Result = ();
@@ -82,32 +447,111 @@ impl Handler<chatserver::SessionMessage> for WsChatSession {
     }
 }
 
+/// Close the websocket on behalf of the chat server
+impl Handler<chatserver::SessionClose> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: chatserver::SessionClose, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: msg.code,
+            description: Some(msg.reason),
+        }));
+        ctx.stop();
+    }
+}
+
+/// Follow the room of the session to its new name
+impl Handler<chatserver::RoomRenamed> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: chatserver::RoomRenamed, _: &mut Self::Context) {
+        if self.room == msg.from {
+            self.room = msg.to;
+        }
+    }
+}
+
 // WebSocket message handler
 impl StreamHandler<ws::Message, ws::ProtocolError> for WsChatSession {
     fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
-        println!("WEBSOCKET MESSAGE: {:?}", msg);
+        trace!("Websocket message: {:?}", msg);
         match msg {
-            ws::Message::Ping(msg) => ctx.pong(&msg),
+            ws::Message::Ping(msg) => {
+                self.hb = Instant::now();
+                ctx.pong(&msg);
+            }
             ws::Message::Pong(_msg) => self.hb = Instant::now(),
             ws::Message::Text(text) => {
-                let m = text.trim();
-
-                let msg = if let Some(ref name) = self.name {
-                    format!("{}: {}", name, m)
-                } else {
-                    m.to_owned()
+                self.hb = Instant::now();
+                if let Some(command) = ClientCommand::parse(&text) {
+                    self.handle_command(command, ctx);
+                    return;
+                }
+                let m = match self.normalize(&text, ctx) {
+                    Some(m) => m,
+                    None => return,
                 };
+
                 // send message to chat server
                 ctx.state().addr.do_send(chatserver::Message {
                     id: self.id,
-                    msg: msg,
+                    msg: m,
                     room: self.room.clone(),
+                    parent: None,
                 })
             }
-            ws::Message::Binary(_bin) => println!("Unexpected binary"),
+            ws::Message::Binary(bin) => {
+                self.hb = Instant::now();
+                match self.uploads.push(bin.as_ref()) {
+                    Ok(Some(upload)) => {
+                        let blob = match ctx.state().blobs.put(&upload.data) {
+                            Ok(blob) => blob,
+                            Err(err) => {
+                                error!("Cannot store attachment: {}", err);
+                                let event = ServerEvent::error(
+                                    ErrorCode::InvalidUpload,
+                                    "Cannot store attachment",
+                                );
+                                ctx.text(event.to_json());
+                                return;
+                            }
+                        };
+                        ctx.state().addr.do_send(chatserver::AttachmentUploaded {
+                            session: Some(self.id),
+                            user_id: self.user_id,
+                            username: self.name.clone().unwrap_or_default(),
+                            room: self.room.clone(),
+                            upload_id: Some(upload.id),
+                            filename: upload.filename,
+                            blob: blob,
+                        });
+                    }
+                    Ok(None) => (),
+                    Err(err) => {
+                        let code = match err {
+                            UploadError::TooLarge(_, _) => ErrorCode::UploadTooLarge,
+                            _ => ErrorCode::InvalidUpload,
+                        };
+                        ctx.text(ServerEvent::error(code, &err.message()).to_json());
+                    }
+                }
+            }
             ws::Message::Close(_) => {
                 ctx.stop();
             }
         }
     }
+
+    fn error(&mut self, err: ws::ProtocolError, ctx: &mut Self::Context) -> Running {
+        if let ws::ProtocolError::Overflow = err {
+            let max = ctx.state().config.limits.max_frame_size;
+            let message = format!("Frames must not be larger than {} bytes", max);
+            ctx.text(ServerEvent::error(ErrorCode::FrameTooLarge, &message).to_json());
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Size,
+                description: Some(message),
+            }));
+        }
+        Running::Stop
+    }
 }
@@ -0,0 +1,50 @@
+// Content type detection by magic bytes, the type given by the client is
+// never trusted.
+
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"ID3", "audio/mpeg"),
+    (b"OggS", "audio/ogg"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+];
+
+pub fn sniff(data: &[u8]) -> &'static str {
+    for &(signature, content_type) in SIGNATURES {
+        if data.starts_with(signature) {
+            return content_type;
+        }
+    }
+
+    // RIFF containers and ISO media carry their kind at a fixed offset
+    if data.len() >= 12 && data.starts_with(b"RIFF") {
+        match &data[8..12] {
+            b"WEBP" => return "image/webp",
+            b"WAVE" => return "audio/wav",
+            _ => (),
+        }
+    }
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        return "video/mp4";
+    }
+
+    if !data.contains(&0) && ::std::str::from_utf8(data).is_ok() {
+        return "text/plain; charset=utf-8";
+    }
+    "application/octet-stream"
+}
+
+#[test]
+fn test_sniff() {
+    assert_eq!("image/png", sniff(b"\x89PNG\r\n\x1a\n\x00\x00"));
+    assert_eq!("image/webp", sniff(b"RIFF\x00\x00\x00\x00WEBPVP8 "));
+    assert_eq!("text/plain; charset=utf-8", sniff("grüß dich".as_bytes()));
+    // html is served as plain text, never as a page
+    assert_eq!("text/plain; charset=utf-8", sniff(b"<html><script>"));
+    assert_eq!("application/octet-stream", sniff(b"\x00\x01\x02\xff"));
+}
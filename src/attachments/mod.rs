@@ -0,0 +1,38 @@
+// File attachments posted to rooms.
+//
+// Files are uploaded in chunks over the websocket or in one piece over http,
+// their content goes to the content addressed `BlobStore` while the chat
+// server keeps the `Attachment` records and decides who may download them.
+
+pub mod mime;
+pub mod store;
+pub mod upload;
+
+use chrono::{DateTime, Local};
+use uuid::Uuid;
+
+pub use self::store::{Blob, BlobStore};
+pub use self::upload::{UploadAssembler, UploadError};
+
+// Announced file, chat messages reference it by id
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: Uuid,
+    pub filename: String,
+    pub size: usize,
+    pub content_type: String,
+    // sha256 of the content, the key in the blob store
+    pub hash: String,
+    pub uploader: Uuid,
+    pub room: String,
+    pub uploaded_at: DateTime<Local>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttachmentError {
+    UnknownRoom,
+    UnknownAttachment,
+    NotMember,
+    RateLimited,
+    Muted,
+}
@@ -0,0 +1,106 @@
+// Content addressed file storage on local disk.
+//
+// A blob is stored as `<dir>/<first two hex digits>/<sha256 hex>`, uploading
+// the same content twice keeps a single copy.
+
+use attachments::mime;
+use openssl::sha::sha256;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Blob {
+    pub hash: String,
+    pub size: usize,
+    pub content_type: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    pub fn new(dir: &str) -> io::Result<BlobStore> {
+        fs::create_dir_all(dir)?;
+        Ok(BlobStore {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    pub fn put(&self, data: &[u8]) -> io::Result<Blob> {
+        let hash: String = sha256(data)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let path = self.path(&hash);
+
+        if !path.exists() {
+            fs::create_dir_all(path.parent().unwrap())?;
+            // unique temporary name, concurrent uploads of the same content must not clash
+            let temp = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+            File::create(&temp)?.write_all(data)?;
+            fs::rename(&temp, &path)?;
+        }
+
+        Ok(Blob {
+            hash,
+            size: data.len(),
+            content_type: mime::sniff(data).to_string(),
+        })
+    }
+
+    pub fn get(&self, hash: &str) -> io::Result<Vec<u8>> {
+        check_hash(hash)?;
+        fs::read(self.path(hash))
+    }
+
+    // A blob that is already gone counts as removed
+    pub fn remove(&self, hash: &str) -> io::Result<()> {
+        check_hash(hash)?;
+        match fs::remove_file(self.path(hash)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn path(&self, hash: &str) -> PathBuf {
+        self.dir.join(&hash[..2]).join(hash)
+    }
+}
+
+// hashes come from clients, they must not name other files
+fn check_hash(hash: &str) -> io::Result<()> {
+    if hash.len() != 64 || !hash.chars().all(|c| c.is_digit(16)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid hash"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_blob_store() {
+    use std::env;
+
+    let dir = env::temp_dir().join(format!("alienchat-{}", Uuid::new_v4()));
+    let store = BlobStore::new(dir.to_str().unwrap()).unwrap();
+
+    let blob = store.put(b"hello").unwrap();
+    assert_eq!(
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+        blob.hash
+    );
+    assert_eq!(5, blob.size);
+    assert_eq!("text/plain; charset=utf-8", blob.content_type);
+    assert_eq!(blob, store.put(b"hello").unwrap());
+    assert_eq!(b"hello".to_vec(), store.get(&blob.hash).unwrap());
+    assert!(store.get("../../etc/passwd").is_err());
+
+    store.remove(&blob.hash).unwrap();
+    assert!(store.get(&blob.hash).is_err());
+    store.remove(&blob.hash).unwrap();
+    assert!(store.remove("../../etc/passwd").is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+}
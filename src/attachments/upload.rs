@@ -0,0 +1,234 @@
+// Chunked attachment uploads over websocket binary frames.
+//
+// Every binary frame carries one chunk:
+//
+//     upload id       16 bytes, uuid chosen by the client
+//     sequence         4 bytes, big endian, starting at 0
+//     flags            1 byte, bit 0 marks the final chunk
+//     name length      2 bytes, big endian
+//     name             file name, only read from the first chunk
+//     data             rest of the frame
+//
+// Chunks of an upload must arrive in order, the upload is complete with the
+// final chunk.
+
+use byteorder::{BigEndian, ReadBytesExt};
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use uuid::Uuid;
+
+const FLAG_FINAL: u8 = 1;
+const HEADER_SIZE: usize = 16 + 4 + 1 + 2;
+const MAX_FILENAME_LENGTH: usize = 255;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UploadError {
+    Malformed,
+    OutOfOrder(Uuid),
+    TooLarge(Uuid, usize),
+    TooManyUploads,
+}
+
+impl UploadError {
+    pub fn message(&self) -> String {
+        match *self {
+            UploadError::Malformed => "Malformed upload chunk".to_string(),
+            UploadError::OutOfOrder(id) => format!("Upload {} chunk out of order", id),
+            UploadError::TooLarge(id, max) => {
+                format!("Upload {} is larger than {} bytes", id, max)
+            }
+            UploadError::TooManyUploads => "Too many uploads in progress".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Chunk<'a> {
+    pub upload_id: Uuid,
+    pub sequence: u32,
+    pub last: bool,
+    pub filename: String,
+    pub data: &'a [u8],
+}
+
+pub fn parse_chunk(frame: &[u8]) -> Result<Chunk, UploadError> {
+    if frame.len() < HEADER_SIZE {
+        return Err(UploadError::Malformed);
+    }
+    let upload_id = Uuid::from_bytes(&frame[..16]).map_err(|_| UploadError::Malformed)?;
+
+    let mut cursor = Cursor::new(&frame[16..]);
+    let sequence = cursor
+        .read_u32::<BigEndian>()
+        .map_err(|_| UploadError::Malformed)?;
+    let flags = cursor.read_u8().map_err(|_| UploadError::Malformed)?;
+    let name_length = cursor
+        .read_u16::<BigEndian>()
+        .map_err(|_| UploadError::Malformed)? as usize;
+
+    let mut name = vec![0; name_length];
+    cursor
+        .read_exact(&mut name)
+        .map_err(|_| UploadError::Malformed)?;
+    let filename = String::from_utf8(name).map_err(|_| UploadError::Malformed)?;
+
+    Ok(Chunk {
+        upload_id,
+        sequence,
+        last: flags & FLAG_FINAL != 0,
+        filename: sanitize_filename(&filename),
+        data: &frame[HEADER_SIZE + name_length..],
+    })
+}
+
+// keeps only the last path component and drops control characters
+pub fn sanitize_filename(name: &str) -> String {
+    let name: String = name.rsplit(|c| c == '/' || c == '\\')
+        .next()
+        .unwrap_or("")
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_FILENAME_LENGTH)
+        .collect();
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        "attachment".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+#[derive(Debug)]
+struct Upload {
+    filename: String,
+    data: Vec<u8>,
+    next_sequence: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CompletedUpload {
+    pub id: Uuid,
+    pub filename: String,
+    pub data: Vec<u8>,
+}
+
+// Reassembles the uploads of a single session
+#[derive(Debug)]
+pub struct UploadAssembler {
+    uploads: HashMap<Uuid, Upload>,
+    max_size: usize,
+    max_uploads: usize,
+}
+
+impl UploadAssembler {
+    pub fn new(max_size: usize, max_uploads: usize) -> UploadAssembler {
+        UploadAssembler {
+            uploads: HashMap::new(),
+            max_size,
+            max_uploads,
+        }
+    }
+
+    // adds a frame, returns the upload once its final chunk arrived
+    pub fn push(&mut self, frame: &[u8]) -> Result<Option<CompletedUpload>, UploadError> {
+        let chunk = parse_chunk(frame)?;
+
+        if chunk.sequence == 0 {
+            if !self.uploads.contains_key(&chunk.upload_id)
+                && self.uploads.len() >= self.max_uploads
+            {
+                return Err(UploadError::TooManyUploads);
+            }
+            self.uploads.insert(
+                chunk.upload_id,
+                Upload {
+                    filename: chunk.filename.clone(),
+                    data: Vec::new(),
+                    next_sequence: 0,
+                },
+            );
+        }
+
+        let size = {
+            let upload = match self.uploads.get_mut(&chunk.upload_id) {
+                Some(upload) => upload,
+                None => return Err(UploadError::OutOfOrder(chunk.upload_id)),
+            };
+            if upload.next_sequence != chunk.sequence {
+                None
+            } else {
+                upload.next_sequence += 1;
+                upload.data.extend_from_slice(chunk.data);
+                Some(upload.data.len())
+            }
+        };
+
+        match size {
+            None => {
+                self.uploads.remove(&chunk.upload_id);
+                Err(UploadError::OutOfOrder(chunk.upload_id))
+            }
+            Some(size) if size > self.max_size => {
+                self.uploads.remove(&chunk.upload_id);
+                Err(UploadError::TooLarge(chunk.upload_id, self.max_size))
+            }
+            Some(_) if chunk.last => {
+                let upload = self.uploads.remove(&chunk.upload_id).unwrap();
+                Ok(Some(CompletedUpload {
+                    id: chunk.upload_id,
+                    filename: upload.filename,
+                    data: upload.data,
+                }))
+            }
+            Some(_) => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+fn build_chunk(id: &Uuid, sequence: u32, last: bool, name: &str, data: &[u8]) -> Vec<u8> {
+    use byteorder::WriteBytesExt;
+
+    let mut frame = id.as_bytes().to_vec();
+    frame.write_u32::<BigEndian>(sequence).unwrap();
+    frame.write_u8(if last { FLAG_FINAL } else { 0 }).unwrap();
+    frame.write_u16::<BigEndian>(name.len() as u16).unwrap();
+    frame.extend_from_slice(name.as_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+#[test]
+fn test_upload_reassembly() {
+    let id = Uuid::new_v4();
+    let mut assembler = UploadAssembler::new(10, 1);
+
+    assert_eq!(
+        Ok(None),
+        assembler.push(&build_chunk(&id, 0, false, "../notes.txt", b"hello "))
+    );
+    assert_eq!(
+        Err(UploadError::TooManyUploads),
+        assembler.push(&build_chunk(&Uuid::new_v4(), 0, false, "", b""))
+    );
+    assert_eq!(
+        Ok(Some(CompletedUpload {
+            id,
+            filename: "notes.txt".to_string(),
+            data: b"hello you".to_vec(),
+        })),
+        assembler.push(&build_chunk(&id, 1, true, "", b"you"))
+    );
+
+    assert_eq!(Err(UploadError::Malformed), assembler.push(b"short"));
+    assert_eq!(
+        Err(UploadError::OutOfOrder(id)),
+        assembler.push(&build_chunk(&id, 3, true, "", b""))
+    );
+    assembler.push(&build_chunk(&id, 0, false, "", b"0123456789"))
+        .unwrap();
+    assert_eq!(
+        Err(UploadError::TooLarge(id, 10)),
+        assembler.push(&build_chunk(&id, 1, true, "", b"!"))
+    );
+}
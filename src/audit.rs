@@ -0,0 +1,231 @@
+// Audit log of moderation actions.
+//
+// Entries are only ever appended, nothing changes or removes them. Ids of
+// rooms and users change with every restart, entries are searched by the
+// names they had at the time of the action.
+
+use chrono::{DateTime, Local};
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Ban,
+    Unban,
+    Mute,
+    Unmute,
+    Kick,
+    AddModerator,
+    RemoveModerator,
+    TransferOwnership,
+    GrantRole,
+    RevokeRole,
+    DeleteMessage,
+    UpdateRoom,
+    DeleteRoom,
+    ServerBan,
+    ServerUnban,
+    UpdateAutomod,
+}
+
+impl FromStr for AuditAction {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<AuditAction, ()> {
+        match value {
+            "ban" => Ok(AuditAction::Ban),
+            "unban" => Ok(AuditAction::Unban),
+            "mute" => Ok(AuditAction::Mute),
+            "unmute" => Ok(AuditAction::Unmute),
+            "kick" => Ok(AuditAction::Kick),
+            "add_moderator" => Ok(AuditAction::AddModerator),
+            "remove_moderator" => Ok(AuditAction::RemoveModerator),
+            "transfer_ownership" => Ok(AuditAction::TransferOwnership),
+            "grant_role" => Ok(AuditAction::GrantRole),
+            "revoke_role" => Ok(AuditAction::RevokeRole),
+            "delete_message" => Ok(AuditAction::DeleteMessage),
+            "update_room" => Ok(AuditAction::UpdateRoom),
+            "delete_room" => Ok(AuditAction::DeleteRoom),
+            "server_ban" => Ok(AuditAction::ServerBan),
+            "server_unban" => Ok(AuditAction::ServerUnban),
+            "update_automod" => Ok(AuditAction::UpdateAutomod),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: Uuid,
+    pub action: AuditAction,
+    // none for the server itself, e.g. when a sanction expires
+    pub actor: Option<Uuid>,
+    #[serde(default)]
+    pub actor_name: Option<String>,
+    pub target: Option<Uuid>,
+    #[serde(default)]
+    pub target_name: Option<String>,
+    pub room: Option<Uuid>,
+    pub room_name: Option<String>,
+    pub reason: Option<String>,
+    // e.g. the changed settings or the id of the deleted message
+    pub details: Option<String>,
+    pub at: DateTime<Local>,
+}
+
+impl AuditEntry {
+    pub fn new(action: AuditAction, actor: Option<Uuid>, at: DateTime<Local>) -> AuditEntry {
+        AuditEntry {
+            id: Uuid::new_v4(),
+            action,
+            actor,
+            actor_name: None,
+            target: None,
+            target_name: None,
+            room: None,
+            room_name: None,
+            reason: None,
+            details: None,
+            at,
+        }
+    }
+
+    pub fn for_room(
+        action: AuditAction,
+        actor: Option<Uuid>,
+        room: Uuid,
+        room_name: String,
+        target: Option<Uuid>,
+        at: DateTime<Local>,
+    ) -> AuditEntry {
+        AuditEntry {
+            target,
+            room: Some(room),
+            room_name: Some(room_name),
+            ..AuditEntry::new(action, actor, at)
+        }
+    }
+}
+
+// All filters are optional, rooms and users by name
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    pub room: Option<String>,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub action: Option<AuditAction>,
+    pub from: Option<DateTime<Local>>,
+    pub until: Option<DateTime<Local>>,
+    pub limit: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuditLog {
+    // oldest first
+    entries: Vec<AuditEntry>,
+}
+
+impl AuditLog {
+    pub fn new() -> AuditLog {
+        AuditLog::default()
+    }
+
+    pub fn record(&mut self, entry: AuditEntry) {
+        self.entries.push(entry);
+    }
+
+    // newest entries first, only those `visible` accepts
+    pub fn query<F>(&self, query: &AuditQuery, visible: F) -> Vec<AuditEntry>
+    where
+        F: Fn(&AuditEntry) -> bool,
+    {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|entry| {
+                matches(&query.room, &entry.room_name)
+                    && matches(&query.actor, &entry.actor_name)
+                    && matches(&query.target, &entry.target_name)
+                    && query.action.map_or(true, |action| entry.action == action)
+                    && query.from.map_or(true, |from| entry.at >= from)
+                    && query.until.map_or(true, |until| entry.at <= until)
+                    && visible(entry)
+            })
+            .take(query.limit)
+            .cloned()
+            .collect()
+    }
+}
+
+fn matches(filter: &Option<String>, name: &Option<String>) -> bool {
+    filter.is_none() || filter == name
+}
+
+#[test]
+fn test_audit_log() {
+    use chrono::Duration;
+
+    let room = Uuid::new_v4();
+    let moderator = Uuid::new_v4();
+    let user = Uuid::new_v4();
+    let now = Local::now();
+    let mut log = AuditLog::new();
+
+    let mut ban = AuditEntry::for_room(
+        AuditAction::Ban,
+        Some(moderator),
+        room,
+        "Main".to_string(),
+        Some(user),
+        now,
+    );
+    ban.actor_name = Some("moderator".to_string());
+    ban.target_name = Some("user".to_string());
+    ban.reason = Some("spam".to_string());
+    log.record(ban.clone());
+    let mut unban = AuditEntry::for_room(
+        AuditAction::Unban,
+        None,
+        room,
+        "Main".to_string(),
+        Some(user),
+        now + Duration::minutes(5),
+    );
+    unban.target_name = Some("user".to_string());
+    log.record(unban.clone());
+    log.record(AuditEntry::new(AuditAction::GrantRole, Some(moderator), now));
+
+    let all = AuditQuery {
+        limit: 10,
+        ..AuditQuery::default()
+    };
+    assert_eq!(3, log.query(&all, |_| true).len());
+    assert_eq!(
+        vec![unban.clone(), ban.clone()],
+        log.query(&all, |entry| entry.room == Some(room))
+    );
+
+    let mut by_moderator = all.clone();
+    by_moderator.actor = Some("moderator".to_string());
+    by_moderator.room = Some("Main".to_string());
+    assert_eq!(vec![ban.clone()], log.query(&by_moderator, |_| true));
+
+    let mut bans = all.clone();
+    bans.action = "ban".parse().ok();
+    bans.until = Some(now + Duration::minutes(1));
+    assert_eq!(vec![ban], log.query(&bans, |_| true));
+
+    let mut latest = all.clone();
+    latest.room = Some("Main".to_string());
+    latest.limit = 1;
+    assert_eq!(vec![unban], log.query(&latest, |_| true));
+    assert_eq!(Err(()), "warn".parse::<AuditAction>());
+
+    // names still match once the ids changed with a restart
+    let mut about_user = all.clone();
+    about_user.target = Some("user".to_string());
+    assert_eq!(2, log.query(&about_user, |_| true).len());
+    about_user.target = Some("moderator".to_string());
+    assert!(log.query(&about_user, |_| true).is_empty());
+}
@@ -0,0 +1,190 @@
+// Signed session tokens.
+//
+// A token is the hex encoded json of its `Claims` followed by a dot and the hex
+// encoded HMAC-SHA256 of that payload. Tokens expire after a fixed lifetime and
+// can be revoked server side, revoked ids are kept until the token would have
+// expired anyway.
+
+use chrono::Utc;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use role::Role;
+use serde_json;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use user::User;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+    pub token_id: Uuid,
+    pub user_id: Uuid,
+    pub username: String,
+    pub roles: Vec<Role>,
+    pub issued_at: i64,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenError {
+    Malformed,
+    BadSignature,
+    Expired,
+    Revoked,
+}
+
+#[derive(Debug)]
+pub struct TokenService {
+    secret: Vec<u8>,
+    lifetime: i64,
+    // token id -> expiry of the revoked token
+    revoked: RwLock<HashMap<Uuid, i64>>,
+}
+
+impl TokenService {
+    pub fn new(secret: Vec<u8>, lifetime: i64) -> TokenService {
+        TokenService {
+            secret,
+            lifetime,
+            revoked: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // service with a random secret, tokens do not survive a restart
+    pub fn with_random_secret(lifetime: i64) -> TokenService {
+        let mut secret = vec![0; 32];
+        rand_bytes(&mut secret).expect("Cannot generate token secret");
+        TokenService::new(secret, lifetime)
+    }
+
+    pub fn issue(&self, user: &User) -> (String, Claims) {
+        self.issue_for(user.copy_id(), user.get_username().to_string(), user.copy_roles())
+    }
+
+    pub fn issue_for(&self, user_id: Uuid, username: String, roles: Vec<Role>) -> (String, Claims) {
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            token_id: Uuid::new_v4(),
+            user_id,
+            username,
+            roles,
+            issued_at: now,
+            expires_at: now + self.lifetime,
+        };
+        (self.encode(&claims), claims)
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, TokenError> {
+        let mut parts = token.splitn(2, '.');
+        let payload = parts.next().ok_or(TokenError::Malformed)?;
+        let signature = parts
+            .next()
+            .and_then(from_hex)
+            .ok_or(TokenError::Malformed)?;
+
+        let expected = self.sign(payload.as_bytes());
+        if expected.len() != signature.len() || !memcmp::eq(&expected, &signature) {
+            return Err(TokenError::BadSignature);
+        }
+
+        let claims: Claims = from_hex(payload)
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or(TokenError::Malformed)?;
+
+        if claims.expires_at <= Utc::now().timestamp() {
+            return Err(TokenError::Expired);
+        }
+        if self.is_revoked(&claims.token_id) {
+            return Err(TokenError::Revoked);
+        }
+        Ok(claims)
+    }
+
+    // exchanges a valid token for a fresh one, the old token is revoked
+    pub fn refresh(&self, token: &str) -> Result<(String, Claims), TokenError> {
+        let claims = self.verify(token)?;
+        self.revoke(&claims);
+        Ok(self.issue_for(claims.user_id, claims.username, claims.roles))
+    }
+
+    pub fn revoke(&self, claims: &Claims) {
+        let now = Utc::now().timestamp();
+        let mut revoked = self.revoked.write().unwrap();
+        revoked.retain(|_, expires_at| *expires_at > now);
+        revoked.insert(claims.token_id, claims.expires_at);
+    }
+
+    pub fn is_revoked(&self, token_id: &Uuid) -> bool {
+        self.revoked.read().unwrap().contains_key(token_id)
+    }
+
+    fn encode(&self, claims: &Claims) -> String {
+        let payload = to_hex(&serde_json::to_vec(claims).unwrap());
+        let signature = to_hex(&self.sign(payload.as_bytes()));
+        format!("{}.{}", payload, signature)
+    }
+
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        let key = PKey::hmac(&self.secret).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        signer.update(data).unwrap();
+        signer.sign_to_vec().unwrap()
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(data: &str) -> Option<Vec<u8>> {
+    if data.len() % 2 != 0 {
+        return None;
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|i| {
+            data.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect()
+}
+
+#[test]
+fn test_token_roundtrip() {
+    let user = User::new(
+        "user@example.com".to_string(),
+        "user1".to_string(),
+        "username1".to_string(),
+        "password1".to_string(),
+    );
+    let service = TokenService::with_random_secret(60);
+    let (token, claims) = service.issue(&user);
+
+    let verified = service.verify(&token).unwrap();
+    assert_eq!(claims, verified);
+    assert_eq!(*user.get_id(), verified.user_id);
+    assert_eq!("username1", verified.username);
+
+    let other = TokenService::with_random_secret(60);
+    assert_eq!(Err(TokenError::BadSignature), other.verify(&token));
+    assert_eq!(Err(TokenError::Malformed), service.verify("garbage"));
+}
+
+#[test]
+fn test_token_expiry_and_revocation() {
+    let service = TokenService::with_random_secret(0);
+    let (token, _) = service.issue_for(Uuid::new_v4(), "user".to_string(), Vec::new());
+    assert_eq!(Err(TokenError::Expired), service.verify(&token));
+
+    let service = TokenService::with_random_secret(60);
+    let (token, _) = service.issue_for(Uuid::new_v4(), "user".to_string(), Vec::new());
+    let (refreshed, claims) = service.refresh(&token).unwrap();
+    assert_eq!(Err(TokenError::Revoked), service.verify(&token));
+    assert!(service.verify(&refreshed).is_ok());
+
+    service.revoke(&claims);
+    assert_eq!(Err(TokenError::Revoked), service.verify(&refreshed));
+}
@@ -0,0 +1,509 @@
+// Automatic moderation of messages, configured per room.
+//
+// The moderators of a room add rules, every rule pairs a filter with an
+// action. The chat server runs the rules of the room in order before a message
+// is broadcast: the first rule that blocks or mutes stops the message, rules
+// that replace rewrite it for the rules after them, flags only tell the
+// moderators.
+
+use chrono::{DateTime, Local};
+use protocol::ErrorCode;
+use regex::{self, Captures, Regex, RegexBuilder};
+use sanctions::MAX_DURATION;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// most rules per room
+pub const MAX_RULES: usize = 50;
+
+// most words of a word filter
+const MAX_WORDS: usize = 200;
+
+// compiled size limit of a pattern, in bytes
+const PATTERN_SIZE_LIMIT: usize = 1 << 16;
+
+// links with a scheme or starting with `www.`, the host is the first group
+const LINK_PATTERN: &str = r"(?i)\b(?:https?://|www\.)([^\s/?#:]+)[^\s]*";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Filter {
+    // whole words, ignoring case
+    Words { words: Vec<String> },
+    Regex { pattern: String },
+    // domains, subdomains included, with an allow list only its domains may be linked
+    Links {
+        #[serde(default)]
+        allow: Vec<String>,
+        #[serde(default)]
+        deny: Vec<String>,
+    },
+    // share of capital letters in percent, only for messages with `min_letters` letters
+    Caps {
+        max_percent: u8,
+        #[serde(default = "default_min_letters")]
+        min_letters: usize,
+    },
+    // longest run of the same character
+    Repeat { max_run: usize },
+}
+
+fn default_min_letters() -> usize {
+    10
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Block,
+    // matches are starred out, shouting is lowercased and runs are shortened
+    Replace,
+    Flag,
+    // the message is blocked and the author muted for the seconds given
+    Mute(u64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AutomodError {
+    InvalidRule(&'static str),
+    UnknownRule,
+    TooManyRules,
+}
+
+impl AutomodError {
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            AutomodError::InvalidRule(_) => ErrorCode::InvalidRule,
+            AutomodError::UnknownRule => ErrorCode::UnknownRule,
+            AutomodError::TooManyRules => ErrorCode::TooManyRules,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match *self {
+            AutomodError::InvalidRule(reason) => format!("Invalid rule: {}", reason),
+            AutomodError::UnknownRule => "Rule not found".to_string(),
+            AutomodError::TooManyRules => {
+                format!("A room can not have more than {} rules", MAX_RULES)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    pub id: Uuid,
+    pub filter: Filter,
+    pub action: Action,
+    pub created_by: Uuid,
+    pub created_at: DateTime<Local>,
+    // built from the filter, again after loading
+    #[serde(skip)]
+    matcher: Option<Regex>,
+}
+
+impl Rule {
+    pub fn new(
+        filter: Filter,
+        action: Action,
+        created_by: Uuid,
+        now: DateTime<Local>,
+    ) -> Result<Rule, AutomodError> {
+        let action = match action {
+            Action::Mute(0) => return Err(AutomodError::InvalidRule("mute without a duration")),
+            Action::Mute(seconds) => Action::Mute(seconds.min(MAX_DURATION)),
+            action => action,
+        };
+        let mut rule = Rule {
+            id: Uuid::new_v4(),
+            filter,
+            action,
+            created_by,
+            created_at: now,
+            matcher: None,
+        };
+        rule.compile()?;
+        Ok(rule)
+    }
+
+    fn compile(&mut self) -> Result<(), AutomodError> {
+        let pattern = match self.filter {
+            Filter::Words { ref mut words } => {
+                for word in words.iter_mut() {
+                    *word = word.trim().to_string();
+                }
+                words.retain(|word| !word.is_empty());
+                if words.is_empty() || words.len() > MAX_WORDS {
+                    return Err(AutomodError::InvalidRule("between 1 and 200 words are needed"));
+                }
+                let escaped: Vec<String> = words.iter().map(|word| regex::escape(word)).collect();
+                format!(r"(?i)\b(?:{})\b", escaped.join("|"))
+            }
+            Filter::Regex { ref pattern } => pattern.clone(),
+            Filter::Links {
+                ref mut allow,
+                ref mut deny,
+            } => {
+                for domain in allow.iter_mut().chain(deny.iter_mut()) {
+                    *domain = domain.trim().trim_matches('.').to_lowercase();
+                }
+                allow.retain(|domain| !domain.is_empty());
+                deny.retain(|domain| !domain.is_empty());
+                if allow.is_empty() && deny.is_empty() {
+                    return Err(AutomodError::InvalidRule("no domains given"));
+                }
+                LINK_PATTERN.to_string()
+            }
+            Filter::Caps { max_percent, .. } => {
+                if max_percent >= 100 {
+                    return Err(AutomodError::InvalidRule("max_percent must be below 100"));
+                }
+                return Ok(());
+            }
+            Filter::Repeat { max_run } => {
+                if max_run == 0 {
+                    return Err(AutomodError::InvalidRule("max_run must be at least 1"));
+                }
+                return Ok(());
+            }
+        };
+        let matcher = RegexBuilder::new(&pattern)
+            .size_limit(PATTERN_SIZE_LIMIT)
+            .build()
+            .map_err(|_| AutomodError::InvalidRule("invalid or too large pattern"))?;
+        self.matcher = Some(matcher);
+        Ok(())
+    }
+
+    // the text with the matches replaced, none if the rule does not match
+    pub fn apply(&self, text: &str) -> Option<String> {
+        match self.filter {
+            Filter::Words { .. } | Filter::Regex { .. } => {
+                let matcher = self.matcher.as_ref()?;
+                if !matcher.is_match(text) {
+                    return None;
+                }
+                Some(
+                    matcher
+                        .replace_all(text, |caps: &Captures| stars(&caps[0]))
+                        .into_owned(),
+                )
+            }
+            Filter::Links { .. } => {
+                let matcher = self.matcher.as_ref()?;
+                if !matcher
+                    .captures_iter(text)
+                    .any(|caps| self.link_blocked(&caps[1]))
+                {
+                    return None;
+                }
+                Some(
+                    matcher
+                        .replace_all(text, |caps: &Captures| {
+                            if self.link_blocked(&caps[1]) {
+                                stars(&caps[0])
+                            } else {
+                                caps[0].to_string()
+                            }
+                        })
+                        .into_owned(),
+                )
+            }
+            Filter::Caps {
+                max_percent,
+                min_letters,
+            } => {
+                let letters = text.chars().filter(|c| c.is_alphabetic()).count();
+                let capitals = text.chars().filter(|c| c.is_uppercase()).count();
+                if letters == 0 || letters < min_letters
+                    || capitals * 100 <= letters * max_percent as usize
+                {
+                    return None;
+                }
+                Some(text.to_lowercase())
+            }
+            Filter::Repeat { max_run } => {
+                let mut shortened = String::with_capacity(text.len());
+                let mut last = None;
+                let mut run = 0;
+                let mut matched = false;
+                for c in text.chars() {
+                    if Some(c) == last {
+                        run += 1;
+                    } else {
+                        last = Some(c);
+                        run = 1;
+                    }
+                    if run > max_run {
+                        matched = true;
+                    } else {
+                        shortened.push(c);
+                    }
+                }
+                if matched {
+                    Some(shortened)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn link_blocked(&self, host: &str) -> bool {
+        let (allow, deny) = match self.filter {
+            Filter::Links {
+                ref allow,
+                ref deny,
+            } => (allow, deny),
+            _ => return false,
+        };
+        let host = host.to_lowercase();
+        let listed = |domains: &Vec<String>| {
+            domains.iter().any(|domain| {
+                host == *domain || host.ends_with(&format!(".{}", domain))
+            })
+        };
+        listed(deny) || (!allow.is_empty() && !listed(allow))
+    }
+}
+
+fn stars(matched: &str) -> String {
+    "*".repeat(matched.chars().count())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    // the text, rewritten by replace rules, and the flag rules that matched
+    Pass { text: String, flags: Vec<Uuid> },
+    Block(Uuid),
+    // rule id and seconds
+    Mute(Uuid, u64),
+}
+
+// rules by room id
+#[derive(Debug, Clone, Default)]
+pub struct AutoMod {
+    rooms: HashMap<Uuid, Vec<Rule>>,
+}
+
+impl AutoMod {
+    pub fn new() -> AutoMod {
+        AutoMod::default()
+    }
+
+    // rules that no longer compile are dropped
+    pub fn from_rules(rooms: HashMap<Uuid, Vec<Rule>>) -> AutoMod {
+        let mut automod = AutoMod::new();
+        for (room, rules) in rooms {
+            let rules: Vec<Rule> = rules
+                .into_iter()
+                .filter_map(|mut rule| match rule.compile() {
+                    Ok(()) => Some(rule),
+                    Err(_) => {
+                        warn!("Dropping automod rule {} of room {}", rule.id, room);
+                        None
+                    }
+                })
+                .collect();
+            if !rules.is_empty() {
+                automod.rooms.insert(room, rules);
+            }
+        }
+        automod
+    }
+
+    // rules by room name for storing them, the ids change on restart
+    pub fn named_rules<F>(&self, mut names: F) -> HashMap<String, Vec<Rule>>
+    where
+        F: FnMut(&Uuid) -> Option<String>,
+    {
+        self.rooms
+            .iter()
+            .filter_map(|(room, rules)| Some((names(room)?, rules.clone())))
+            .collect()
+    }
+
+    // rules of rooms that are gone are dropped
+    pub fn from_named_rules<F>(rooms: HashMap<String, Vec<Rule>>, mut ids: F) -> AutoMod
+    where
+        F: FnMut(&str) -> Option<Uuid>,
+    {
+        AutoMod::from_rules(rooms
+            .into_iter()
+            .filter_map(|(name, rules)| Some((ids(&name)?, rules)))
+            .collect())
+    }
+
+    pub fn rules(&self, room: &Uuid) -> &[Rule] {
+        self.rooms
+            .get(room)
+            .map(|rules| rules.as_slice())
+            .unwrap_or(&[])
+    }
+
+    pub fn add(&mut self, room: Uuid, rule: Rule) -> Result<(), AutomodError> {
+        let rules = self.rooms.entry(room).or_insert_with(Vec::new);
+        if rules.len() >= MAX_RULES {
+            return Err(AutomodError::TooManyRules);
+        }
+        rules.push(rule);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, room: &Uuid, id: &Uuid) -> Result<Rule, AutomodError> {
+        let rule = {
+            let rules = self.rooms.get_mut(room).ok_or(AutomodError::UnknownRule)?;
+            let position = rules
+                .iter()
+                .position(|rule| rule.id == *id)
+                .ok_or(AutomodError::UnknownRule)?;
+            rules.remove(position)
+        };
+        if self.rules(room).is_empty() {
+            self.rooms.remove(room);
+        }
+        Ok(rule)
+    }
+
+    pub fn remove_room(&mut self, room: &Uuid) -> bool {
+        self.rooms.remove(room).is_some()
+    }
+
+    pub fn check(&self, room: &Uuid, text: &str) -> Verdict {
+        let mut text = text.to_string();
+        let mut flags = Vec::new();
+        for rule in self.rules(room) {
+            let replaced = match rule.apply(&text) {
+                Some(replaced) => replaced,
+                None => continue,
+            };
+            match rule.action {
+                Action::Block => return Verdict::Block(rule.id),
+                Action::Mute(seconds) => return Verdict::Mute(rule.id, seconds),
+                Action::Replace => text = replaced,
+                Action::Flag => flags.push(rule.id),
+            }
+        }
+        Verdict::Pass { text, flags }
+    }
+}
+
+#[test]
+fn test_rules() {
+    let now = Local::now();
+    let moderator = Uuid::new_v4();
+    let rule = |filter| Rule::new(filter, Action::Replace, moderator, now).unwrap();
+
+    let words = rule(Filter::Words {
+        words: vec![" darn ".to_string(), "a.b".to_string()],
+    });
+    assert_eq!(Some("oh **** it".to_string()), words.apply("oh DARN it"));
+    assert_eq!(None, words.apply("darned axb"));
+    assert_eq!(Some("***".to_string()), words.apply("a.b"));
+
+    let links = rule(Filter::Links {
+        allow: vec![],
+        deny: vec!["spam.com".to_string()],
+    });
+    assert_eq!(
+        Some("see ************************** and https://ok.org".to_string()),
+        links.apply("see https://www.spam.com/offer and https://ok.org")
+    );
+    assert_eq!(None, links.apply("see https://notspam.com"));
+    let allowed = rule(Filter::Links {
+        allow: vec!["example.org".to_string()],
+        deny: vec![],
+    });
+    assert_eq!(None, allowed.apply("docs at https://docs.example.org/x"));
+    assert!(allowed.apply("www.other.net").is_some());
+
+    let caps = rule(Filter::Caps {
+        max_percent: 70,
+        min_letters: 5,
+    });
+    assert_eq!(Some("stop shouting".to_string()), caps.apply("STOP SHOUTING"));
+    assert_eq!(None, caps.apply("OK"));
+    assert_eq!(None, caps.apply("Hello World"));
+
+    let repeat = rule(Filter::Repeat { max_run: 3 });
+    assert_eq!(Some("nooo!!!".to_string()), repeat.apply("noooooo!!!!!"));
+    assert_eq!(None, repeat.apply("nooo"));
+
+    let invalid = |filter, action| Rule::new(filter, action, moderator, now).is_err();
+    assert!(invalid(
+        Filter::Regex {
+            pattern: "(".to_string(),
+        },
+        Action::Block
+    ));
+    assert!(invalid(
+        Filter::Words {
+            words: vec![" ".to_string()],
+        },
+        Action::Block
+    ));
+    assert!(invalid(Filter::Caps { max_percent: 100, min_letters: 5 }, Action::Flag));
+    assert!(invalid(Filter::Repeat { max_run: 3 }, Action::Mute(0)));
+}
+
+#[test]
+fn test_automod_check() {
+    let now = Local::now();
+    let room = Uuid::new_v4();
+    let moderator = Uuid::new_v4();
+    let mut automod = AutoMod::new();
+    let add = |automod: &mut AutoMod, filter, action| {
+        let rule = Rule::new(filter, action, moderator, now).unwrap();
+        let id = rule.id;
+        automod.add(room, rule).unwrap();
+        id
+    };
+
+    let replace = add(&mut automod, Filter::Repeat { max_run: 2 }, Action::Replace);
+    let flag = add(
+        &mut automod,
+        Filter::Regex {
+            pattern: r"\bfree\b".to_string(),
+        },
+        Action::Flag,
+    );
+    let mute = add(
+        &mut automod,
+        Filter::Words {
+            words: vec!["scam".to_string()],
+        },
+        Action::Mute(600),
+    );
+
+    assert_eq!(
+        Verdict::Pass {
+            text: "free stuff!!".to_string(),
+            flags: vec![flag],
+        },
+        automod.check(&room, "free stuff!!!!")
+    );
+    assert_eq!(Verdict::Mute(mute, 600), automod.check(&room, "a scam"));
+    // other rooms have no rules
+    assert_eq!(
+        Verdict::Pass {
+            text: "a scam".to_string(),
+            flags: vec![],
+        },
+        automod.check(&Uuid::new_v4(), "a scam")
+    );
+
+    // the rules survive a restart, which gives the room a new id
+    let json = ::serde_json::to_string(&automod.named_rules(|_| Some("Main".to_string()))).unwrap();
+    let room = Uuid::new_v4();
+    let rules = ::serde_json::from_str(&json).unwrap();
+    let mut automod = AutoMod::from_named_rules(rules, |name| {
+        assert_eq!("Main", name);
+        Some(room)
+    });
+    assert_eq!(Verdict::Mute(mute, 600), automod.check(&room, "a scam"));
+
+    assert_eq!(replace, automod.remove(&room, &replace).unwrap().id);
+    assert_eq!(Err(AutomodError::UnknownRule), automod.remove(&room, &replace).map(|_| ()));
+    assert!(automod.remove_room(&room));
+    assert!(automod.rules(&room).is_empty());
+}
@@ -0,0 +1,47 @@
+// Repeats messages starting with `!echo`.
+
+use bots::{Bot, BotEvent, BotPost, EventKind, Subscription};
+
+const PREFIX: &str = "!echo ";
+
+pub struct EchoBot;
+
+impl Bot for EchoBot {
+    fn name(&self) -> &str {
+        "echobot"
+    }
+
+    fn subscription(&self) -> Subscription {
+        Subscription::new(vec![EventKind::Message])
+    }
+
+    fn handle(&mut self, event: &BotEvent) -> Vec<BotPost> {
+        match *event {
+            BotEvent::Message(ref message) if message.text.starts_with(PREFIX) => {
+                let text = message.text[PREFIX.len()..].trim();
+                if text.is_empty() {
+                    return Vec::new();
+                }
+                vec![BotPost::new(&message.room, text.to_string())]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[test]
+fn test_echo_bot() {
+    use bots::harness::SimulatedRoom;
+
+    let mut room = SimulatedRoom::new("Main");
+    room.add_bot(Box::new(EchoBot));
+    assert_eq!(vec!["hello there".to_string()], room.say("user3", "!echo hello there"));
+    assert!(room.say("user3", "!echo   ").is_empty());
+    assert!(room.say("user3", "hello").is_empty());
+    // bots do not answer bots, not even themselves
+    assert!(room.say("echobot", "!echo loop").is_empty());
+    assert_eq!(
+        ("echobot".to_string(), "hello there".to_string()),
+        room.transcript()[1]
+    );
+}
@@ -0,0 +1,94 @@
+// Runs bots against a simulated room, without a chat server.
+//
+// Users join, leave and talk by name, every call answers with the texts the
+// bots posted to the room in reply. Bot posts are dispatched again like in the
+// chat server, so bots ignoring each other is covered as well.
+
+use bots::{Bot, BotEvent, BotRegistry};
+use message::ChatMessage;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+pub struct SimulatedRoom {
+    name: String,
+    registry: BotRegistry,
+    users: HashMap<String, Uuid>,
+    // username and text of every message, bot posts included
+    transcript: Vec<(String, String)>,
+}
+
+impl SimulatedRoom {
+    pub fn new(name: &str) -> SimulatedRoom {
+        SimulatedRoom {
+            name: name.to_string(),
+            registry: BotRegistry::new(),
+            users: HashMap::new(),
+            transcript: Vec::new(),
+        }
+    }
+
+    pub fn add_bot(&mut self, bot: Box<dyn Bot>) {
+        let user = self.registry.register(bot);
+        self.users
+            .insert(user.get_username().to_string(), user.copy_id());
+    }
+
+    pub fn join(&mut self, username: &str) -> Vec<String> {
+        let event = BotEvent::Join {
+            room: self.name.clone(),
+            user: self.user_id(username),
+            username: username.to_string(),
+        };
+        self.run(event)
+    }
+
+    pub fn leave(&mut self, username: &str) -> Vec<String> {
+        let event = BotEvent::Leave {
+            room: self.name.clone(),
+            user: self.user_id(username),
+            username: username.to_string(),
+        };
+        self.run(event)
+    }
+
+    pub fn say(&mut self, username: &str, text: &str) -> Vec<String> {
+        let message = ChatMessage::new(
+            self.name.clone(),
+            self.user_id(username),
+            username.to_string(),
+            text.to_string(),
+        );
+        self.transcript
+            .push((username.to_string(), text.to_string()));
+        self.run(BotEvent::Message(message))
+    }
+
+    pub fn transcript(&self) -> &[(String, String)] {
+        &self.transcript
+    }
+
+    fn user_id(&mut self, username: &str) -> Uuid {
+        *self.users
+            .entry(username.to_string())
+            .or_insert_with(Uuid::new_v4)
+    }
+
+    // posts to other rooms are dropped
+    fn run(&mut self, event: BotEvent) -> Vec<String> {
+        let mut posted = Vec::new();
+        let mut events = vec![event];
+        while let Some(event) = events.pop() {
+            for (user, username, post) in self.registry.dispatch(&event) {
+                if post.room != self.name {
+                    continue;
+                }
+                self.transcript.push((username.clone(), post.text.clone()));
+                posted.push(post.text.clone());
+                events.push(BotEvent::Message(ChatMessage::new(
+                    post.room, user, username, post.text,
+                )));
+            }
+        }
+        posted
+    }
+}
@@ -0,0 +1,55 @@
+// Greets users joining a room and explains the bots on `!help`.
+
+use bots::{Bot, BotEvent, BotPost, EventKind, Subscription};
+
+const HELP: &str = "Bots answer messages starting with `!`: \
+                    `!help` shows this text, `!echo <text>` repeats the text \
+                    if the echo bot runs.";
+
+pub struct HelpBot;
+
+impl Bot for HelpBot {
+    fn name(&self) -> &str {
+        "helpbot"
+    }
+
+    fn subscription(&self) -> Subscription {
+        Subscription::new(vec![EventKind::Message, EventKind::Join])
+    }
+
+    fn handle(&mut self, event: &BotEvent) -> Vec<BotPost> {
+        match *event {
+            BotEvent::Message(ref message) if message.text.trim() == "!help" => {
+                vec![BotPost::new(&message.room, HELP.to_string())]
+            }
+            BotEvent::Join {
+                ref room,
+                ref username,
+                ..
+            } => vec![BotPost::new(
+                room,
+                format!("Welcome to {}, {}! Say !help to learn about the bots.", room, username),
+            )],
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[test]
+fn test_help_bot() {
+    use bots::harness::SimulatedRoom;
+    use bots::EchoBot;
+
+    let mut room = SimulatedRoom::new("Main");
+    room.add_bot(Box::new(HelpBot));
+    room.add_bot(Box::new(EchoBot));
+    assert_eq!(
+        vec!["Welcome to Main, user3! Say !help to learn about the bots.".to_string()],
+        room.join("user3")
+    );
+    assert_eq!(vec![HELP.to_string()], room.say("user3", " !help "));
+    // the echo bot's answer is not taken for a request
+    assert_eq!(vec!["!help".to_string()], room.say("user3", "!echo !help"));
+    assert!(room.leave("user3").is_empty());
+    assert_eq!(5, room.transcript().len());
+}
@@ -0,0 +1,191 @@
+// In-process bots.
+//
+// A bot runs inside the chat server under a user of its own. It subscribes to
+// the message, join and leave events of some or all rooms and answers them
+// with posts, which the chat server sends as the bot user like any other
+// message. Bots never see messages of bots, so they can not keep each other
+// talking forever.
+
+use message::ChatMessage;
+use user::User;
+use uuid::Uuid;
+
+mod echo;
+#[cfg(test)]
+pub mod harness;
+mod help;
+
+pub use self::echo::EchoBot;
+pub use self::help::HelpBot;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    Message,
+    Join,
+    Leave,
+}
+
+#[derive(Debug, Clone)]
+pub enum BotEvent {
+    Message(ChatMessage),
+    // the first session of the user entered the room
+    Join {
+        room: String,
+        user: Uuid,
+        username: String,
+    },
+    // the last session of the user left the room
+    Leave {
+        room: String,
+        user: Uuid,
+        username: String,
+    },
+}
+
+impl BotEvent {
+    pub fn kind(&self) -> EventKind {
+        match *self {
+            BotEvent::Message(_) => EventKind::Message,
+            BotEvent::Join { .. } => EventKind::Join,
+            BotEvent::Leave { .. } => EventKind::Leave,
+        }
+    }
+
+    pub fn room(&self) -> &str {
+        match *self {
+            BotEvent::Message(ref message) => &message.room,
+            BotEvent::Join { ref room, .. } | BotEvent::Leave { ref room, .. } => room,
+        }
+    }
+}
+
+// The events a bot gets, no rooms means every room
+#[derive(Debug, Clone, Default)]
+pub struct Subscription {
+    pub rooms: Vec<String>,
+    pub events: Vec<EventKind>,
+}
+
+impl Subscription {
+    pub fn new(events: Vec<EventKind>) -> Subscription {
+        Subscription {
+            rooms: Vec::new(),
+            events,
+        }
+    }
+
+    pub fn matches(&self, event: &BotEvent) -> bool {
+        self.events.contains(&event.kind())
+            && (self.rooms.is_empty() || self.rooms.iter().any(|room| room == event.room()))
+    }
+}
+
+// A message the chat server posts for the bot
+#[derive(Debug, Clone, PartialEq)]
+pub struct BotPost {
+    pub room: String,
+    pub text: String,
+}
+
+impl BotPost {
+    pub fn new(room: &str, text: String) -> BotPost {
+        BotPost {
+            room: room.to_string(),
+            text,
+        }
+    }
+}
+
+pub trait Bot {
+    // username of the bot user
+    fn name(&self) -> &str;
+
+    // asked once, when the bot is registered
+    fn subscription(&self) -> Subscription;
+
+    fn handle(&mut self, event: &BotEvent) -> Vec<BotPost>;
+}
+
+// bots by the name used in the configuration
+pub fn create(name: &str) -> Option<Box<dyn Bot>> {
+    match name {
+        "echo" => Some(Box::new(EchoBot)),
+        "help" => Some(Box::new(HelpBot)),
+        _ => None,
+    }
+}
+
+struct RegisteredBot {
+    user: Uuid,
+    username: String,
+    subscription: Subscription,
+    bot: Box<dyn Bot>,
+}
+
+#[derive(Default)]
+pub struct BotRegistry {
+    bots: Vec<RegisteredBot>,
+}
+
+impl BotRegistry {
+    pub fn new() -> BotRegistry {
+        BotRegistry::default()
+    }
+
+    // answers the user the bot posts as, nobody knows its password
+    pub fn register(&mut self, bot: Box<dyn Bot>) -> User {
+        let username = bot.name().to_string();
+        let user = User::new(
+            format!("{}@bots.invalid", username),
+            username.clone(),
+            username.clone(),
+            Uuid::new_v4().simple().to_string(),
+        );
+        self.bots.push(RegisteredBot {
+            user: user.copy_id(),
+            username: username,
+            subscription: bot.subscription(),
+            bot: bot,
+        });
+        user
+    }
+
+    pub fn is_bot(&self, user: &Uuid) -> bool {
+        self.bots.iter().any(|registered| registered.user == *user)
+    }
+
+    // the posts of all subscribed bots, with the id and name of the bot user
+    pub fn dispatch(&mut self, event: &BotEvent) -> Vec<(Uuid, String, BotPost)> {
+        if let BotEvent::Message(ref message) = *event {
+            if self.is_bot(&message.author) {
+                return Vec::new();
+            }
+        }
+        let mut posts = Vec::new();
+        for registered in &mut self.bots {
+            if !registered.subscription.matches(event) {
+                continue;
+            }
+            for post in registered.bot.handle(event) {
+                posts.push((registered.user, registered.username.clone(), post));
+            }
+        }
+        posts
+    }
+}
+
+#[test]
+fn test_subscription() {
+    let join = BotEvent::Join {
+        room: "Main".to_string(),
+        user: Uuid::new_v4(),
+        username: "user3".to_string(),
+    };
+    let mut subscription = Subscription::new(vec![EventKind::Join]);
+    assert!(subscription.matches(&join));
+    subscription.rooms = vec!["Other".to_string()];
+    assert!(!subscription.matches(&join));
+    assert!(!Subscription::new(vec![EventKind::Message]).matches(&join));
+    assert!(create("echo").is_some());
+    assert!(create("nobody").is_none());
+}
@@ -0,0 +1,235 @@
+// Commands every server has.
+
+use commands::{Command, CommandContext, CommandError, CommandRegistry, CommandResult, Permission};
+
+pub fn register(registry: &mut CommandRegistry) {
+    registry.register(Command {
+        name: "help",
+        usage: "/help [command]",
+        summary: "list the commands or explain one",
+        permission: Permission::Guest,
+        handler: help,
+    });
+    registry.register(Command {
+        name: "me",
+        usage: "/me <action>",
+        summary: "tell the room what you are doing",
+        permission: Permission::Member,
+        handler: me,
+    });
+    registry.register(Command {
+        name: "topic",
+        usage: "/topic [text]",
+        summary: "show the topic of the room, moderators also set it",
+        permission: Permission::Member,
+        handler: topic,
+    });
+    registry.register(Command {
+        name: "who",
+        usage: "/who",
+        summary: "list who is online in the room",
+        permission: Permission::Member,
+        handler: who,
+    });
+    registry.register(Command {
+        name: "whois",
+        usage: "/whois <user>",
+        summary: "show who a user is",
+        permission: Permission::Guest,
+        handler: whois,
+    });
+}
+
+fn help(context: &mut CommandContext) -> CommandResult {
+    if context.args.is_empty() {
+        return Ok(Some(context.registry.help(context.caller.permission)));
+    }
+    let name = context.args.trim_matches('/').to_lowercase();
+    match context.registry.get(&name) {
+        Some(command) if command.permission <= context.caller.permission => {
+            Ok(Some(format!("{}  {}", command.usage, command.summary)))
+        }
+        _ => Err(CommandError::Unknown(name)),
+    }
+}
+
+fn me(context: &mut CommandContext) -> CommandResult {
+    if context.args.is_empty() {
+        return Err(CommandError::Usage("/me <action>"));
+    }
+    context
+        .host
+        .post(context.caller, context.args.to_string(), true);
+    Ok(None)
+}
+
+fn topic(context: &mut CommandContext) -> CommandResult {
+    let room = &context.caller.room;
+    if context.args.is_empty() {
+        return Ok(Some(match context.host.topic(room) {
+            Some(ref topic) if !topic.is_empty() => format!("Topic of {}: {}", room, topic),
+            _ => format!("{} has no topic", room),
+        }));
+    }
+    if context.caller.permission < Permission::Moderator {
+        return Err(CommandError::NotAllowed);
+    }
+    context
+        .host
+        .set_topic(context.caller, context.args.to_string());
+    Ok(None)
+}
+
+fn who(context: &mut CommandContext) -> CommandResult {
+    let room = &context.caller.room;
+    let online = context.host.who(room);
+    Ok(Some(format!(
+        "Online in {} ({}): {}",
+        room,
+        online.len(),
+        online.join(", ")
+    )))
+}
+
+fn whois(context: &mut CommandContext) -> CommandResult {
+    let username = context.args.trim_matches('@');
+    if username.is_empty() {
+        return Err(CommandError::Usage("/whois <user>"));
+    }
+    let info = match context.host.whois(username) {
+        Some(info) => info,
+        None => return Ok(Some(format!("There is no user {}", username))),
+    };
+    let mut text = format!("{} ({})", info.username, info.display_name);
+    if info.admin {
+        text.push_str(", admin");
+    }
+    if info.bot {
+        text.push_str(", bot");
+    }
+    text.push_str(if info.online { ", online" } else { ", offline" });
+    Ok(Some(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use commands::{Caller, CommandError, CommandHost, CommandRegistry, Permission, UserInfo};
+    use uuid::Uuid;
+
+    // records what the commands did
+    #[derive(Default)]
+    struct FakeHost {
+        posts: Vec<(String, bool)>,
+        topic: Option<String>,
+    }
+
+    impl CommandHost for FakeHost {
+        fn post(&mut self, _: &Caller, text: String, emote: bool) {
+            self.posts.push((text, emote));
+        }
+
+        fn topic(&mut self, _: &str) -> Option<String> {
+            self.topic.clone()
+        }
+
+        fn set_topic(&mut self, _: &Caller, topic: String) {
+            self.topic = Some(topic);
+        }
+
+        fn who(&mut self, _: &str) -> Vec<String> {
+            vec!["user3".to_string(), "user4".to_string()]
+        }
+
+        fn whois(&mut self, username: &str) -> Option<UserInfo> {
+            if username != "admin" {
+                return None;
+            }
+            Some(UserInfo {
+                username: "admin".to_string(),
+                display_name: "Admin".to_string(),
+                admin: true,
+                bot: false,
+                online: false,
+            })
+        }
+    }
+
+    fn caller(permission: Permission) -> Caller {
+        Caller {
+            session: 1,
+            user_id: Uuid::new_v4(),
+            username: "user3".to_string(),
+            room: "Main".to_string(),
+            permission,
+        }
+    }
+
+    #[test]
+    fn test_builtins() {
+        let registry = CommandRegistry::with_builtins();
+        let mut host = FakeHost::default();
+        let member = caller(Permission::Member);
+        let run = |host: &mut FakeHost, caller: &Caller, name: &str, args: &str| {
+            registry.dispatch(host, caller, name, args)
+        };
+
+        assert_eq!(Ok(None), run(&mut host, &member, "me", "waves"));
+        assert_eq!(vec![("waves".to_string(), true)], host.posts);
+        assert_eq!(
+            Err(CommandError::Usage("/me <action>")),
+            run(&mut host, &member, "me", "")
+        );
+
+        assert_eq!(
+            Ok(Some("Main has no topic".to_string())),
+            run(&mut host, &member, "topic", "")
+        );
+        assert_eq!(
+            Err(CommandError::NotAllowed),
+            run(&mut host, &member, "topic", "new")
+        );
+        let moderator = caller(Permission::Moderator);
+        assert_eq!(Ok(None), run(&mut host, &moderator, "topic", "new"));
+        assert_eq!(
+            Ok(Some("Topic of Main: new".to_string())),
+            run(&mut host, &member, "topic", "")
+        );
+
+        assert_eq!(
+            Ok(Some("Online in Main (2): user3, user4".to_string())),
+            run(&mut host, &member, "who", "")
+        );
+        assert_eq!(
+            Ok(Some("admin (Admin), admin, offline".to_string())),
+            run(&mut host, &member, "whois", "@admin")
+        );
+        assert_eq!(
+            Ok(Some("There is no user nobody".to_string())),
+            run(&mut host, &member, "whois", "nobody")
+        );
+
+        // guests only get the commands they may use
+        let guest = caller(Permission::Guest);
+        assert_eq!(Err(CommandError::NotAllowed), run(&mut host, &guest, "who", ""));
+        assert_eq!(
+            Ok(Some(
+                "Commands:\n/help [command]  list the commands or explain one\n\
+                 /whois <user>    show who a user is"
+                    .to_string()
+            )),
+            run(&mut host, &guest, "help", "")
+        );
+        assert_eq!(
+            Err(CommandError::Unknown("who".to_string())),
+            run(&mut host, &guest, "help", "/who")
+        );
+        assert_eq!(
+            Ok(Some("/who  list who is online in the room".to_string())),
+            run(&mut host, &member, "help", "who")
+        );
+        assert_eq!(
+            Err(CommandError::Unknown("nope".to_string())),
+            run(&mut host, &member, "nope", "")
+        );
+    }
+}
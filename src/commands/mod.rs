@@ -0,0 +1,213 @@
+// Slash commands.
+//
+// A chat line starting with `/` runs a command instead of being sent, `//`
+// sends a line starting with a single slash. Commands are registered with a
+// usage line, a summary and the permission they need, `/help` is generated
+// from them. Handlers act through a `CommandHost`, which the chat server
+// implements for the session that sent the command.
+
+use protocol::ErrorCode;
+use uuid::Uuid;
+
+mod builtins;
+
+// ordered, every level may do what the ones below may
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    Guest,
+    Member,
+    Moderator,
+    Admin,
+}
+
+// Who runs a command, `permission` is what the caller may do in `room`
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub session: usize,
+    pub user_id: Uuid,
+    pub username: String,
+    // room of the session
+    pub room: String,
+    pub permission: Permission,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserInfo {
+    pub username: String,
+    pub display_name: String,
+    pub admin: bool,
+    pub bot: bool,
+    pub online: bool,
+}
+
+// What commands may see and do
+pub trait CommandHost {
+    // posts to the caller's room as the caller
+    fn post(&mut self, caller: &Caller, text: String, emote: bool);
+
+    fn topic(&mut self, room: &str) -> Option<String>;
+
+    // errors are sent to the caller's session
+    fn set_topic(&mut self, caller: &Caller, topic: String);
+
+    // usernames of the sessions in the room
+    fn who(&mut self, room: &str) -> Vec<String>;
+
+    fn whois(&mut self, username: &str) -> Option<UserInfo>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandError {
+    Unknown(String),
+    NotAllowed,
+    // the usage line of the command
+    Usage(&'static str),
+}
+
+impl CommandError {
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            CommandError::Unknown(_) => ErrorCode::UnknownCommand,
+            CommandError::NotAllowed => ErrorCode::NotAllowed,
+            CommandError::Usage(_) => ErrorCode::InvalidCommand,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match *self {
+            CommandError::Unknown(ref name) => {
+                format!("Unknown command /{}, /help lists the commands", name)
+            }
+            CommandError::NotAllowed => "You are not allowed to use this command".to_string(),
+            CommandError::Usage(usage) => format!("Usage: {}", usage),
+        }
+    }
+}
+
+// a reply only the caller gets, if any
+pub type CommandResult = Result<Option<String>, CommandError>;
+
+pub struct CommandContext<'a> {
+    pub host: &'a mut dyn CommandHost,
+    pub caller: &'a Caller,
+    // the rest of the line, trimmed
+    pub args: &'a str,
+    pub registry: &'a CommandRegistry,
+}
+
+pub type Handler = fn(&mut CommandContext) -> CommandResult;
+
+#[derive(Clone)]
+pub struct Command {
+    pub name: &'static str,
+    // e.g. `/whois <user>`
+    pub usage: &'static str,
+    pub summary: &'static str,
+    pub permission: Permission,
+    pub handler: Handler,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line<'a> {
+    Text(&'a str),
+    // lowercased name and arguments
+    Command(String, &'a str),
+}
+
+// chat text or a command with its arguments
+pub fn parse<'a>(text: &'a str) -> Line<'a> {
+    if text.starts_with("//") {
+        return Line::Text(&text[1..]);
+    }
+    if !text.starts_with('/') || text[1..].starts_with(char::is_whitespace) || text.len() == 1 {
+        return Line::Text(text);
+    }
+    let line = &text[1..];
+    match line.find(char::is_whitespace) {
+        Some(end) => Line::Command(line[..end].to_lowercase(), line[end..].trim()),
+        None => Line::Command(line.to_lowercase(), ""),
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct CommandRegistry {
+    commands: Vec<Command>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> CommandRegistry {
+        CommandRegistry::default()
+    }
+
+    // `/help`, `/me`, `/topic`, `/who` and `/whois`
+    pub fn with_builtins() -> CommandRegistry {
+        let mut registry = CommandRegistry::new();
+        builtins::register(&mut registry);
+        registry
+    }
+
+    // replaces a command of the same name
+    pub fn register(&mut self, command: Command) {
+        self.commands.retain(|existing| existing.name != command.name);
+        self.commands.push(command);
+        self.commands.sort_by_key(|command| command.name);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|command| command.name == name)
+    }
+
+    pub fn dispatch(
+        &self,
+        host: &mut dyn CommandHost,
+        caller: &Caller,
+        name: &str,
+        args: &str,
+    ) -> CommandResult {
+        let command = self.get(name)
+            .ok_or_else(|| CommandError::Unknown(name.to_string()))?;
+        if caller.permission < command.permission {
+            return Err(CommandError::NotAllowed);
+        }
+        let mut context = CommandContext {
+            host,
+            caller,
+            args,
+            registry: self,
+        };
+        (command.handler)(&mut context)
+    }
+
+    // the commands the permission allows, one per line
+    pub fn help(&self, permission: Permission) -> String {
+        let allowed: Vec<&Command> = self.commands
+            .iter()
+            .filter(|command| command.permission <= permission)
+            .collect();
+        let width = allowed
+            .iter()
+            .map(|command| command.usage.len())
+            .max()
+            .unwrap_or(0);
+        let mut help = "Commands:".to_string();
+        for command in allowed {
+            help.push_str(&format!(
+                "\n{:width$}  {}",
+                command.usage,
+                command.summary,
+                width = width
+            ));
+        }
+        help
+    }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(Line::Command("me".to_string(), "waves"), parse("/ME  waves "));
+    assert_eq!(Line::Command("who".to_string(), ""), parse("/who"));
+    assert_eq!(Line::Text("/shrug"), parse("//shrug"));
+    assert_eq!(Line::Text("/ not a command"), parse("/ not a command"));
+    assert_eq!(Line::Text("/"), parse("/"));
+    assert_eq!(Line::Text("hello /me"), parse("hello /me"));
+}
@@ -0,0 +1,486 @@
+// Server configuration.
+//
+// Settings are layered: built in defaults, then the toml file given by
+// `--config` / `ALIENCHAT_CONFIG` (or `alienchat.toml` if present), then
+// `ALIENCHAT_*` environment variables and finally command line flags.
+// Environment variables and flags use the flat key names of `Config::set`,
+// e.g. `ALIENCHAT_HISTORY_SIZE=50` or `--history-size 50`.
+
+use ratelimit::Penalty;
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+use storage::StorageBackend;
+use toml;
+use validation::ControlCharacters;
+
+const DEFAULT_CONFIG_FILE: &str = "alienchat.toml";
+const ENV_PREFIX: &str = "ALIENCHAT_";
+
+pub const USAGE: &str = "Usage: alienchat [OPTIONS]
+
+Options:
+    --config <file>                 toml configuration file
+    --bind <addr>[,<addr>...]       addresses to listen on
+    --bots <name>[,<name>...]       in-process bots to run: echo, help
+    --log-level <filter>            log filter, e.g. info or alienchat=debug
+    --heartbeat-timeout <secs>      drop websocket clients silent for this long
+    --default-room <name>           room new sessions are joined to
+    --history-size <n>              messages kept per room
+    --join-request-lifetime <secs>  pending join requests expire after this long
+    --shutdown-grace-period <secs>  time to drain connections on shutdown, at most 65535
+    --storage-backend <memory|file> where server state is persisted
+    --storage-path <dir>            directory of the file storage backend
+    --rate-limit-burst <n>          messages a session may send at once
+    --rate-limit-refill <n>         messages per second refilled per session
+    --rate-limit-user-burst <n>     messages a user may send at once
+    --rate-limit-user-refill <n>    messages per second refilled per user
+    --rate-limit-max-violations <n> rejected messages until the penalty applies
+    --rate-limit-violation-window <secs>
+                                    time violations are remembered
+    --rate-limit-penalty <mute|disconnect>
+                                    penalty for repeat offenders
+    --rate-limit-mute-duration <secs>
+                                    duration of the flood mute
+    --max-frame-size <bytes>        largest websocket frame accepted
+    --max-message-length <chars>    longest chat message accepted
+    --control-characters <strip|reject>
+                                    handling of control characters in messages
+    --max-upload-size <bytes>       largest attachment accepted
+    --max-concurrent-uploads <n>    uploads a session may have in progress
+    --attachments-path <dir>        directory attachment files are stored in
+    --token-secret <secret>         secret used to sign session tokens
+    --token-lifetime <secs>         lifetime of session tokens
+    --admin-username <name>         admin account created on start
+    --admin-password <password>     its password, no admin is created without one
+    --tls-enabled <true|false>      serve https and wss on the bind addresses
+    --tls-cert <file>               pem certificate chain
+    --tls-key <file>                pem private key
+    --tls-redirect-bind <addr>,...  plain http addresses redirecting to https
+    --tls-reload-interval <secs>    check certificate files for changes, 0 disables
+    --help                          print this help
+";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub bind: Vec<String>,
+    // names of the in-process bots
+    pub bots: Vec<String>,
+    pub log_level: String,
+    // seconds
+    pub heartbeat_timeout: u64,
+    pub default_room: String,
+    pub history_size: usize,
+    // seconds
+    pub join_request_lifetime: u64,
+    // seconds
+    pub shutdown_grace_period: u64,
+    pub storage: StorageConfig,
+    pub rate_limit: RateLimitConfig,
+    pub limits: LimitsConfig,
+    pub attachments: AttachmentsConfig,
+    pub auth: AuthConfig,
+    pub tls: TlsConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    // messages a session may send in a row
+    pub burst: u32,
+    // messages per second added back to the bucket
+    pub refill: f64,
+    // same for all sessions of a user together
+    pub user_burst: u32,
+    pub user_refill: f64,
+    // rejected messages within the window until the penalty applies
+    pub max_violations: u32,
+    // seconds
+    pub violation_window: u64,
+    pub penalty: Penalty,
+    // seconds
+    pub mute_duration: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    // bytes
+    pub max_frame_size: usize,
+    // characters
+    pub max_message_length: usize,
+    pub control_characters: ControlCharacters,
+    // bytes
+    pub max_upload_size: usize,
+    // per session
+    pub max_concurrent_uploads: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AttachmentsConfig {
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    // random per process if not set
+    pub secret: Option<String>,
+    // seconds
+    pub token_lifetime: i64,
+    // admin account created on start, there is none by default
+    pub admin_username: Option<String>,
+    pub admin_password: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub cert: String,
+    pub key: String,
+    pub redirect_bind: Vec<String>,
+    // seconds
+    pub reload_interval: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: vec!["0.0.0.0:1888".to_string()],
+            bots: Vec::new(),
+            log_level: "info".to_string(),
+            heartbeat_timeout: 10,
+            default_room: "Main".to_string(),
+            history_size: 100,
+            join_request_lifetime: 24 * 60 * 60,
+            shutdown_grace_period: 10,
+            storage: StorageConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            limits: LimitsConfig::default(),
+            attachments: AttachmentsConfig::default(),
+            auth: AuthConfig::default(),
+            tls: TlsConfig::default(),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> StorageConfig {
+        StorageConfig {
+            backend: StorageBackend::Memory,
+            path: "data".to_string(),
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        RateLimitConfig {
+            burst: 10,
+            refill: 1.0,
+            user_burst: 20,
+            user_refill: 2.0,
+            max_violations: 5,
+            violation_window: 60,
+            penalty: Penalty::Mute,
+            mute_duration: 60,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig {
+            max_frame_size: 64 * 1024,
+            max_message_length: 2000,
+            control_characters: ControlCharacters::Strip,
+            max_upload_size: 10 * 1024 * 1024,
+            max_concurrent_uploads: 4,
+        }
+    }
+}
+
+impl Default for AttachmentsConfig {
+    fn default() -> AttachmentsConfig {
+        AttachmentsConfig {
+            path: "data/attachments".to_string(),
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> AuthConfig {
+        AuthConfig {
+            secret: None,
+            token_lifetime: 60 * 60,
+            admin_username: None,
+            admin_password: None,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> TlsConfig {
+        TlsConfig {
+            enabled: false,
+            cert: "cert.pem".to_string(),
+            key: "key.pem".to_string(),
+            redirect_bind: Vec::new(),
+            reload_interval: 60,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, io::Error),
+    Parse(String, toml::de::Error),
+    UnknownKey(String),
+    InvalidValue(String, String),
+    MissingValue(String),
+    // `--help` was given
+    Help,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref path, ref err) => write!(f, "Cannot read {}: {}", path, err),
+            ConfigError::Parse(ref path, ref err) => write!(f, "Cannot parse {}: {}", path, err),
+            ConfigError::UnknownKey(ref key) => write!(f, "Unknown option {}", key),
+            ConfigError::InvalidValue(ref key, ref value) => {
+                write!(f, "Invalid value {:?} for {}", value, key)
+            }
+            ConfigError::MissingValue(ref key) => write!(f, "Missing value for {}", key),
+            ConfigError::Help => write!(f, "{}", USAGE),
+        }
+    }
+}
+
+impl Config {
+    // loads the configuration of this process
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::load_from(env::args().skip(1).collect(), env::vars().collect())
+    }
+
+    pub fn load_from(
+        args: Vec<String>,
+        vars: Vec<(String, String)>,
+    ) -> Result<Config, ConfigError> {
+        let flags = parse_flags(args)?;
+        let vars: Vec<(String, String)> = vars
+            .into_iter()
+            .filter(|&(ref name, _)| name.starts_with(ENV_PREFIX))
+            .map(|(name, value)| (name[ENV_PREFIX.len()..].to_lowercase(), value))
+            .collect();
+
+        let file = flags
+            .iter()
+            .chain(vars.iter())
+            .find(|&&(ref key, _)| key == "config")
+            .map(|&(_, ref path)| path.clone());
+
+        let mut config = match file {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(DEFAULT_CONFIG_FILE)?
+            }
+            None => Config::default(),
+        };
+
+        // flags win over environment variables
+        for (key, value) in vars.iter().chain(flags.iter()) {
+            if key != "config" {
+                config.set(key, value)?;
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    // rejects values the servers can not use
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        // the http servers take the grace period as u16
+        if u16::try_from(self.shutdown_grace_period).is_err() {
+            return Err(ConfigError::InvalidValue(
+                "shutdown_grace_period".to_string(),
+                self.shutdown_grace_period.to_string(),
+            ));
+        }
+        // the admin account needs both, there is no default password
+        let missing =
+            |value: &Option<String>| value.as_ref().map_or(true, |value| value.is_empty());
+        let auth = &self.auth;
+        match (missing(&auth.admin_username), missing(&auth.admin_password)) {
+            (false, true) => return Err(ConfigError::MissingValue("admin_password".to_string())),
+            (true, false) => return Err(ConfigError::MissingValue("admin_username".to_string())),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // grace period of the http servers in seconds
+    pub fn shutdown_timeout(&self) -> u16 {
+        u16::try_from(self.shutdown_grace_period).unwrap_or(u16::MAX)
+    }
+
+    pub fn from_file(path: &str) -> Result<Config, ConfigError> {
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .map_err(|err| ConfigError::Io(path.to_string(), err))?;
+        Config::from_toml(&content).map_err(|err| ConfigError::Parse(path.to_string(), err))
+    }
+
+    pub fn from_toml(content: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(content)
+    }
+
+    // sets a single option by its flat key name
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            "bind" => self.bind = parse_list(value),
+            "bots" => self.bots = parse_list(value),
+            "log_level" => self.log_level = value.to_string(),
+            "heartbeat_timeout" => self.heartbeat_timeout = parse(key, value)?,
+            "default_room" => self.default_room = value.to_string(),
+            "history_size" => self.history_size = parse(key, value)?,
+            "join_request_lifetime" => self.join_request_lifetime = parse(key, value)?,
+            "shutdown_grace_period" => self.shutdown_grace_period = parse(key, value)?,
+            "storage_backend" => self.storage.backend = parse(key, value)?,
+            "storage_path" => self.storage.path = value.to_string(),
+            "rate_limit_burst" => self.rate_limit.burst = parse(key, value)?,
+            "rate_limit_refill" => self.rate_limit.refill = parse(key, value)?,
+            "rate_limit_user_burst" => self.rate_limit.user_burst = parse(key, value)?,
+            "rate_limit_user_refill" => self.rate_limit.user_refill = parse(key, value)?,
+            "rate_limit_max_violations" => self.rate_limit.max_violations = parse(key, value)?,
+            "rate_limit_violation_window" => {
+                self.rate_limit.violation_window = parse(key, value)?
+            }
+            "rate_limit_penalty" => self.rate_limit.penalty = parse(key, value)?,
+            "rate_limit_mute_duration" => self.rate_limit.mute_duration = parse(key, value)?,
+            "max_frame_size" => self.limits.max_frame_size = parse(key, value)?,
+            "max_message_length" => self.limits.max_message_length = parse(key, value)?,
+            "control_characters" => self.limits.control_characters = parse(key, value)?,
+            "max_upload_size" => self.limits.max_upload_size = parse(key, value)?,
+            "max_concurrent_uploads" => self.limits.max_concurrent_uploads = parse(key, value)?,
+            "attachments_path" => self.attachments.path = value.to_string(),
+            "token_secret" => self.auth.secret = Some(value.to_string()),
+            "token_lifetime" => self.auth.token_lifetime = parse(key, value)?,
+            "admin_username" => self.auth.admin_username = Some(value.to_string()),
+            "admin_password" => self.auth.admin_password = Some(value.to_string()),
+            "tls_enabled" => self.tls.enabled = parse(key, value)?,
+            "tls_cert" => self.tls.cert = value.to_string(),
+            "tls_key" => self.tls.key = value.to_string(),
+            "tls_redirect_bind" => self.tls.redirect_bind = parse_list(value),
+            "tls_reload_interval" => self.tls.reload_interval = parse(key, value)?,
+            _ => return Err(ConfigError::UnknownKey(key.to_string())),
+        }
+        Ok(())
+    }
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, ConfigError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| ConfigError::InvalidValue(key.to_string(), value.to_string()))
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+// turns `--some-flag value` and `--some-flag=value` into ("some_flag", "value")
+fn parse_flags(args: Vec<String>) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut flags = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            return Err(ConfigError::Help);
+        }
+        if !arg.starts_with("--") {
+            return Err(ConfigError::UnknownKey(arg));
+        }
+
+        let (key, value) = match arg.find('=') {
+            Some(pos) => (arg[2..pos].to_string(), arg[pos + 1..].to_string()),
+            None => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?;
+                (arg[2..].to_string(), value)
+            }
+        };
+        flags.push((key.replace('-', "_"), value));
+    }
+    Ok(flags)
+}
+
+#[test]
+fn test_config_layers() {
+    let config = Config::from_toml(
+        "
+        bind = [\"127.0.0.1:1888\"]
+        history_size = 20
+
+        [storage]
+        backend = \"file\"
+        ",
+    ).unwrap();
+    assert_eq!(vec!["127.0.0.1:1888".to_string()], config.bind);
+    assert_eq!(20, config.history_size);
+    assert_eq!(StorageBackend::File, config.storage.backend);
+    assert_eq!("Main", config.default_room);
+
+    let config = Config::load_from(
+        vec![
+            "--history-size".to_string(),
+            "5".to_string(),
+            "--default-room=Lobby".to_string(),
+        ],
+        vec![
+            ("ALIENCHAT_HISTORY_SIZE".to_string(), "50".to_string()),
+            ("ALIENCHAT_BIND".to_string(), "127.0.0.1:1,127.0.0.1:2".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ],
+    ).unwrap();
+    assert_eq!(5, config.history_size);
+    assert_eq!("Lobby", config.default_room);
+    assert_eq!(2, config.bind.len());
+
+    assert!(Config::load_from(vec!["--history-size".to_string()], Vec::new()).is_err());
+    assert!(Config::load_from(vec!["--nope=1".to_string()], Vec::new()).is_err());
+    let grace_period = vec!["--shutdown-grace-period=70000".to_string()];
+    assert!(Config::load_from(grace_period, Vec::new()).is_err());
+
+    // there is no admin unless one is configured, and never one without password
+    assert_eq!(None, Config::default().auth.admin_username);
+    let admin = vec![("ALIENCHAT_ADMIN_PASSWORD".to_string(), "secret".to_string())];
+    let config = Config::load_from(vec!["--admin-username=root".to_string()], admin).unwrap();
+    assert_eq!(Some("root".to_string()), config.auth.admin_username);
+    assert_eq!(Some("secret".to_string()), config.auth.admin_password);
+    assert!(Config::load_from(vec!["--admin-username=root".to_string()], Vec::new()).is_err());
+    let empty = vec!["--admin-username=root", "--admin-password="];
+    let empty = empty.into_iter().map(|arg| arg.to_string()).collect();
+    assert!(Config::load_from(empty, Vec::new()).is_err());
+}
@@ -1,7 +1,9 @@
 use external_data_source::RoomDataInterface;
 use external_data_source::UserDataInterface;
 use role::Role;
-use room::Room;
+use room::{Room, RoomError};
+use sanctions::{SanctionKind, Sanctions};
+use server_bans::ServerBans;
 use std::fmt::Debug;
 use std::fmt::Error;
 use std::fmt::Formatter;
@@ -18,6 +20,8 @@ where
     R: Debug,
 {
     user_list: Vec<Uuid>,
+    // users added at runtime, e.g. bots, the user data source does not know them
+    added_users: Vec<User>,
     public_rooms: Vec<Room>,
     private_rooms: Vec<Room>,
     user_data_interface: U,
@@ -35,6 +39,7 @@ where
     pub fn new(user_interface: U, room_interface: R) -> Controller<U, R> {
         let mut controller = Controller {
             user_list: Vec::new(),
+            added_users: Vec::new(),
             public_rooms: Vec::new(),
             private_rooms: Vec::new(),
             user_data_interface: user_interface,
@@ -67,24 +72,96 @@ where
 
     //user based methods
     //TODO: insert user into the database
-    pub fn add_user(&mut self, user: User) {
+    // false if the username is taken
+    pub fn add_user(&mut self, user: User) -> bool {
+        if self.find_user_by_username(user.get_username()).is_some() {
+            return false;
+        }
         self.user_list.push(user.copy_id());
+        self.added_users.push(user);
+        true
     }
 
     //TODO: remove user from the database
+    // rooms owned by the user pass to their longest-standing moderator
     pub fn remove_user(&mut self, user_id: &Uuid) -> bool {
         if Controller::<U, R>::remove_uuid_from_vec(&mut self.user_list, user_id) {
+            self.added_users.retain(|user| user.get_id() != user_id);
+            for room in self.public_rooms
+                .iter_mut()
+                .chain(self.private_rooms.iter_mut())
+            {
+                if room.is_owner(user_id) {
+                    room.pass_ownership();
+                }
+                room.remove_moderator(user_id);
+                room.remove_member(user_id);
+            }
             return true;
         }
         false
     }
 
+    pub fn is_admin(&mut self, user_id: &Uuid) -> bool {
+        self.find_user(user_id)
+            .map_or(false, |mut user| user.has_role(&Role::generate_admin()))
+    }
+
     pub fn is_user(&self, user_id: &Uuid) -> bool {
         self.user_list.contains(&user_id)
     }
 
     pub fn find_user(&mut self, user_id: &Uuid) -> Option<User> {
-        self.user_data_interface.provide_user(user_id)
+        let added = &self.added_users;
+        self.user_data_interface
+            .provide_user(user_id)
+            .or_else(|| added.iter().find(|user| user.get_id() == user_id).cloned())
+    }
+
+    pub fn find_user_by_username(&mut self, username: &str) -> Option<User> {
+        let added = &self.added_users;
+        self.user_data_interface
+            .provide_user_data()
+            .into_iter()
+            .find(|user| user.get_username() == username)
+            .or_else(|| {
+                added
+                    .iter()
+                    .find(|user| user.get_username() == username)
+                    .cloned()
+            })
+    }
+
+    // names the rooms and users of the sanctions before they are stored
+    pub fn name_sanctions(&mut self, sanctions: &mut Sanctions) {
+        sanctions.set_names(|room_id, user_id| {
+            let room = self.find_room(room_id)?.get_name().to_string();
+            let user = self.find_user(user_id)?.get_username().to_string();
+            Some((room, user))
+        });
+    }
+
+    // applies stored sanctions, the rooms forget their bans and mutes on restart
+    pub fn restore_sanctions(&mut self, sanctions: &mut Sanctions) {
+        sanctions.rebind(|room, username| {
+            let room = self.find_room_by_name(room)?.copy_id();
+            let user = self.find_user_by_username(username)?.copy_id();
+            Some((room, user))
+        });
+        for sanction in sanctions.all() {
+            match sanction.kind {
+                SanctionKind::Ban => self.ban_member(&sanction.room, sanction.user),
+                SanctionKind::Mute => self.mute_member(&sanction.room, sanction.user),
+            }
+        }
+    }
+
+    pub fn name_server_bans(&mut self, bans: &ServerBans) {
+        bans.set_names(|user_id| Some(self.find_user(user_id)?.get_username().to_string()));
+    }
+
+    pub fn restore_server_bans(&mut self, bans: &ServerBans) {
+        bans.rebind(|username| Some(self.find_user_by_username(username)?.copy_id()));
     }
 
     //TODO: update user in database!
@@ -125,16 +202,34 @@ where
         }
     }
 
-    pub fn remove_room(&mut self, room: &Uuid) -> bool {
+    // only the owner or an admin may delete a room
+    pub fn remove_room(&mut self, room: &Uuid, by: &Uuid) -> Result<(), RoomError> {
+        self.owned_room_mut(room, by)?;
+
         if Controller::<U, R>::remove_room_from_vec(&mut self.public_rooms, room) {
-            return true;
+            return Ok(());
         }
 
         if Controller::<U, R>::remove_room_from_vec(&mut self.private_rooms, room) {
-            return true;
+            return Ok(());
         }
 
-        false
+        Err(RoomError::UnknownRoom)
+    }
+
+    // the owner or an admin may hand the room to another member
+    pub fn transfer_ownership(
+        &mut self,
+        room_id: &Uuid,
+        by: &Uuid,
+        new_owner: Uuid,
+    ) -> Result<(), RoomError> {
+        let room = self.owned_room_mut(room_id, by)?;
+        if !room.has_member(&new_owner) {
+            return Err(RoomError::NotMember);
+        }
+        room.set_owner(new_owner);
+        Ok(())
     }
 
     pub fn contains_room(&self, room: &Room) -> bool {
@@ -190,52 +285,115 @@ where
         }
     }
 
-    pub fn add_moderator_to_room(&mut self, room_id: &Uuid, user_id: Uuid) {
-        match self.find_room_match(room_id) {
-            Some((counter, room_public)) => {
-                if room_public {
-                    match self.public_rooms.get_mut(counter) {
-                        Some(t) => {
-                            t.add_moderator(user_id);
-                            if !t.has_member(&user_id) {
-                                t.add_member(user_id);
-                            }
-                        }
-                        None => (),
-                    }
-                } else {
-                    match self.private_rooms.get_mut(counter) {
-                        Some(t) => {
-                            t.add_moderator(user_id);
-                            if !t.has_member(&user_id) {
-                                t.add_member(user_id);
-                            }
-                        }
-                        None => (),
-                    }
-                }
-            }
-            None => (),
-        };
+    // moderators are managed by the owner or an admin
+    pub fn add_moderator_to_room(
+        &mut self,
+        room_id: &Uuid,
+        by: &Uuid,
+        user_id: Uuid,
+    ) -> Result<(), RoomError> {
+        let room = self.owned_room_mut(room_id, by)?;
+        if room.is_member_banned(&user_id) {
+            return Err(RoomError::NotMember);
+        }
+        if !room.has_moderator(&user_id) && !room.is_owner(&user_id) {
+            room.add_moderator(user_id);
+        }
+        if !room.has_member(&user_id) {
+            room.add_member(user_id);
+        }
+        Ok(())
     }
 
-    pub fn remove_moderator_from_room(&mut self, room_id: &Uuid, user_id: &Uuid) -> bool {
-        match self.find_room_match(room_id) {
-            Some((counter, room_public)) => {
-                if room_public {
-                    match self.public_rooms.get_mut(counter) {
-                        Some(t) => t.remove_moderator(user_id),
-                        None => false,
-                    }
-                } else {
-                    match self.private_rooms.get_mut(counter) {
-                        Some(t) => t.remove_moderator(user_id),
-                        None => false,
-                    }
-                }
-            }
-            None => false,
+    pub fn remove_moderator_from_room(
+        &mut self,
+        room_id: &Uuid,
+        by: &Uuid,
+        user_id: &Uuid,
+    ) -> Result<bool, RoomError> {
+        Ok(self.owned_room_mut(room_id, by)?.remove_moderator(user_id))
+    }
+
+    // moderators and admins manage the invites of a room
+    pub fn check_room_manager(&mut self, room_id: &Uuid, user_id: &Uuid) -> Result<(), RoomError> {
+        self.managed_room_mut(room_id, user_id).map(|_| ())
+    }
+
+    // an invite makes the user a member, unless banned from the room,
+    // answers false if the user already was a member
+    pub fn join_room_by_invite(&mut self, room_id: &Uuid, user_id: Uuid) -> Result<bool, RoomError> {
+        let room = self.room_mut(room_id).ok_or(RoomError::UnknownRoom)?;
+        if room.is_member_banned(&user_id) {
+            return Err(RoomError::NotAllowed);
+        }
+        if room.has_member(&user_id) {
+            return Ok(false);
+        }
+        room.add_member(user_id);
+        Ok(true)
+    }
+
+    // only private rooms take join requests, not from members or banned users
+    pub fn check_join_request(&self, room_id: &Uuid, user_id: &Uuid) -> Result<(), RoomError> {
+        let room = self.find_room(room_id).ok_or(RoomError::UnknownRoom)?;
+        if !room.is_private() || room.has_member(user_id) || room.is_member_banned(user_id) {
+            return Err(RoomError::NotAllowed);
+        }
+        Ok(())
+    }
+
+    // a moderator lets the user in, unless banned in the meantime
+    pub fn approve_join_request(
+        &mut self,
+        room_id: &Uuid,
+        by: &Uuid,
+        user_id: Uuid,
+    ) -> Result<(), RoomError> {
+        let room = self.managed_room_mut(room_id, by)?;
+        if room.is_member_banned(&user_id) {
+            return Err(RoomError::NotAllowed);
+        }
+        if !room.has_member(&user_id) {
+            room.add_member(user_id);
         }
+        Ok(())
+    }
+
+    // the room stays as it is, only moderators may deny though
+    pub fn deny_join_request(&mut self, room_id: &Uuid, by: &Uuid) -> Result<(), RoomError> {
+        self.managed_room_mut(room_id, by).map(|_| ())
+    }
+
+    // moderators ban or mute members, other moderators only the owner or an admin,
+    // nobody the owner
+    pub fn sanction_member(
+        &mut self,
+        room_id: &Uuid,
+        by: &Uuid,
+        user_id: Uuid,
+        kind: SanctionKind,
+    ) -> Result<(), RoomError> {
+        let room = self.moderated_room_mut(room_id, by, &user_id)?;
+        match kind {
+            SanctionKind::Ban => if !room.is_member_banned(&user_id) {
+                room.bann_member(user_id);
+            },
+            SanctionKind::Mute => if !room.is_member_muted(&user_id) {
+                room.mute_member(user_id);
+            },
+        }
+        Ok(())
+    }
+
+    // the user leaves the room without a ban, same rules as for sanctions
+    pub fn kick_member(&mut self, room_id: &Uuid, by: &Uuid, user_id: &Uuid) -> Result<(), RoomError> {
+        let room = self.moderated_room_mut(room_id, by, user_id)?;
+        if !room.remove_member(user_id) {
+            return Err(RoomError::NotMember);
+        }
+        // a kicked moderator rejoining is a plain member
+        room.remove_moderator(user_id);
+        Ok(())
     }
 
     pub fn ban_member(&mut self, room_id: &Uuid, user_id: Uuid) {
@@ -335,6 +493,115 @@ where
         }
     }
 
+    fn room_mut(&mut self, room_id: &Uuid) -> Option<&mut Room> {
+        match self.find_room_match(room_id) {
+            Some((counter, true)) => self.public_rooms.get_mut(counter),
+            Some((counter, false)) => self.private_rooms.get_mut(counter),
+            None => None,
+        }
+    }
+
+    // checks that the user owns the room or is an admin
+    fn owned_room_mut(&mut self, room_id: &Uuid, by: &Uuid) -> Result<&mut Room, RoomError> {
+        let admin = self.is_admin(by);
+        match self.room_mut(room_id) {
+            Some(room) => if admin || room.is_owner(by) {
+                Ok(room)
+            } else {
+                Err(RoomError::NotAllowed)
+            },
+            None => Err(RoomError::UnknownRoom),
+        }
+    }
+
+    // checks that `by` may take moderation actions against the user
+    fn moderated_room_mut(
+        &mut self,
+        room_id: &Uuid,
+        by: &Uuid,
+        user_id: &Uuid,
+    ) -> Result<&mut Room, RoomError> {
+        let admin = self.is_admin(by);
+        let room = self.managed_room_mut(room_id, by)?;
+        if room.is_owner(user_id) || by == user_id
+            || (room.has_moderator(user_id) && !admin && !room.is_owner(by))
+        {
+            return Err(RoomError::NotAllowed);
+        }
+        Ok(room)
+    }
+
+    // checks that the editor may change the settings of the room
+    fn managed_room_mut(&mut self, room_id: &Uuid, editor: &Uuid) -> Result<&mut Room, RoomError> {
+        let admin = self.is_admin(editor);
+        match self.room_mut(room_id) {
+            Some(room) => if admin || room.can_manage(editor) {
+                Ok(room)
+            } else {
+                Err(RoomError::NotAllowed)
+            },
+            None => Err(RoomError::UnknownRoom),
+        }
+    }
+
+    pub fn set_room_topic(
+        &mut self,
+        room_id: &Uuid,
+        editor: &Uuid,
+        topic: String,
+    ) -> Result<(), RoomError> {
+        self.managed_room_mut(room_id, editor)?.set_topic(topic)
+    }
+
+    pub fn rename_room(
+        &mut self,
+        room_id: &Uuid,
+        editor: &Uuid,
+        name: String,
+    ) -> Result<(), RoomError> {
+        if self.find_room_by_name(&name)
+            .map_or(false, |room| !room.eq_by_uuid(room_id))
+        {
+            return Err(RoomError::NameTaken);
+        }
+        self.managed_room_mut(room_id, editor)?.rename(name)
+    }
+
+    // private rooms are kept apart from public ones, the room changes lists
+    pub fn set_room_private(
+        &mut self,
+        room_id: &Uuid,
+        editor: &Uuid,
+        private: bool,
+    ) -> Result<(), RoomError> {
+        self.managed_room_mut(room_id, editor)?;
+        let mut room = match self.find_room_match(room_id) {
+            Some((counter, true)) => self.public_rooms.remove(counter),
+            Some((counter, false)) => self.private_rooms.remove(counter),
+            None => return Err(RoomError::UnknownRoom),
+        };
+        room.set_private(private);
+        self.add_room(room);
+        Ok(())
+    }
+
+    pub fn set_room_hidden(
+        &mut self,
+        room_id: &Uuid,
+        editor: &Uuid,
+        hidden: bool,
+    ) -> Result<(), RoomError> {
+        self.managed_room_mut(room_id, editor)?.set_hidden(hidden);
+        Ok(())
+    }
+
+    pub fn find_room_by_name(&self, name: &str) -> Option<&Room> {
+        self.public_rooms
+            .iter()
+            .chain(self.private_rooms.iter())
+            .find(|room| room.get_name() == name)
+    }
+
     fn find_room_match(&self, id: &Uuid) -> Option<(usize, bool)> {
         let mut counter = 0;
         let mut matched = false;
@@ -425,7 +692,15 @@ fn test_room() {
     //the controller consumes the "inserted" room entirely
     controller.add_room(room);
     assert_eq!(id, *controller.find_room(&id).unwrap().get_id());
-    controller.remove_room(&id);
+    assert_eq!(
+        id,
+        *controller.find_room_by_name("Testroom").unwrap().get_id()
+    );
+    assert_eq!(
+        Err(RoomError::NotAllowed),
+        controller.remove_room(&id, &Uuid::new_v4())
+    );
+    assert_eq!(Ok(()), controller.remove_room(&id, owner.get_id()));
     assert_eq!(None, controller.find_room(&id));
 }
 
@@ -487,14 +762,27 @@ fn test_moderator() {
     let mut controller = Controller::new(user_data_interface, room_data_interface);
 
     controller.add_room(room);
-    controller.add_moderator_to_room(&id, user.copy_id());
+    assert_eq!(
+        Err(RoomError::NotAllowed),
+        controller.add_moderator_to_room(&id, user.get_id(), user.copy_id())
+    );
+    controller
+        .add_moderator_to_room(&id, owner.get_id(), user.copy_id())
+        .unwrap();
     assert!(
         controller
             .find_room(&id)
             .unwrap()
             .has_moderator(user.get_id())
     );
-    controller.remove_moderator_from_room(&id, user.get_id());
+    assert_eq!(
+        Err(RoomError::NotAllowed),
+        controller.remove_moderator_from_room(&id, user.get_id(), user.get_id())
+    );
+    assert_eq!(
+        Ok(true),
+        controller.remove_moderator_from_room(&id, owner.get_id(), user.get_id())
+    );
     assert!(!controller
         .find_room(&id)
         .unwrap()
@@ -596,3 +884,486 @@ fn test_room_data_interface(){
     println!("{:?}",room_data_provider.provide_room_data());
 
 }*/
+
+#[test]
+fn test_room_settings() {
+    use mock_data::*;
+    use user::User;
+
+    let owner = User::new(
+        "testinator@example.com".to_string(),
+        "Test Test".to_string(),
+        "testinator".to_string(),
+        "1234567".to_string(),
+    );
+    let user = User::new(
+        "blubb@example.com".to_string(),
+        "Test Test".to_string(),
+        "blubb".to_string(),
+        "1234567".to_string(),
+    );
+    let room = Room::new("Testroom".to_string(), owner.copy_id());
+    let id = room.copy_id();
+
+    let mut user_data_interface = MockUserDataImpl::new();
+    let user_data = user_data_interface.provide_user_data();
+    let mut room_data_interface = MockRoomDataImpl::new(&user_data);
+    let mut controller = Controller::new(user_data_interface, room_data_interface);
+
+    controller.add_room(room);
+    assert_eq!(
+        Err(RoomError::NotAllowed),
+        controller.set_room_topic(&id, user.get_id(), "spam".to_string())
+    );
+    controller
+        .add_moderator_to_room(&id, owner.get_id(), user.copy_id())
+        .unwrap();
+    assert_eq!(
+        Ok(()),
+        controller.set_room_topic(&id, user.get_id(), "Testing".to_string())
+    );
+    assert_eq!("Testing", controller.find_room(&id).unwrap().get_topic());
+    assert!(controller.find_room(&id).unwrap().settings().updated_at.is_some());
+
+    assert_eq!(
+        Err(RoomError::NameTaken),
+        controller.rename_room(&id, owner.get_id(), "testroom one".to_string())
+    );
+    assert_eq!(
+        Err(RoomError::InvalidName),
+        controller.rename_room(&id, owner.get_id(), "".to_string())
+    );
+    assert_eq!(
+        Ok(()),
+        controller.rename_room(&id, owner.get_id(), "Renamed".to_string())
+    );
+    assert_eq!(id, *controller.find_room_by_name("Renamed").unwrap().get_id());
+
+    assert_eq!(
+        Ok(()),
+        controller.set_room_private(&id, owner.get_id(), false)
+    );
+    assert!(!controller.find_room(&id).unwrap().is_private());
+    assert_eq!(
+        Ok(()),
+        controller.set_room_hidden(&id, owner.get_id(), true)
+    );
+    assert!(controller.find_room(&id).unwrap().is_hidden());
+    assert_eq!(
+        Err(RoomError::UnknownRoom),
+        controller.set_room_hidden(&Uuid::new_v4(), owner.get_id(), true)
+    );
+}
+
+#[test]
+fn test_ownership() {
+    use message::ChatMessage;
+    use mock_data::*;
+    use user::User;
+
+    let owner = User::new(
+        "testinator@example.com".to_string(),
+        "Test Test".to_string(),
+        "testinator".to_string(),
+        "1234567".to_string(),
+    );
+    let first = User::new(
+        "blubb@example.com".to_string(),
+        "Test Test".to_string(),
+        "blubb".to_string(),
+        "1234567".to_string(),
+    );
+    let second = User::new(
+        "bla@example.com".to_string(),
+        "Test Test".to_string(),
+        "bla".to_string(),
+        "1234567".to_string(),
+    );
+    let room = Room::new("Testroom".to_string(), owner.copy_id());
+    let id = room.copy_id();
+
+    let mut admin_user = User::new(
+        "admin@example.com".to_string(),
+        "Admin".to_string(),
+        "admin".to_string(),
+        "1234567".to_string(),
+    );
+    admin_user.grant_role(&Role::generate_admin());
+    let admin = admin_user.copy_id();
+
+    let mut user_data_interface = MockUserDataImpl::new();
+    let user_data = user_data_interface.provide_user_data();
+    let mut room_data_interface = MockRoomDataImpl::new(&user_data);
+    let mut controller = Controller::new(user_data_interface, room_data_interface);
+
+    controller.add_room(room);
+    controller.add_user(owner.clone());
+    controller.add_user(admin_user);
+    assert_eq!(
+        Err(RoomError::NotMember),
+        controller.transfer_ownership(&id, owner.get_id(), first.copy_id())
+    );
+    controller.add_member_to_room(&id, first.copy_id());
+    assert_eq!(
+        Ok(()),
+        controller.transfer_ownership(&id, owner.get_id(), first.copy_id())
+    );
+    assert!(controller.find_room(&id).unwrap().is_owner(first.get_id()));
+    assert_eq!(
+        Err(RoomError::NotAllowed),
+        controller.transfer_ownership(&id, owner.get_id(), owner.copy_id())
+    );
+
+    // the owner is no moderator but may delete the messages of others
+    assert!(!controller.find_room(&id).unwrap().has_moderator(first.get_id()));
+    let mut message = ChatMessage::new(
+        "Testroom".to_string(),
+        owner.copy_id(),
+        "testinator".to_string(),
+        "hello".to_string(),
+    );
+    let is_manager = controller.check_room_manager(&id, first.get_id()).is_ok();
+    assert_eq!(Ok(()), message.delete(first.get_id(), is_manager));
+
+    // admins may manage every room
+    controller
+        .add_moderator_to_room(&id, &admin, owner.copy_id())
+        .unwrap();
+    controller
+        .add_moderator_to_room(&id, &admin, second.copy_id())
+        .unwrap();
+    assert_eq!(
+        Ok(()),
+        controller.transfer_ownership(&id, &admin, owner.copy_id())
+    );
+    assert!(!controller.find_room(&id).unwrap().has_moderator(owner.get_id()));
+    controller
+        .add_moderator_to_room(&id, owner.get_id(), first.copy_id())
+        .unwrap();
+
+    // the deleted owner is followed by the longest-standing moderator
+    assert!(controller.remove_user(owner.get_id()));
+    let room = controller.find_room(&id).unwrap();
+    assert!(room.is_owner(second.get_id()));
+    assert!(!room.has_member(owner.get_id()));
+    assert!(room.has_moderator(first.get_id()));
+    assert!(!room.has_moderator(second.get_id()));
+}
+
+#[test]
+fn test_invite_join() {
+    use mock_data::*;
+    use user::User;
+
+    let owner = User::new(
+        "testinator@example.com".to_string(),
+        "Test Test".to_string(),
+        "testinator".to_string(),
+        "1234567".to_string(),
+    );
+    let user = User::new(
+        "blubb@example.com".to_string(),
+        "Test Test".to_string(),
+        "blubb".to_string(),
+        "1234567".to_string(),
+    );
+    let mut room = Room::new("Testroom".to_string(), owner.copy_id());
+    room.set_private(true);
+    let id = room.copy_id();
+
+    let mut user_data_interface = MockUserDataImpl::new();
+    let user_data = user_data_interface.provide_user_data();
+    let mut room_data_interface = MockRoomDataImpl::new(&user_data);
+    let mut controller = Controller::new(user_data_interface, room_data_interface);
+
+    controller.add_room(room);
+    assert_eq!(Ok(()), controller.check_room_manager(&id, owner.get_id()));
+    assert_eq!(
+        Err(RoomError::NotAllowed),
+        controller.check_room_manager(&id, user.get_id())
+    );
+    assert_eq!(Ok(true), controller.join_room_by_invite(&id, user.copy_id()));
+    assert_eq!(Ok(false), controller.join_room_by_invite(&id, user.copy_id()));
+    assert!(controller.find_room(&id).unwrap().has_member(user.get_id()));
+
+    controller.ban_member(&id, user.copy_id());
+    assert_eq!(
+        Err(RoomError::NotAllowed),
+        controller.join_room_by_invite(&id, user.copy_id())
+    );
+    assert_eq!(
+        Err(RoomError::UnknownRoom),
+        controller.join_room_by_invite(&Uuid::new_v4(), user.copy_id())
+    );
+}
+
+#[test]
+fn test_join_request() {
+    use mock_data::*;
+    use user::User;
+
+    let owner = User::new(
+        "testinator@example.com".to_string(),
+        "Test Test".to_string(),
+        "testinator".to_string(),
+        "1234567".to_string(),
+    );
+    let user = User::new(
+        "blubb@example.com".to_string(),
+        "Test Test".to_string(),
+        "blubb".to_string(),
+        "1234567".to_string(),
+    );
+    let mut room = Room::new("Testroom".to_string(), owner.copy_id());
+    let id = room.copy_id();
+
+    let mut user_data_interface = MockUserDataImpl::new();
+    let user_data = user_data_interface.provide_user_data();
+    let mut room_data_interface = MockRoomDataImpl::new(&user_data);
+    let mut controller = Controller::new(user_data_interface, room_data_interface);
+
+    room.set_private(false);
+    controller.add_room(room);
+    assert_eq!(
+        Err(RoomError::NotAllowed),
+        controller.check_join_request(&id, user.get_id())
+    );
+    controller
+        .set_room_private(&id, owner.get_id(), true)
+        .unwrap();
+    assert_eq!(Ok(()), controller.check_join_request(&id, user.get_id()));
+
+    assert_eq!(
+        Err(RoomError::NotAllowed),
+        controller.approve_join_request(&id, user.get_id(), user.copy_id())
+    );
+    assert_eq!(
+        Err(RoomError::NotAllowed),
+        controller.deny_join_request(&id, user.get_id())
+    );
+    assert_eq!(Ok(()), controller.deny_join_request(&id, owner.get_id()));
+    assert_eq!(
+        Ok(()),
+        controller.approve_join_request(&id, owner.get_id(), user.copy_id())
+    );
+    assert!(controller.find_room(&id).unwrap().has_member(user.get_id()));
+    assert_eq!(
+        Err(RoomError::NotAllowed),
+        controller.check_join_request(&id, user.get_id())
+    );
+}
+
+#[test]
+fn test_sanction_member() {
+    use mock_data::*;
+    use user::User;
+
+    let owner = User::new(
+        "testinator@example.com".to_string(),
+        "Test Test".to_string(),
+        "testinator".to_string(),
+        "1234567".to_string(),
+    );
+    let moderator = User::new(
+        "blubb@example.com".to_string(),
+        "Test Test".to_string(),
+        "blubb".to_string(),
+        "1234567".to_string(),
+    );
+    let user = User::new(
+        "bla@example.com".to_string(),
+        "Test Test".to_string(),
+        "bla".to_string(),
+        "1234567".to_string(),
+    );
+    let room = Room::new("Testroom".to_string(), owner.copy_id());
+    let id = room.copy_id();
+
+    let mut user_data_interface = MockUserDataImpl::new();
+    let user_data = user_data_interface.provide_user_data();
+    let mut room_data_interface = MockRoomDataImpl::new(&user_data);
+    let mut controller = Controller::new(user_data_interface, room_data_interface);
+
+    controller.add_room(room);
+    controller.add_member_to_room(&id, user.copy_id());
+    controller
+        .add_moderator_to_room(&id, owner.get_id(), moderator.copy_id())
+        .unwrap();
+
+    assert_eq!(
+        Err(RoomError::NotAllowed),
+        controller.sanction_member(&id, user.get_id(), moderator.copy_id(), SanctionKind::Mute)
+    );
+    assert_eq!(
+        Err(RoomError::NotAllowed),
+        controller.sanction_member(&id, moderator.get_id(), owner.copy_id(), SanctionKind::Ban)
+    );
+    assert_eq!(
+        Ok(()),
+        controller.sanction_member(&id, moderator.get_id(), user.copy_id(), SanctionKind::Mute)
+    );
+    assert!(controller.find_room(&id).unwrap().is_member_muted(user.get_id()));
+    assert_eq!(
+        Ok(()),
+        controller.sanction_member(&id, owner.get_id(), moderator.copy_id(), SanctionKind::Ban)
+    );
+    let room = controller.find_room(&id).unwrap();
+    assert!(room.is_member_banned(moderator.get_id()));
+    assert!(!room.has_member(moderator.get_id()));
+    assert!(!room.has_moderator(moderator.get_id()));
+}
+
+#[test]
+fn test_kick_member() {
+    use mock_data::*;
+    use user::User;
+
+    let owner = User::new(
+        "testinator@example.com".to_string(),
+        "Test Test".to_string(),
+        "testinator".to_string(),
+        "1234567".to_string(),
+    );
+    let user = User::new(
+        "blubb@example.com".to_string(),
+        "Test Test".to_string(),
+        "blubb".to_string(),
+        "1234567".to_string(),
+    );
+    let room = Room::new("Testroom".to_string(), owner.copy_id());
+    let id = room.copy_id();
+
+    let mut user_data_interface = MockUserDataImpl::new();
+    let user_data = user_data_interface.provide_user_data();
+    let mut room_data_interface = MockRoomDataImpl::new(&user_data);
+    let mut controller = Controller::new(user_data_interface, room_data_interface);
+
+    controller.add_room(room);
+    controller.add_member_to_room(&id, user.copy_id());
+    assert_eq!(
+        Err(RoomError::NotAllowed),
+        controller.kick_member(&id, user.get_id(), owner.get_id())
+    );
+    controller
+        .add_moderator_to_room(&id, owner.get_id(), user.copy_id())
+        .unwrap();
+    assert_eq!(Ok(()), controller.kick_member(&id, owner.get_id(), user.get_id()));
+    let room = controller.find_room(&id).unwrap();
+    assert!(!room.has_member(user.get_id()));
+    assert!(!room.has_moderator(user.get_id()));
+    assert!(!room.is_member_banned(user.get_id()));
+    assert_eq!(
+        Err(RoomError::NotMember),
+        controller.kick_member(&id, owner.get_id(), user.get_id())
+    );
+}
+
+#[test]
+fn test_restore_sanctions() {
+    use chrono::Local;
+    use mock_data::*;
+    use sanctions::Sanction;
+    use serde_json;
+
+    // rooms and users get new ids on every start
+    let start = || {
+        let mut user_data_interface = MockUserDataImpl::new();
+        let user_data = user_data_interface.provide_user_data();
+        let room_data_interface = MockRoomDataImpl::new(&user_data);
+        let mut controller = Controller::new(user_data_interface, room_data_interface);
+        controller.generate_room("Testroom".to_string(), Uuid::nil());
+        controller
+    };
+
+    let mut controller = start();
+    let room = controller.find_room_by_name("Testroom").unwrap().copy_id();
+    let user = controller.find_user_by_username("user3").unwrap().copy_id();
+    controller.ban_member(&room, user);
+    let mut sanctions = Sanctions::new();
+    let ban = Sanction::new(room, user, SanctionKind::Ban, Uuid::nil(), None, None, Local::now());
+    sanctions.add(ban);
+    let gone = Sanction::new(
+        Uuid::new_v4(),
+        user,
+        SanctionKind::Mute,
+        Uuid::nil(),
+        None,
+        None,
+        Local::now(),
+    );
+    sanctions.add(gone);
+    controller.name_sanctions(&mut sanctions);
+    let stored = serde_json::to_string(&sanctions).unwrap();
+
+    let mut controller = start();
+    let mut sanctions: Sanctions = serde_json::from_str(&stored).unwrap();
+    controller.restore_sanctions(&mut sanctions);
+    let user = controller.find_user_by_username("user3").unwrap().copy_id();
+    let room = controller.find_room_by_name("Testroom").unwrap();
+    assert!(room.is_member_banned(&user));
+    assert!(sanctions.get(room.get_id(), &user, SanctionKind::Ban).is_some());
+    // the muted room did not come back
+    assert_eq!(1, sanctions.all().len());
+}
+
+#[test]
+fn test_restore_server_bans() {
+    use chrono::Local;
+    use mock_data::*;
+    use server_bans::ServerBan;
+    use serde_json;
+
+    // users get new ids on every start
+    let start = || {
+        let mut user_data_interface = MockUserDataImpl::new();
+        let user_data = user_data_interface.provide_user_data();
+        let room_data_interface = MockRoomDataImpl::new(&user_data);
+        Controller::new(user_data_interface, room_data_interface)
+    };
+
+    let mut controller = start();
+    let user = controller.find_user_by_username("user3").unwrap().copy_id();
+    let bans = ServerBans::new();
+    bans.add(ServerBan::new(user, vec![], Uuid::nil(), None, None, Local::now()));
+    bans.add(ServerBan::new(Uuid::new_v4(), vec![], Uuid::nil(), None, None, Local::now()));
+    controller.name_server_bans(&bans);
+    let stored = serde_json::to_string(&bans.all()).unwrap();
+
+    let mut controller = start();
+    let bans = ServerBans::new();
+    bans.replace(serde_json::from_str(&stored).unwrap());
+    controller.restore_server_bans(&bans);
+    let user = controller.find_user_by_username("user3").unwrap().copy_id();
+    assert!(bans.check(Some(&user), None, Local::now()).is_some());
+    // the unknown user did not come back
+    assert_eq!(1, bans.all().len());
+}
+
+#[test]
+fn test_added_users() {
+    use bots::{BotRegistry, EchoBot};
+    use mock_data::*;
+
+    let mut user_data_interface = MockUserDataImpl::new();
+    let user_data = user_data_interface.provide_user_data();
+    let room_data_interface = MockRoomDataImpl::new(&user_data);
+    let mut controller = Controller::new(user_data_interface, room_data_interface);
+
+    let mut bots = BotRegistry::new();
+    let bot = bots.register(Box::new(EchoBot));
+    let id = bot.copy_id();
+    assert!(controller.add_user(bot));
+    // `/whois echobot`, mentions and sanctions find bots like other users
+    let found = controller.find_user_by_username("echobot").unwrap();
+    assert_eq!(id, found.copy_id());
+    assert!(bots.is_bot(found.get_id()));
+    assert!(controller.find_user(&id).is_some());
+
+    // a second bot of the same name is turned away
+    let twin = BotRegistry::new().register(Box::new(EchoBot));
+    assert!(!controller.add_user(twin));
+    assert!(!controller.add_user(user_data[0].clone()));
+
+    assert!(controller.remove_user(&id));
+    assert!(controller.find_user_by_username("echobot").is_none());
+}
@@ -0,0 +1,251 @@
+// Invite codes for private rooms.
+//
+// Moderators create invites for their room, an invite may expire and may be
+// limited to a number of uses. Invites are kept by room id, so they survive
+// renames of the room. Ids change on restart, stored invites find their room
+// again by name.
+
+use chrono::{DateTime, Local};
+use protocol::ErrorCode;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// length of generated codes, about five random bits per character
+const CODE_LENGTH: usize = 16;
+
+// no 0/o and 1/l, codes are typed in by hand now and then
+const CODE_ALPHABET: &[u8; 32] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Invite {
+    pub code: String,
+    pub room: Uuid,
+    // set before the invites are stored
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub room_name: String,
+    pub creator: Uuid,
+    pub created_at: DateTime<Local>,
+    pub expires_at: Option<DateTime<Local>>,
+    // none allows any number of uses
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InviteError {
+    UnknownInvite,
+    Expired,
+    UsedUp,
+}
+
+impl InviteError {
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            InviteError::UnknownInvite => ErrorCode::UnknownInvite,
+            InviteError::Expired | InviteError::UsedUp => ErrorCode::InviteExpired,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match *self {
+            InviteError::UnknownInvite => "Invite not found".to_string(),
+            InviteError::Expired => "Invite has expired".to_string(),
+            InviteError::UsedUp => "Invite has been used up".to_string(),
+        }
+    }
+}
+
+impl Invite {
+    fn check(&self, now: DateTime<Local>) -> Result<(), InviteError> {
+        if self.expires_at.map_or(false, |expires_at| expires_at <= now) {
+            return Err(InviteError::Expired);
+        }
+        if self.max_uses.map_or(false, |max_uses| self.uses >= max_uses) {
+            return Err(InviteError::UsedUp);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InviteStore {
+    // by code
+    invites: HashMap<String, Invite>,
+}
+
+fn generate_code() -> String {
+    Uuid::new_v4()
+        .as_bytes()
+        .iter()
+        .take(CODE_LENGTH)
+        .map(|byte| CODE_ALPHABET[(*byte % 32) as usize] as char)
+        .collect()
+}
+
+impl InviteStore {
+    pub fn new() -> InviteStore {
+        InviteStore::default()
+    }
+
+    pub fn create(
+        &mut self,
+        room: Uuid,
+        creator: Uuid,
+        expires_at: Option<DateTime<Local>>,
+        max_uses: Option<u32>,
+        now: DateTime<Local>,
+    ) -> Invite {
+        let mut code = generate_code();
+        while self.invites.contains_key(&code) {
+            code = generate_code();
+        }
+        let invite = Invite {
+            code: code.clone(),
+            room,
+            room_name: String::new(),
+            creator,
+            created_at: now,
+            expires_at,
+            max_uses,
+            uses: 0,
+        };
+        self.invites.insert(code, invite.clone());
+        invite
+    }
+
+    // room of a usable invite, does not count as use
+    pub fn check(&self, code: &str, now: DateTime<Local>) -> Result<Uuid, InviteError> {
+        let invite = self.invites.get(code).ok_or(InviteError::UnknownInvite)?;
+        invite.check(now)?;
+        Ok(invite.room)
+    }
+
+    // counts a use of the invite, used up invites are dropped
+    pub fn redeem(&mut self, code: &str, now: DateTime<Local>) -> Result<Uuid, InviteError> {
+        let (room, used_up) = {
+            let invite = self.invites
+                .get_mut(code)
+                .ok_or(InviteError::UnknownInvite)?;
+            invite.check(now)?;
+            invite.uses += 1;
+            (
+                invite.room,
+                invite.max_uses.map_or(false, |max_uses| invite.uses >= max_uses),
+            )
+        };
+        if used_up {
+            self.invites.remove(code);
+        }
+        Ok(room)
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, code: &str) -> Option<&Invite> {
+        self.invites.get(code)
+    }
+
+    // usable invites of the room, oldest first
+    pub fn list(&self, room: &Uuid, now: DateTime<Local>) -> Vec<Invite> {
+        let mut invites: Vec<Invite> = self.invites
+            .values()
+            .filter(|invite| invite.room == *room && invite.check(now).is_ok())
+            .cloned()
+            .collect();
+        invites.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+        invites
+    }
+
+    pub fn revoke(&mut self, code: &str) -> Option<Invite> {
+        self.invites.remove(code)
+    }
+
+    pub fn set_names<F>(&mut self, mut names: F)
+    where
+        F: FnMut(&Uuid) -> Option<String>,
+    {
+        for invite in self.invites.values_mut() {
+            if let Some(room_name) = names(&invite.room) {
+                invite.room_name = room_name;
+            }
+        }
+    }
+
+    // moves the invites to the current ids of their rooms by name, drops
+    // those whose room is gone
+    pub fn rebind<F>(&mut self, mut ids: F)
+    where
+        F: FnMut(&str) -> Option<Uuid>,
+    {
+        self.invites = self.invites
+            .drain()
+            .filter_map(|(code, mut invite)| {
+                invite.room = ids(&invite.room_name)?;
+                Some((code, invite))
+            })
+            .collect();
+    }
+
+    pub fn remove_room(&mut self, room: &Uuid) -> bool {
+        let before = self.invites.len();
+        self.invites.retain(|_, invite| invite.room != *room);
+        self.invites.len() != before
+    }
+
+    // drops expired invites, true if any was dropped
+    pub fn purge(&mut self, now: DateTime<Local>) -> bool {
+        let before = self.invites.len();
+        self.invites.retain(|_, invite| invite.check(now).is_ok());
+        self.invites.len() != before
+    }
+}
+
+#[test]
+fn test_invites() {
+    use chrono::Duration;
+
+    let room = Uuid::new_v4();
+    let creator = Uuid::new_v4();
+    let now = Local::now();
+    let mut invites = InviteStore::new();
+
+    let once = invites.create(room, creator, None, Some(1), now);
+    assert_eq!(CODE_LENGTH, once.code.len());
+    assert_eq!(Ok(room), invites.check(&once.code, now));
+    assert_eq!(Ok(room), invites.redeem(&once.code, now));
+    assert_eq!(
+        Err(InviteError::UnknownInvite),
+        invites.redeem(&once.code, now)
+    );
+
+    let expiring = invites.create(room, creator, Some(now + Duration::hours(1)), None, now);
+    let open = invites.create(room, creator, None, None, now);
+    invites.create(Uuid::new_v4(), creator, None, None, now);
+    assert_eq!(2, invites.list(&room, now).len());
+    assert_eq!(Ok(room), invites.redeem(&open.code, now));
+    assert_eq!(Ok(room), invites.redeem(&open.code, now));
+    assert_eq!(2, invites.get(&open.code).unwrap().uses);
+
+    let later = now + Duration::hours(2);
+    assert_eq!(
+        Err(InviteError::Expired),
+        invites.check(&expiring.code, later)
+    );
+    assert_eq!(1, invites.list(&room, later).len());
+    assert!(invites.purge(later));
+    assert!(invites.get(&expiring.code).is_none());
+
+    assert_eq!(Some(open.code.clone()), invites.revoke(&open.code).map(|i| i.code));
+    assert!(invites.list(&room, now).is_empty());
+    assert!(!invites.remove_room(&room));
+
+    // after a restart the room has a new id, invites of other rooms are gone
+    let kept = invites.create(room, creator, None, None, now);
+    invites.set_names(|id| if *id == room { Some("Secret".to_string()) } else { None });
+    let json = ::serde_json::to_string(&invites).unwrap();
+    let mut invites: InviteStore = ::serde_json::from_str(&json).unwrap();
+    let room = Uuid::new_v4();
+    invites.rebind(|name| if name == "Secret" { Some(room) } else { None });
+    let codes: Vec<String> = invites.list(&room, now).into_iter().map(|i| i.code).collect();
+    assert_eq!(vec![kept.code], codes);
+    assert_eq!(1, invites.invites.len());
+}
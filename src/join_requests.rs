@@ -0,0 +1,205 @@
+// Requests to join private rooms.
+//
+// A user knocks on a private room, its moderators approve or deny the request.
+// Requests without an answer expire, every user has at most one pending
+// request per room. Stored requests find their room and user by name, the
+// ids change on restart.
+
+use chrono::{DateTime, Local};
+use protocol::ErrorCode;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JoinRequest {
+    pub id: Uuid,
+    pub room: Uuid,
+    // set before the requests are stored
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub room_name: String,
+    pub user: Uuid,
+    pub username: String,
+    pub requested_at: DateTime<Local>,
+    pub expires_at: DateTime<Local>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinOutcome {
+    Approved,
+    Denied,
+    Expired,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JoinRequestError {
+    UnknownRequest,
+    AlreadyRequested,
+}
+
+impl JoinRequestError {
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            JoinRequestError::UnknownRequest => ErrorCode::UnknownJoinRequest,
+            JoinRequestError::AlreadyRequested => ErrorCode::JoinRequestPending,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match *self {
+            JoinRequestError::UnknownRequest => "Join request not found".to_string(),
+            JoinRequestError::AlreadyRequested => {
+                "You already asked to join this room".to_string()
+            }
+        }
+    }
+}
+
+// pending requests, oldest first
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JoinRequests {
+    requests: Vec<JoinRequest>,
+}
+
+impl JoinRequests {
+    pub fn new() -> JoinRequests {
+        JoinRequests::default()
+    }
+
+    pub fn request(
+        &mut self,
+        room: Uuid,
+        user: Uuid,
+        username: String,
+        now: DateTime<Local>,
+        expires_at: DateTime<Local>,
+    ) -> Result<JoinRequest, JoinRequestError> {
+        if self.requests
+            .iter()
+            .any(|request| request.room == room && request.user == user && request.expires_at > now)
+        {
+            return Err(JoinRequestError::AlreadyRequested);
+        }
+        let request = JoinRequest {
+            id: Uuid::new_v4(),
+            room,
+            room_name: String::new(),
+            user,
+            username,
+            requested_at: now,
+            expires_at,
+        };
+        self.requests.push(request.clone());
+        Ok(request)
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<&JoinRequest> {
+        self.requests.iter().find(|request| request.id == *id)
+    }
+
+    // removes the request once it is answered
+    pub fn take(&mut self, id: &Uuid) -> Option<JoinRequest> {
+        let position = self.requests.iter().position(|request| request.id == *id)?;
+        Some(self.requests.remove(position))
+    }
+
+    pub fn pending(&self, room: &Uuid, now: DateTime<Local>) -> Vec<JoinRequest> {
+        self.requests
+            .iter()
+            .filter(|request| request.room == *room && request.expires_at > now)
+            .cloned()
+            .collect()
+    }
+
+    // removes and answers the expired requests
+    pub fn expire(&mut self, now: DateTime<Local>) -> Vec<JoinRequest> {
+        let (expired, pending) = self.requests
+            .drain(..)
+            .partition(|request| request.expires_at <= now);
+        self.requests = pending;
+        expired
+    }
+
+    pub fn set_names<F>(&mut self, mut names: F)
+    where
+        F: FnMut(&Uuid) -> Option<String>,
+    {
+        for request in &mut self.requests {
+            if let Some(room_name) = names(&request.room) {
+                request.room_name = room_name;
+            }
+        }
+    }
+
+    // moves the requests to the current ids of their room and user by name,
+    // drops those whose room or user is gone
+    pub fn rebind<F>(&mut self, mut ids: F)
+    where
+        F: FnMut(&str, &str) -> Option<(Uuid, Uuid)>,
+    {
+        self.requests = self.requests
+            .drain(..)
+            .filter_map(|mut request| {
+                let (room, user) = ids(&request.room_name, &request.username)?;
+                request.room = room;
+                request.user = user;
+                Some(request)
+            })
+            .collect();
+    }
+
+    pub fn remove_room(&mut self, room: &Uuid) -> bool {
+        let before = self.requests.len();
+        self.requests.retain(|request| request.room != *room);
+        self.requests.len() != before
+    }
+}
+
+#[test]
+fn test_join_requests() {
+    use chrono::Duration;
+
+    let room = Uuid::new_v4();
+    let user = Uuid::new_v4();
+    let now = Local::now();
+    let later = now + Duration::hours(1);
+    let mut requests = JoinRequests::new();
+
+    let first = requests
+        .request(room, user, "user".to_string(), now, later)
+        .unwrap();
+    assert_eq!(
+        Err(JoinRequestError::AlreadyRequested),
+        requests.request(room, user, "user".to_string(), now, later)
+    );
+    let other = requests
+        .request(Uuid::new_v4(), user, "user".to_string(), now, later)
+        .unwrap();
+    assert_eq!(vec![first.clone()], requests.pending(&room, now));
+
+    assert_eq!(Some(first.clone()), requests.take(&first.id));
+    assert!(requests.take(&first.id).is_none());
+    assert!(requests.pending(&room, now).is_empty());
+
+    let expired = requests.expire(later);
+    assert_eq!(vec![other.clone()], expired);
+    assert!(requests.get(&other.id).is_none());
+
+    requests
+        .request(room, user, "user".to_string(), now, later)
+        .unwrap();
+    assert!(requests.remove_room(&room));
+    assert!(!requests.remove_room(&room));
+
+    // after a restart room and user have new ids
+    let pending = requests
+        .request(room, user, "user".to_string(), now, later)
+        .unwrap();
+    requests.set_names(|_| Some("Secret".to_string()));
+    let (room, user) = (Uuid::new_v4(), Uuid::new_v4());
+    requests.rebind(|room_name, username| {
+        assert_eq!(("Secret", "user"), (room_name, username));
+        Some((room, user))
+    });
+    let pending = requests.get(&pending.id).unwrap();
+    assert_eq!((room, user), (pending.room, pending.user));
+}
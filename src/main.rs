@@ -5,49 +5,125 @@ extern crate bytes;
 extern crate chrono;
 extern crate env_logger;
 extern crate futures;
+#[macro_use]
+extern crate log;
 extern crate openssl;
 extern crate rand;
+extern crate regex;
 extern crate serde;
 extern crate serde_json;
 extern crate tokio_core;
 extern crate tokio_io;
+extern crate toml;
 extern crate uuid;
 
 #[macro_use]
 extern crate serde_derive;
 
-use actix::{Addr, Arbiter, Syn};
-use actix_web::{http, middleware, server::HttpServer, App, HttpResponse};
-//use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use actix::{Actor, Addr, Arbiter, Recipient, Syn};
+use actix_web::server::{HttpServer, StopServer};
+use actix_web::{http, middleware, App, HttpResponse};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[macro_use]
 mod actors;
+mod attachments;
+mod audit;
+mod auth;
+mod automod;
+mod bots;
+mod commands;
+mod config;
 mod controller;
 mod external_data_source;
+mod invites;
+mod join_requests;
+mod mentions;
+mod message;
 mod mock_data;
+mod protocol;
+mod ratelimit;
 mod role;
 mod room;
+mod routes;
+mod sanctions;
+mod search;
+mod server_bans;
+mod storage;
+mod tls;
+mod unread;
 mod user;
+mod validation;
 
 fn main() {
+    let config = match config::Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(config::ConfigError::Help) => {
+            print!("{}", config::USAGE);
+            return;
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(2);
+        }
+    };
+
     // Enable logger
-    ::std::env::set_var("RUST_LOG", "info");
-    env_logger::init();
+    env_logger::Builder::new().parse(&config.log_level).init();
 
-    // Enable ssl and get certs
-    //    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-    //    builder.set_private_key_file("key.pem", SslFiletype::PEM).unwrap();
-    //    builder.set_certificate_chain_file("cert.pem").unwrap();
+    let storage = storage::Storage::new(config.storage.backend, &config.storage.path)
+        .expect("Cannot open storage");
 
     let sys = actix::System::new("chat");
 
     //Start chat server actor in seperate thread
-    let server: Addr<Syn, _> = Arbiter::start(|_| actors::chatserver::ChatServer::default());
+    // Server wide bans, changed by the chat server and checked by all workers
+    let bans = Arc::new(server_bans::ServerBans::new());
+
+    let blobs = attachments::BlobStore::new(&config.attachments.path)
+        .expect("Cannot open attachment store");
 
-    HttpServer::new(
+    let server_config = config.clone();
+    let server_bans = bans.clone();
+    let server_blobs = blobs.clone();
+    let server: Addr<Syn, _> = Arbiter::start(move |_| {
+        let mut server = actors::chatserver::ChatServer::new(
+            server_config.clone(),
+            storage,
+            server_bans,
+            server_blobs,
+        );
+        for name in &server_config.bots {
+            match bots::create(name) {
+                Some(bot) => server.register_bot(bot),
+                None => error!("Unknown bot {}", name),
+            }
+        }
+        server
+    });
+
+    // Signs session tokens, shared by all workers
+    let tokens = Arc::new(match config.auth.secret {
+        Some(ref secret) => {
+            auth::TokenService::new(secret.as_bytes().to_vec(), config.auth.token_lifetime)
+        }
+        None => auth::TokenService::with_random_secret(config.auth.token_lifetime),
+    });
+
+    let chat_server = server.clone();
+    let app_config = config.clone();
+    let mut http_server = HttpServer::new(
         move || {
             // Websocket sessions state
-            App::with_state(actors::websocket::WsChatSessionState { addr: server.clone() })
+            App::with_state(actors::websocket::WsChatSessionState {
+                addr: server.clone(),
+                tokens: tokens.clone(),
+                bans: bans.clone(),
+                config: app_config.clone(),
+                blobs: blobs.clone(),
+            })
                 .middleware(middleware::Logger::default())
                  // redirect to websocket.html
                 .resource("/", |r| r.method(http::Method::GET).f(|_| {
@@ -55,13 +131,88 @@ fn main() {
                         .header("LOCATION", "/ws")
                         .finish()
                 }))
+                .resource("/auth/login", |r| r.method(http::Method::POST).f(routes::auth::login))
+                .resource("/auth/refresh", |r| r.method(http::Method::POST).f(routes::auth::refresh))
+                .resource("/auth/logout", |r| r.method(http::Method::POST).f(routes::auth::logout))
+                .resource("/attachments", |r| r.method(http::Method::POST).f(routes::attachments::upload))
+                .resource("/attachments/{id}", |r| r.method(http::Method::GET).f(routes::attachments::download))
+                .resource("/search", |r| r.method(http::Method::GET).f(routes::search::search))
+                .resource("/audit", |r| r.method(http::Method::GET).f(routes::audit::audit))
                 // websocket route
                 .resource("/ws/", |r| r.route().f(actors::websocket::chat_route))
     })
-    .bind("0.0.0.0:1888").expect("Cannot bind to 0.0.0.0:8080")
-//    .start_ssl(builder).unwrap();
-    .start();
+    // signals are handled by the `ShutdownCoordinator`
+    .disable_signals()
+    .shutdown_timeout(config.shutdown_timeout());
+
+    for addr in &config.bind {
+        http_server = http_server
+            .bind(addr.as_str())
+            .unwrap_or_else(|err| panic!("Cannot bind to {}: {}", addr, err));
+    }
+
+    let mut http_servers: Vec<Recipient<Syn, StopServer>> = Vec::new();
+    if config.tls.enabled {
+        // Enable ssl and get certs
+        let store = tls::CertificateStore::open(&config.tls.cert, &config.tls.key)
+            .unwrap_or_else(|err| panic!("{}", err));
+        let acceptor =
+            tls::CertificateStore::acceptor(&store).unwrap_or_else(|err| panic!("{}", err));
+        let addr = http_server
+            .start_ssl(acceptor)
+            .expect("Cannot start https server");
+        http_servers.push(addr.recipient());
+
+        if config.tls.reload_interval > 0 {
+            let interval = Duration::from_secs(config.tls.reload_interval);
+            let _: Addr<Syn, _> = Arbiter::start(move |_| {
+                actors::certreloader::CertReloader { store, interval }
+            });
+        }
+        if let Some(addr) = start_https_redirect(&config) {
+            http_servers.push(addr);
+        }
+    } else {
+        let addr: Addr<Syn, _> = http_server.start();
+        http_servers.push(addr.recipient());
+    }
+
+    let grace_period = Duration::from_secs(config.shutdown_grace_period);
+    let _: Addr<Syn, _> = actors::shutdown::ShutdownCoordinator::new(
+        chat_server,
+        http_servers,
+        grace_period,
+    ).start();
 
     println!("Server is up and running!");
     let _ = sys.run();
 }
+
+// plain http server answering every request with a redirect to https
+fn start_https_redirect(config: &config::Config) -> Option<Recipient<Syn, StopServer>> {
+    if config.tls.redirect_bind.is_empty() {
+        return None;
+    }
+    let https_port = config
+        .bind
+        .first()
+        .and_then(|addr| addr.rsplit(':').next())
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(443);
+
+    let mut redirect_server = HttpServer::new(move || {
+        App::new()
+            .middleware(middleware::Logger::default())
+            .default_resource(move |r| {
+                r.f(move |req| routes::redirect::https_redirect(req, https_port))
+            })
+    }).disable_signals()
+        .shutdown_timeout(config.shutdown_timeout());
+    for addr in &config.tls.redirect_bind {
+        redirect_server = redirect_server
+            .bind(addr.as_str())
+            .unwrap_or_else(|err| panic!("Cannot bind to {}: {}", addr, err));
+    }
+    let addr: Addr<Syn, _> = redirect_server.start();
+    Some(addr.recipient())
+}
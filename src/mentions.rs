@@ -0,0 +1,66 @@
+// `@username` mentions in chat messages.
+//
+// A mention starts with `@` at the beginning of the text or after a character
+// that can not be part of a username, so mail addresses do not mention anyone.
+
+use chrono::{DateTime, Local};
+use message::ChatMessage;
+use uuid::Uuid;
+
+// Mention of a user that was offline when the message was sent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mention {
+    pub message: Uuid,
+    pub room: String,
+    // username of the author
+    pub username: String,
+    pub sent_at: DateTime<Local>,
+}
+
+impl Mention {
+    pub fn new(message: &ChatMessage) -> Mention {
+        Mention {
+            message: message.id,
+            room: message.room.clone(),
+            username: message.username.clone(),
+            sent_at: message.sent_at,
+        }
+    }
+}
+
+fn is_username_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+// usernames mentioned in the text, each once in order of appearance
+pub fn parse_mentions(text: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let mut previous: Option<char> = None;
+
+    for (index, c) in text.char_indices() {
+        if c == '@' && !previous.map_or(false, is_username_char) {
+            let rest = &text[index + 1..];
+            let end = rest.find(|c| !is_username_char(c)).unwrap_or(rest.len());
+            // a trailing dot ends the sentence, not the name
+            let name = rest[..end].trim_end_matches('.');
+            if !name.is_empty() && !names.iter().any(|known| known == name) {
+                names.push(name.to_string());
+            }
+        }
+        previous = Some(c);
+    }
+    names
+}
+
+#[test]
+fn test_parse_mentions() {
+    assert_eq!(
+        vec!["alice".to_string(), "bob_2".to_string()],
+        parse_mentions("@alice and @bob_2, see @alice.")
+    );
+    assert_eq!(
+        vec!["b.c".to_string()],
+        parse_mentions("(@b.c) mail me at a@example.com")
+    );
+    assert!(parse_mentions("@ alone @").is_empty());
+}
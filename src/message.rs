@@ -0,0 +1,331 @@
+// Chat messages posted to rooms.
+//
+// Authors may edit and delete their own messages, moderators of the room may
+// delete any message. Edits keep the previous texts as revisions, deleted
+// messages stay in the history as empty tombstones. Members react to messages
+// with emoji, every user counts once per emoji. Replies name the first message
+// of their thread as parent, threads do not nest.
+
+use attachments::Attachment;
+use chrono::{DateTime, Local};
+use protocol::ErrorCode;
+use uuid::Uuid;
+
+// longest emoji sequence accepted as reaction, in characters
+const MAX_REACTION_LENGTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Revision {
+    pub text: String,
+    // when this text was replaced
+    pub edited_at: DateTime<Local>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: usize,
+    pub users: Vec<Uuid>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub id: Uuid,
+    pub room: String,
+    pub author: Uuid,
+    pub username: String,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<Attachment>,
+    // first message of the thread this message replies to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<Uuid>,
+    // sent with `/me`, the text describes what the author does
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub emote: bool,
+    // users notified by `@username` in the text
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<Uuid>,
+    // replies to this message, including deleted ones
+    #[serde(default)]
+    pub reply_count: usize,
+    #[serde(default)]
+    pub last_reply_at: Option<DateTime<Local>>,
+    pub sent_at: DateTime<Local>,
+    #[serde(default)]
+    pub edited_at: Option<DateTime<Local>>,
+    // earlier texts, oldest first
+    #[serde(default)]
+    pub revisions: Vec<Revision>,
+    // in order of the first use of each emoji
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub deleted_by: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageError {
+    UnknownMessage,
+    NotAllowed,
+    Deleted,
+    InvalidReaction,
+}
+
+impl MessageError {
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            MessageError::UnknownMessage => ErrorCode::UnknownMessage,
+            MessageError::NotAllowed => ErrorCode::NotAllowed,
+            MessageError::Deleted => ErrorCode::MessageDeleted,
+            MessageError::InvalidReaction => ErrorCode::InvalidReaction,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match *self {
+            MessageError::UnknownMessage => "Message not found".to_string(),
+            MessageError::NotAllowed => "You are not allowed to change this message".to_string(),
+            MessageError::Deleted => "Message was deleted".to_string(),
+            MessageError::InvalidReaction => "Reactions must be a single emoji".to_string(),
+        }
+    }
+}
+
+impl ChatMessage {
+    pub fn new(room: String, author: Uuid, username: String, text: String) -> ChatMessage {
+        ChatMessage {
+            id: Uuid::new_v4(),
+            room,
+            author,
+            username,
+            text,
+            attachment: None,
+            parent: None,
+            emote: false,
+            mentions: Vec::new(),
+            reply_count: 0,
+            last_reply_at: None,
+            sent_at: Local::now(),
+            edited_at: None,
+            revisions: Vec::new(),
+            reactions: Vec::new(),
+            deleted: false,
+            deleted_by: None,
+        }
+    }
+
+    // only the author may edit, the replaced text is kept as revision
+    pub fn edit(
+        &mut self,
+        editor: &Uuid,
+        text: String,
+        now: DateTime<Local>,
+    ) -> Result<(), MessageError> {
+        if self.deleted {
+            return Err(MessageError::Deleted);
+        }
+        if self.author != *editor || self.attachment.is_some() {
+            return Err(MessageError::NotAllowed);
+        }
+        let previous = ::std::mem::replace(&mut self.text, text);
+        self.revisions.push(Revision {
+            text: previous,
+            edited_at: now,
+        });
+        self.edited_at = Some(now);
+        Ok(())
+    }
+
+    // the author or a moderator of the room may delete, text and revisions are dropped
+    pub fn delete(&mut self, by: &Uuid, is_moderator: bool) -> Result<(), MessageError> {
+        if self.deleted {
+            return Err(MessageError::Deleted);
+        }
+        if self.author != *by && !is_moderator {
+            return Err(MessageError::NotAllowed);
+        }
+        self.text.clear();
+        self.attachment = None;
+        self.revisions.clear();
+        self.reactions.clear();
+        self.deleted = true;
+        self.deleted_by = Some(*by);
+        Ok(())
+    }
+
+    pub fn is_reply(&self) -> bool {
+        self.parent.is_some()
+    }
+
+    // id of the thread a reply to this message belongs to
+    pub fn thread_id(&self) -> Uuid {
+        self.parent.unwrap_or(self.id)
+    }
+
+    pub fn add_reply(&mut self, reply: &ChatMessage) {
+        self.reply_count += 1;
+        self.last_reply_at = Some(reply.sent_at);
+    }
+
+    // answers the new count of the emoji, none if the user already reacted with it
+    pub fn add_reaction(
+        &mut self,
+        emoji: &str,
+        user: Uuid,
+    ) -> Result<Option<usize>, MessageError> {
+        if self.deleted {
+            return Err(MessageError::Deleted);
+        }
+        if !is_emoji(emoji) {
+            return Err(MessageError::InvalidReaction);
+        }
+        if let Some(reaction) = self.reactions.iter_mut().find(|r| r.emoji == emoji) {
+            if reaction.users.contains(&user) {
+                return Ok(None);
+            }
+            reaction.users.push(user);
+            reaction.count = reaction.users.len();
+            return Ok(Some(reaction.count));
+        }
+        self.reactions.push(Reaction {
+            emoji: emoji.to_string(),
+            count: 1,
+            users: vec![user],
+        });
+        Ok(Some(1))
+    }
+
+    // answers the new count of the emoji, none if the user had not reacted with it
+    pub fn remove_reaction(&mut self, emoji: &str, user: &Uuid) -> Option<usize> {
+        let position = self.reactions.iter().position(|r| r.emoji == emoji)?;
+        let count = {
+            let reaction = &mut self.reactions[position];
+            let index = reaction.users.iter().position(|u| u == user)?;
+            reaction.users.remove(index);
+            reaction.count = reaction.users.len();
+            reaction.count
+        };
+        if count == 0 {
+            self.reactions.remove(position);
+        }
+        Some(count)
+    }
+}
+
+// a short sequence of non ascii symbols, e.g. an emoji with modifiers
+fn is_emoji(text: &str) -> bool {
+    let length = text.chars().count();
+    length > 0
+        && length <= MAX_REACTION_LENGTH
+        && text.chars().any(|c| !c.is_ascii())
+        && !text.chars().any(|c| c.is_whitespace() || c.is_control() || c.is_alphabetic())
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+#[test]
+fn test_edit_and_delete() {
+    let author = Uuid::new_v4();
+    let other = Uuid::new_v4();
+    let mut message = ChatMessage::new(
+        "Main".to_string(),
+        author,
+        "author".to_string(),
+        "helo".to_string(),
+    );
+
+    assert_eq!(
+        Err(MessageError::NotAllowed),
+        message.edit(&other, "hijacked".to_string(), Local::now())
+    );
+    assert_eq!(
+        Ok(()),
+        message.edit(&author, "hello".to_string(), Local::now())
+    );
+    assert_eq!("hello", message.text);
+    assert_eq!(1, message.revisions.len());
+    assert_eq!("helo", message.revisions[0].text);
+    assert!(message.edited_at.is_some());
+
+    assert_eq!(Err(MessageError::NotAllowed), message.delete(&other, false));
+    assert_eq!(Ok(()), message.delete(&other, true));
+    assert!(message.deleted);
+    assert_eq!("", message.text);
+    assert!(message.revisions.is_empty());
+    assert_eq!(Some(other), message.deleted_by);
+    assert_eq!(Err(MessageError::Deleted), message.delete(&author, false));
+    assert_eq!(
+        Err(MessageError::Deleted),
+        message.edit(&author, "again".to_string(), Local::now())
+    );
+}
+
+#[test]
+fn test_reactions() {
+    let author = Uuid::new_v4();
+    let other = Uuid::new_v4();
+    let mut message = ChatMessage::new(
+        "Main".to_string(),
+        author,
+        "author".to_string(),
+        "hello".to_string(),
+    );
+
+    assert_eq!(Ok(Some(1)), message.add_reaction("\u{1f44d}", author));
+    assert_eq!(Ok(None), message.add_reaction("\u{1f44d}", author));
+    assert_eq!(Ok(Some(2)), message.add_reaction("\u{1f44d}", other));
+    assert_eq!(Ok(Some(1)), message.add_reaction("\u{2764}\u{fe0f}", other));
+    assert_eq!(
+        Err(MessageError::InvalidReaction),
+        message.add_reaction("lol", other)
+    );
+    assert_eq!(
+        Err(MessageError::InvalidReaction),
+        message.add_reaction("", other)
+    );
+    assert_eq!(2, message.reactions.len());
+    assert_eq!(vec![author, other], message.reactions[0].users);
+
+    assert_eq!(Some(1), message.remove_reaction("\u{1f44d}", &author));
+    assert_eq!(None, message.remove_reaction("\u{1f44d}", &author));
+    assert_eq!(Some(0), message.remove_reaction("\u{2764}\u{fe0f}", &other));
+    assert_eq!(1, message.reactions.len());
+
+    message.delete(&author, false).unwrap();
+    assert!(message.reactions.is_empty());
+    assert_eq!(
+        Err(MessageError::Deleted),
+        message.add_reaction("\u{1f44d}", other)
+    );
+}
+
+#[test]
+fn test_replies() {
+    let user = Uuid::new_v4();
+    let mut root = ChatMessage::new(
+        "Main".to_string(),
+        user,
+        "user".to_string(),
+        "question".to_string(),
+    );
+    let mut reply = ChatMessage::new(
+        "Main".to_string(),
+        user,
+        "user".to_string(),
+        "answer".to_string(),
+    );
+    reply.parent = Some(root.thread_id());
+
+    assert!(!root.is_reply());
+    assert!(reply.is_reply());
+    assert_eq!(root.id, reply.thread_id());
+
+    root.add_reply(&reply);
+    assert_eq!(1, root.reply_count);
+    assert_eq!(Some(reply.sent_at), root.last_reply_at);
+}
@@ -0,0 +1,308 @@
+// Structured events sent to websocket clients as json text frames.
+//
+// Every event carries its kind in the `type` field, e.g.
+// `{"type": "error", "code": "rate_limited", "message": "..."}`.
+// Clients send commands the same way, e.g. `{"type": "delete", "id": "..."}`,
+// any other text frame is a chat message.
+
+use automod::{Action, Filter, Rule};
+use chrono::{DateTime, Local};
+use invites::Invite;
+use join_requests::{JoinOutcome, JoinRequest};
+use mentions::Mention;
+use message::ChatMessage;
+use room::RoomSettings;
+use sanctions::{Sanction, SanctionKind};
+use server_bans::ServerBan;
+use unread::UnreadCount;
+use serde_json;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    RateLimited,
+    Muted,
+    EmptyMessage,
+    MessageTooLong,
+    InvalidCharacters,
+    FrameTooLarge,
+    InvalidUpload,
+    UploadTooLarge,
+    NotMember,
+    UnknownMessage,
+    NotAllowed,
+    MessageDeleted,
+    InvalidReaction,
+    UnknownRoom,
+    NameTaken,
+    InvalidName,
+    InvalidTopic,
+    UnknownUser,
+    UnknownInvite,
+    InviteExpired,
+    UnknownJoinRequest,
+    JoinRequestPending,
+    NotBanned,
+    MessageBlocked,
+    InvalidRule,
+    UnknownRule,
+    TooManyRules,
+    UnknownCommand,
+    InvalidCommand,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    Error { code: ErrorCode, message: String },
+    Message { message: ChatMessage },
+    MessageEdited { message: ChatMessage },
+    MessageDeleted { id: Uuid, room: String, deleted_by: Uuid },
+    // reply counters of a thread changed, the replies go to thread subscribers
+    ThreadUpdated {
+        id: Uuid,
+        room: String,
+        reply_count: usize,
+        last_reply_at: Option<DateTime<Local>>,
+    },
+    // the receiving user was mentioned, sent to all of the user's sessions
+    Mention { message: ChatMessage },
+    // mentions received while offline
+    UnreadMentions { mentions: Vec<Mention> },
+    // owner or a moderator changed the room, `previous_name` is set on renames
+    RoomSettingsChanged {
+        settings: RoomSettings,
+        previous_name: Option<String>,
+        changed_by: Uuid,
+    },
+    RoomDeleted { room: String, deleted_by: Uuid },
+    // a member read the room up to the message `id`
+    ReadReceipt {
+        room: String,
+        user: Uuid,
+        username: String,
+        id: Uuid,
+    },
+    // answer to `unread_counts`, also sent on connect
+    UnreadCounts { rooms: Vec<UnreadCount> },
+    // answer to `fetch_thread`
+    Thread {
+        message: ChatMessage,
+        replies: Vec<ChatMessage>,
+    },
+    // a reaction was added or removed, `count` is the new total of the emoji
+    Reaction {
+        id: Uuid,
+        room: String,
+        emoji: String,
+        user: Uuid,
+        added: bool,
+        count: usize,
+    },
+    // answer to `create_invite`
+    InviteCreated { room: String, invite: Invite },
+    // answer to `list_invites`, only usable invites
+    Invites { room: String, invites: Vec<Invite> },
+    InviteRevoked { room: String, code: String },
+    // the session's user became a member of the room
+    RoomJoined { settings: RoomSettings },
+    // sent to the moderators of the room and to the requesting session
+    JoinRequested { room: String, request: JoinRequest },
+    // answer to `list_join_requests`
+    JoinRequests {
+        room: String,
+        requests: Vec<JoinRequest>,
+    },
+    // sent to the requester and the moderators, `answered_by` is none for expired requests
+    JoinRequestAnswered {
+        room: String,
+        id: Uuid,
+        user: Uuid,
+        outcome: JoinOutcome,
+        answered_by: Option<Uuid>,
+    },
+    // a member was banned or muted, sent to the room and the sanctioned user
+    Sanctioned { room: String, sanction: Sanction },
+    // sent to the sessions of the kicked user
+    Kicked {
+        room: String,
+        kicked_by: Uuid,
+        reason: Option<String>,
+    },
+    // sent to the room the user was kicked from
+    MemberKicked {
+        room: String,
+        user: Uuid,
+        username: String,
+        kicked_by: Uuid,
+        kicked_by_username: String,
+        reason: Option<String>,
+    },
+    // `lifted_by` is none when the sanction expired
+    SanctionLifted {
+        room: String,
+        user: Uuid,
+        kind: SanctionKind,
+        lifted_by: Option<Uuid>,
+    },
+    // sent to the banned user before the sessions are closed and to the admin
+    ServerBanned { ban: ServerBan },
+    // answer to `server_unban`
+    ServerBanLifted { user: Uuid, lifted_by: Uuid },
+    // answer to `list_server_bans`
+    ServerBans { bans: Vec<ServerBan> },
+    // answers to the automod commands
+    AutomodRuleAdded { room: String, rule: Rule },
+    AutomodRuleRemoved { room: String, id: Uuid },
+    AutomodRules { room: String, rules: Vec<Rule> },
+    // sent to the moderators of the room, `rules` are the ids of the matching flag rules
+    MessageFlagged {
+        message: ChatMessage,
+        rules: Vec<Uuid>,
+    },
+    // output of a slash command, only sent to the session that ran it
+    CommandReply { command: String, text: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientCommand {
+    Edit { id: Uuid, text: String },
+    Delete { id: Uuid },
+    Reply { parent: Uuid, text: String },
+    FetchThread { id: Uuid },
+    Subscribe { id: Uuid },
+    Unsubscribe { id: Uuid },
+    // `receipt` tells the other members of the room
+    MarkRead {
+        room: String,
+        id: Uuid,
+        #[serde(default)]
+        receipt: bool,
+    },
+    UnreadCounts,
+    React { id: Uuid, emoji: String },
+    Unreact { id: Uuid, emoji: String },
+    // owner or admin only, `user` is a username
+    AddModerator { room: String, user: String },
+    RemoveModerator { room: String, user: String },
+    TransferOwnership { room: String, user: String },
+    DeleteRoom { room: String },
+    // changes only the given settings
+    UpdateRoom {
+        room: String,
+        topic: Option<String>,
+        name: Option<String>,
+        private: Option<bool>,
+        hidden: Option<bool>,
+    },
+    // moderators only, `expires_in` is in seconds
+    CreateInvite {
+        room: String,
+        expires_in: Option<u64>,
+        max_uses: Option<u32>,
+    },
+    ListInvites { room: String },
+    RevokeInvite { room: String, code: String },
+    RedeemInvite { code: String },
+    // knock on a private room, its moderators answer by request id
+    RequestJoin { room: String },
+    ListJoinRequests { room: String },
+    ApproveJoin { id: Uuid },
+    DenyJoin { id: Uuid },
+    // moderators only, `user` is a username and `duration` is in seconds,
+    // without duration the sanction lasts until lifted
+    Ban {
+        room: String,
+        user: String,
+        duration: Option<u64>,
+        reason: Option<String>,
+    },
+    Unban { room: String, user: String },
+    Mute {
+        room: String,
+        user: String,
+        duration: Option<u64>,
+        reason: Option<String>,
+    },
+    Unmute { room: String, user: String },
+    // moderators only, removes the user from the room without a ban
+    Kick {
+        room: String,
+        user: String,
+        reason: Option<String>,
+    },
+    // admins only, bans the user from the whole server, `block_ip` also blocks
+    // the addresses of the user's open sessions
+    ServerBan {
+        user: String,
+        duration: Option<u64>,
+        reason: Option<String>,
+        #[serde(default)]
+        block_ip: bool,
+    },
+    ServerUnban { user: String },
+    ListServerBans,
+    // moderators only, rules run in the order they were added
+    AddAutomodRule {
+        room: String,
+        filter: Filter,
+        action: Action,
+    },
+    RemoveAutomodRule { room: String, id: Uuid },
+    ListAutomodRules { room: String },
+}
+
+impl ClientCommand {
+    // none if the frame is not a command but plain chat text
+    pub fn parse(text: &str) -> Option<ClientCommand> {
+        if !text.trim().starts_with('{') {
+            return None;
+        }
+        serde_json::from_str(text).ok()
+    }
+}
+
+impl ServerEvent {
+    pub fn error(code: ErrorCode, message: &str) -> ServerEvent {
+        ServerEvent::Error {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[test]
+fn test_event_serialize() {
+    assert_eq!(
+        "{\"type\":\"error\",\"code\":\"rate_limited\",\"message\":\"slow down\"}",
+        ServerEvent::error(ErrorCode::RateLimited, "slow down").to_json()
+    );
+}
+
+#[test]
+fn test_command_parse() {
+    let id = Uuid::new_v4();
+    assert_eq!(
+        Some(ClientCommand::Delete { id }),
+        ClientCommand::parse(&format!("{{\"type\": \"delete\", \"id\": \"{}\"}}", id))
+    );
+    assert_eq!(None, ClientCommand::parse("hello"));
+    assert_eq!(None, ClientCommand::parse("{not a command}"));
+    assert_eq!(
+        Some(ClientCommand::AddAutomodRule {
+            room: "main".to_string(),
+            filter: Filter::Repeat { max_run: 4 },
+            action: Action::Mute(60),
+        }),
+        ClientCommand::parse(
+            "{\"type\": \"add_automod_rule\", \"room\": \"main\", \
+             \"filter\": {\"kind\": \"repeat\", \"max_run\": 4}, \"action\": {\"mute\": 60}}"
+        )
+    );
+}
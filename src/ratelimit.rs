@@ -0,0 +1,219 @@
+// Flood protection for chat messages.
+//
+// Every session and every user owns a token bucket, a message takes one token
+// from both. Rejected messages count as violations, a user collecting too many
+// violations within the violation window gets the configured penalty.
+
+use config::RateLimitConfig;
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Penalty {
+    Mute,
+    Disconnect,
+}
+
+impl FromStr for Penalty {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Penalty, ()> {
+        match value {
+            "mute" => Ok(Penalty::Mute),
+            "disconnect" => Ok(Penalty::Disconnect),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Allowed,
+    // over the limit, the message is dropped
+    Limited,
+    // over the limit too often, the penalty is applied from now on
+    Penalized(Penalty),
+    // still muted for the given time
+    Muted(Duration),
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    // tokens per second
+    refill: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, refill: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            refill,
+            updated: now,
+        }
+    }
+
+    // refills the bucket, true if a token is left
+    pub fn has_token(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * self.refill).min(self.capacity);
+        self.updated = now;
+        self.tokens >= 1.0
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        if self.has_token(now) {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    sessions: HashMap<usize, TokenBucket>,
+    users: HashMap<Uuid, TokenBucket>,
+    violations: HashMap<Uuid, VecDeque<Instant>>,
+    muted_until: HashMap<Uuid, Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            config,
+            sessions: HashMap::new(),
+            users: HashMap::new(),
+            violations: HashMap::new(),
+            muted_until: HashMap::new(),
+        }
+    }
+
+    // checks a message of `session` sent by `user`
+    pub fn check(&mut self, session: usize, user: Uuid, now: Instant) -> Verdict {
+        if let Some(until) = self.muted_until.get(&user).cloned() {
+            if until > now {
+                return Verdict::Muted(until.duration_since(now));
+            }
+            self.muted_until.remove(&user);
+        }
+
+        let config = &self.config;
+        let session_bucket = self.sessions
+            .entry(session)
+            .or_insert_with(|| TokenBucket::new(config.burst, config.refill, now));
+        let user_bucket = self.users
+            .entry(user)
+            .or_insert_with(|| TokenBucket::new(config.user_burst, config.user_refill, now));
+        // a rejected message takes no token from either bucket
+        if session_bucket.has_token(now) && user_bucket.has_token(now) {
+            session_bucket.try_take(now);
+            user_bucket.try_take(now);
+            return Verdict::Allowed;
+        }
+
+        let window = Duration::from_secs(config.violation_window);
+        let violations = self.violations.entry(user).or_insert_with(VecDeque::new);
+        violations.push_back(now);
+        while violations
+            .front()
+            .map_or(false, |first| now.duration_since(*first) > window)
+        {
+            violations.pop_front();
+        }
+        if violations.len() < config.max_violations as usize {
+            return Verdict::Limited;
+        }
+
+        violations.clear();
+        if config.penalty == Penalty::Mute {
+            self.muted_until
+                .insert(user, now + Duration::from_secs(config.mute_duration));
+        }
+        Verdict::Penalized(config.penalty)
+    }
+
+    pub fn remove_session(&mut self, session: usize) {
+        self.sessions.remove(&session);
+    }
+
+    // forgets a user without sessions, a running mute is kept
+    pub fn remove_user(&mut self, user: &Uuid, now: Instant) {
+        self.users.remove(user);
+        self.violations.remove(user);
+        self.muted_until.retain(|_, until| *until > now);
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    let now = Instant::now();
+    let mut bucket = TokenBucket::new(2, 1.0, now);
+    assert!(bucket.try_take(now));
+    assert!(bucket.try_take(now));
+    assert!(!bucket.try_take(now));
+    assert!(bucket.try_take(now + Duration::from_secs(1)));
+    assert!(!bucket.try_take(now + Duration::from_secs(1)));
+}
+
+#[test]
+fn test_rate_limiter_penalty() {
+    let mut config = RateLimitConfig::default();
+    config.burst = 1;
+    config.refill = 0.0;
+    config.max_violations = 2;
+    config.mute_duration = 30;
+    let mut limiter = RateLimiter::new(config);
+    let user = Uuid::new_v4();
+    let now = Instant::now();
+
+    assert_eq!(Verdict::Allowed, limiter.check(1, user, now));
+    assert_eq!(Verdict::Limited, limiter.check(1, user, now));
+    assert_eq!(
+        Verdict::Penalized(Penalty::Mute),
+        limiter.check(1, user, now)
+    );
+    assert_eq!(
+        Verdict::Muted(Duration::from_secs(20)),
+        limiter.check(2, user, now + Duration::from_secs(10))
+    );
+    // a fresh session of another user is not affected
+    assert_eq!(Verdict::Allowed, limiter.check(3, Uuid::new_v4(), now));
+
+    limiter.remove_session(1);
+    limiter.remove_session(2);
+    limiter.remove_user(&user, now);
+    assert!(limiter.users.get(&user).is_none());
+    assert!(limiter.violations.get(&user).is_none());
+    assert!(limiter.muted_until.contains_key(&user));
+    limiter.remove_user(&user, now + Duration::from_secs(30));
+    assert!(limiter.muted_until.is_empty());
+}
+
+#[test]
+fn test_rate_limiter_rejected_costs_nothing() {
+    let mut config = RateLimitConfig::default();
+    config.burst = 1;
+    config.refill = 0.0;
+    config.user_burst = 2;
+    config.user_refill = 0.0;
+    config.max_violations = 10;
+    let mut limiter = RateLimiter::new(config);
+    let user = Uuid::new_v4();
+    let now = Instant::now();
+
+    assert_eq!(Verdict::Allowed, limiter.check(1, user, now));
+    // the empty session bucket rejects, the user bucket keeps its token
+    assert_eq!(Verdict::Limited, limiter.check(1, user, now));
+    assert_eq!(Verdict::Limited, limiter.check(1, user, now));
+    assert_eq!(Verdict::Allowed, limiter.check(2, user, now));
+}
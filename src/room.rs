@@ -1,7 +1,88 @@
 use chrono::DateTime;
 use chrono::Local;
+use protocol::ErrorCode;
 use uuid::Uuid;
 
+pub const MAX_NAME_LENGTH: usize = 64;
+pub const MAX_TOPIC_LENGTH: usize = 250;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RoomError {
+    UnknownRoom,
+    NotAllowed,
+    NameTaken,
+    InvalidName,
+    InvalidTopic,
+    UnknownUser,
+    NotMember,
+}
+
+impl RoomError {
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            RoomError::UnknownRoom => ErrorCode::UnknownRoom,
+            RoomError::NotAllowed => ErrorCode::NotAllowed,
+            RoomError::NameTaken => ErrorCode::NameTaken,
+            RoomError::InvalidName => ErrorCode::InvalidName,
+            RoomError::InvalidTopic => ErrorCode::InvalidTopic,
+            RoomError::UnknownUser => ErrorCode::UnknownUser,
+            RoomError::NotMember => ErrorCode::NotMember,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match *self {
+            RoomError::UnknownRoom => "Room not found".to_string(),
+            RoomError::NotAllowed => "You are not allowed to do this in the room".to_string(),
+            RoomError::NameTaken => "A room with this name exists already".to_string(),
+            RoomError::InvalidName => format!(
+                "Room names must have 1 to {} characters without control characters",
+                MAX_NAME_LENGTH
+            ),
+            RoomError::InvalidTopic => format!(
+                "Topics must not be longer than {} characters",
+                MAX_TOPIC_LENGTH
+            ),
+            RoomError::UnknownUser => "User not found".to_string(),
+            RoomError::NotMember => "The user is not a member of the room".to_string(),
+        }
+    }
+}
+
+// Public view of the room settings
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RoomSettings {
+    pub id: Uuid,
+    pub name: String,
+    pub topic: String,
+    pub private: bool,
+    pub hidden: bool,
+    pub owner: Uuid,
+    pub moderators: Vec<Uuid>,
+    pub updated_at: Option<DateTime<Local>>,
+}
+
+impl RoomSettings {
+    // names of the settings that differ from `before`, ownership and
+    // moderators are not settings changed by moderators
+    pub fn changes(&self, before: &RoomSettings) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+        if self.name != before.name {
+            changes.push("name");
+        }
+        if self.topic != before.topic {
+            changes.push("topic");
+        }
+        if self.private != before.private {
+            changes.push("private");
+        }
+        if self.hidden != before.hidden {
+            changes.push("hidden");
+        }
+        changes
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Room {
     id: Uuid,
@@ -132,6 +213,96 @@ impl Room {
     pub fn get_id(&self) -> &Uuid {
         &self.id
     }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn get_owner(&self) -> &Uuid {
+        &self.owner
+    }
+
+    // owners and moderators may change the settings of the room
+    pub fn can_manage(&self, user: &Uuid) -> bool {
+        self.owner == *user || self.has_moderator(user)
+    }
+
+    pub fn set_topic(&mut self, topic: String) -> Result<(), RoomError> {
+        if topic.chars().count() > MAX_TOPIC_LENGTH || topic.chars().any(|c| c.is_control()) {
+            return Err(RoomError::InvalidTopic);
+        }
+        self.topic = topic;
+        self.touch();
+        Ok(())
+    }
+
+    pub fn rename(&mut self, name: String) -> Result<(), RoomError> {
+        if !is_valid_name(&name) {
+            return Err(RoomError::InvalidName);
+        }
+        self.name = name;
+        self.touch();
+        Ok(())
+    }
+
+    pub fn set_private(&mut self, private: bool) {
+        self.private = private;
+        self.touch();
+    }
+
+    pub fn is_owner(&self, user: &Uuid) -> bool {
+        self.owner == *user
+    }
+
+    // the new owner is no moderator any more, owners manage the room anyway
+    pub fn set_owner(&mut self, owner: Uuid) {
+        self.owner = owner;
+        remove_ref(&mut self.moderators, &owner);
+        self.touch();
+    }
+
+    // the longest-standing moderator becomes owner, nobody if there is none
+    pub fn pass_ownership(&mut self) -> Option<Uuid> {
+        let owner = self.owner;
+        let successor = self.moderators
+            .iter()
+            .cloned()
+            .find(|moderator| *moderator != owner);
+        self.set_owner(successor.unwrap_or_else(Uuid::nil));
+        successor
+    }
+
+    pub fn set_hidden(&mut self, hidden: bool) {
+        self.hidden = hidden;
+        self.touch();
+    }
+
+    fn touch(&mut self) {
+        self.updated_at = Some(Local::now());
+    }
+
+    pub fn settings(&self) -> RoomSettings {
+        RoomSettings {
+            id: self.id,
+            name: self.name.clone(),
+            topic: self.topic.clone(),
+            private: self.private,
+            hidden: self.hidden,
+            owner: self.owner,
+            moderators: self.moderators.clone(),
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    let length = name.chars().count();
+    length > 0 && length <= MAX_NAME_LENGTH && name.trim() == name
+        && !name.chars().any(|c| c.is_control())
 }
 
 fn remove_ref(list: &mut Vec<Uuid>, element: &Uuid) -> bool {
@@ -163,6 +334,18 @@ fn test_room_serialize() {
     assert!(true);
 }
 
+#[test]
+fn test_settings_changes() {
+    let mut room = Room::new("Testroom".to_string(), Uuid::new_v4());
+    let before = room.settings();
+    assert!(room.settings().changes(&before).is_empty());
+
+    room.set_topic("Testing".to_string()).unwrap();
+    room.set_hidden(true);
+    room.add_moderator(Uuid::new_v4());
+    assert_eq!(vec!["topic", "hidden"], room.settings().changes(&before));
+}
+
 /*
 #[derive(Debug,Clone,Serialize,Deserialize)]
 pub struct RoomDTO{
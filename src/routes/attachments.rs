@@ -0,0 +1,108 @@
+// Attachment upload and download endpoints.
+//
+// Uploads take the raw file as request body, the room and file name are
+// query parameters. Downloads are only served to members of the room the
+// file was posted in.
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{error, AsyncResponder, Error, FutureResponse, HttpMessage, HttpRequest,
+                HttpResponse};
+use actors::chatserver;
+use actors::websocket::WsChatSessionState;
+use attachments::upload::sanitize_filename;
+use attachments::AttachmentError;
+use bytes::Bytes;
+use futures::{future, Future};
+use routes::auth::authenticate;
+use uuid::Uuid;
+
+fn attachment_error(err: AttachmentError) -> Error {
+    match err {
+        AttachmentError::UnknownRoom => error::ErrorNotFound("unknown room"),
+        AttachmentError::UnknownAttachment => error::ErrorNotFound("unknown attachment"),
+        AttachmentError::NotMember => error::ErrorForbidden("not a member of the room"),
+        AttachmentError::RateLimited => {
+            error::InternalError::new("rate limited", StatusCode::TOO_MANY_REQUESTS).into()
+        }
+        AttachmentError::Muted => error::ErrorForbidden("muted in the room"),
+    }
+}
+
+// POST /attachments?room=<room>&filename=<name>
+pub fn upload(req: HttpRequest<WsChatSessionState>) -> FutureResponse<HttpResponse> {
+    let claims = match authenticate(&req) {
+        Ok(claims) => claims,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let room = req.query()
+        .get("room")
+        .map(|room| room.to_string())
+        .unwrap_or_else(|| req.state().config.default_room.clone());
+    let filename = sanitize_filename(&req.query()
+        .get("filename")
+        .map(|name| name.to_string())
+        .unwrap_or_default());
+    let max_size = req.state().config.limits.max_upload_size;
+    let blobs = req.state().blobs.clone();
+    let addr = req.state().addr.clone();
+
+    req.body()
+        .limit(max_size)
+        .from_err()
+        .and_then(move |body: Bytes| blobs.put(&body).map_err(error::ErrorInternalServerError))
+        .and_then(move |blob| {
+            addr.send(chatserver::AttachmentUploaded {
+                session: None,
+                user_id: claims.user_id,
+                username: claims.username,
+                room: room,
+                upload_id: None,
+                filename: filename,
+                blob: blob,
+            }).from_err()
+        })
+        .and_then(|result| match result {
+            Ok(attachment) => Ok(HttpResponse::Created().json(attachment)),
+            Err(err) => Err(attachment_error(err)),
+        })
+        .responder()
+}
+
+// GET /attachments/{id}
+pub fn download(req: HttpRequest<WsChatSessionState>) -> FutureResponse<HttpResponse> {
+    let claims = match authenticate(&req) {
+        Ok(claims) => claims,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let id = match req.match_info().get("id").and_then(|id| id.parse::<Uuid>().ok()) {
+        Some(id) => id,
+        None => return Box::new(future::err(attachment_error(AttachmentError::UnknownAttachment))),
+    };
+    let blobs = req.state().blobs.clone();
+
+    req.state()
+        .addr
+        .send(chatserver::FetchAttachment {
+            user_id: claims.user_id,
+            id: id,
+        })
+        .from_err()
+        .and_then(move |result| {
+            let attachment = result.map_err(attachment_error)?;
+            let data = blobs
+                .get(&attachment.hash)
+                .map_err(error::ErrorInternalServerError)?;
+            Ok(HttpResponse::Ok()
+                .content_type(attachment.content_type.as_str())
+                .header("X-Content-Type-Options", "nosniff")
+                .header(
+                    header::CONTENT_DISPOSITION,
+                    format!(
+                        "attachment; filename=\"{}\"",
+                        attachment.filename.replace('"', "")
+                    ),
+                )
+                .body(data))
+        })
+        .responder()
+}
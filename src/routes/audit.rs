@@ -0,0 +1,84 @@
+// Audit log endpoint.
+//
+// GET /audit?room=<room>&actor=<username>&target=<username>&action=<action>&from=<time>&until=<time>&limit=<n>
+// Admins may query the whole log, moderators only the entries of their rooms
+// and have to name the room. Times are RFC 3339, newest entries first.
+
+use actix_web::{error, AsyncResponder, Error, FutureResponse, HttpRequest, HttpResponse};
+use actors::chatserver;
+use actors::websocket::WsChatSessionState;
+use audit::AuditQuery;
+use chrono::{DateTime, Local};
+use futures::{future, Future};
+use room::RoomError;
+use routes::auth::authenticate;
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+fn parse_time(value: Option<String>) -> Result<Option<DateTime<Local>>, Error> {
+    match value {
+        Some(value) => DateTime::parse_from_rfc3339(&value)
+            .map(|time| Some(time.with_timezone(&Local)))
+            .map_err(|_| error::ErrorBadRequest(format!("invalid time {}", value))),
+        None => Ok(None),
+    }
+}
+
+fn parse_query(req: &HttpRequest<WsChatSessionState>) -> Result<AuditQuery, Error> {
+    let param = |name: &str| req.query().get(name).map(|value| value.to_string());
+    let limit = match param("limit") {
+        Some(limit) => limit
+            .parse::<usize>()
+            .map_err(|_| error::ErrorBadRequest("invalid limit"))?,
+        None => DEFAULT_LIMIT,
+    };
+    let action = match param("action") {
+        Some(action) => Some(action
+            .parse()
+            .map_err(|_| error::ErrorBadRequest(format!("invalid action {}", action)))?),
+        None => None,
+    };
+
+    Ok(AuditQuery {
+        room: param("room"),
+        actor: param("actor"),
+        target: param("target"),
+        action: action,
+        from: parse_time(param("from"))?,
+        until: parse_time(param("until"))?,
+        limit: limit.min(MAX_LIMIT),
+        ..AuditQuery::default()
+    })
+}
+
+fn audit_error(err: RoomError) -> Error {
+    match err {
+        RoomError::UnknownRoom => error::ErrorNotFound("unknown room"),
+        _ => error::ErrorForbidden("not allowed to read the audit log"),
+    }
+}
+
+pub fn audit(req: HttpRequest<WsChatSessionState>) -> FutureResponse<HttpResponse> {
+    let claims = match authenticate(&req) {
+        Ok(claims) => claims,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let query = match parse_query(&req) {
+        Ok(query) => query,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    req.state()
+        .addr
+        .send(chatserver::QueryAudit {
+            user_id: claims.user_id,
+            query: query,
+        })
+        .from_err()
+        .and_then(|entries| match entries {
+            Ok(entries) => Ok(HttpResponse::Ok().json(entries)),
+            Err(err) => Err(audit_error(err)),
+        })
+        .responder()
+}
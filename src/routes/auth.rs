@@ -0,0 +1,137 @@
+// Login, token refresh and logout endpoints.
+//
+// Every other route validates the token with `authenticate`, the token is
+// taken from the `Authorization: Bearer <token>` header or the `token` query
+// parameter (browsers cannot set headers on websocket upgrades). Users and
+// addresses banned from the server are turned away with 403 Forbidden.
+
+use actix_web::http::header;
+use actix_web::{error, AsyncResponder, Error, FutureResponse, HttpMessage, HttpRequest,
+                HttpResponse};
+use actors::chatserver;
+use actors::websocket::WsChatSessionState;
+use auth::{Claims, TokenError};
+use chrono::Local;
+use futures::Future;
+use server_bans::ServerBan;
+use std::net::IpAddr;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub token: String,
+    pub user_id: Uuid,
+    pub expires_at: i64,
+}
+
+impl TokenResponse {
+    fn new(token: String, claims: &Claims) -> TokenResponse {
+        TokenResponse {
+            token,
+            user_id: claims.user_id,
+            expires_at: claims.expires_at,
+        }
+    }
+}
+
+pub fn token_from_request<S>(req: &HttpRequest<S>) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            if value.starts_with("Bearer ") {
+                Some(value[7..].trim().to_string())
+            } else {
+                None
+            }
+        })
+        .or_else(|| req.query().get("token").map(|token| token.to_string()))
+}
+
+// Validates the token of a request, fails with 401 Unauthorized
+pub fn authenticate(req: &HttpRequest<WsChatSessionState>) -> Result<Claims, Error> {
+    let token = token_from_request(req).ok_or_else(|| error::ErrorUnauthorized("missing token"))?;
+    let claims = req.state().tokens.verify(&token).map_err(token_error)?;
+    match server_ban(req, &claims.user_id) {
+        Some(ban) => Err(error::ErrorForbidden(ban_message(&ban))),
+        None => Ok(claims),
+    }
+}
+
+// address of the connected peer, a proxy in front of the server hides the client
+pub fn peer_ip<S>(req: &HttpRequest<S>) -> Option<IpAddr> {
+    req.peer_addr().map(|addr| addr.ip())
+}
+
+fn server_ban(req: &HttpRequest<WsChatSessionState>, user_id: &Uuid) -> Option<ServerBan> {
+    req.state()
+        .bans
+        .check(Some(user_id), peer_ip(req), Local::now())
+}
+
+fn ban_message(ban: &ServerBan) -> String {
+    let mut message = "banned from the server".to_string();
+    if let Some(expires_at) = ban.expires_at {
+        message.push_str(&format!(" until {}", expires_at.to_rfc3339()));
+    }
+    if let Some(ref reason) = ban.reason {
+        message.push_str(&format!(": {}", reason));
+    }
+    message
+}
+
+fn token_error(err: TokenError) -> Error {
+    error::ErrorUnauthorized(match err {
+        TokenError::Malformed => "malformed token",
+        TokenError::BadSignature => "invalid token signature",
+        TokenError::Expired => "token expired",
+        TokenError::Revoked => "token revoked",
+    })
+}
+
+// POST /auth/login
+pub fn login(req: HttpRequest<WsChatSessionState>) -> FutureResponse<HttpResponse> {
+    let state = req.clone();
+    req.json()
+        .from_err()
+        .and_then(move |login: LoginRequest| {
+            let addr = state.state().addr.clone();
+            addr.send(chatserver::Authenticate {
+                username: login.username,
+                password: login.password,
+            }).from_err()
+                .map(move |user| match user {
+                    Some(user) => match server_ban(&state, user.get_id()) {
+                        Some(ban) => HttpResponse::Forbidden().body(ban_message(&ban)),
+                        None => {
+                            let (token, claims) = state.state().tokens.issue(&user);
+                            HttpResponse::Ok().json(TokenResponse::new(token, &claims))
+                        }
+                    },
+                    None => HttpResponse::Unauthorized().finish(),
+                })
+        })
+        .responder()
+}
+
+// POST /auth/refresh
+pub fn refresh(req: HttpRequest<WsChatSessionState>) -> Result<HttpResponse, Error> {
+    // banned users get no fresh tokens
+    authenticate(&req)?;
+    let token = token_from_request(&req).ok_or_else(|| error::ErrorUnauthorized("missing token"))?;
+    let (token, claims) = req.state().tokens.refresh(&token).map_err(token_error)?;
+    Ok(HttpResponse::Ok().json(TokenResponse::new(token, &claims)))
+}
+
+// POST /auth/logout
+pub fn logout(req: HttpRequest<WsChatSessionState>) -> Result<HttpResponse, Error> {
+    let claims = authenticate(&req)?;
+    req.state().tokens.revoke(&claims);
+    Ok(HttpResponse::Ok().finish())
+}
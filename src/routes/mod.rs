@@ -0,0 +1,5 @@
+pub mod attachments;
+pub mod audit;
+pub mod auth;
+pub mod redirect;
+pub mod search;
@@ -0,0 +1,46 @@
+// Plain http endpoint sending every request to the https server.
+
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+
+pub fn https_redirect<S>(req: HttpRequest<S>, https_port: u16) -> HttpResponse {
+    let host = req.headers()
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(|host| strip_port(host).to_string())
+        .unwrap_or_else(|| "localhost".to_string());
+    let port = if https_port == 443 {
+        String::new()
+    } else {
+        format!(":{}", https_port)
+    };
+    let path = req.uri()
+        .path_and_query()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "/".to_string());
+
+    HttpResponse::MovedPermanently()
+        .header(header::LOCATION, format!("https://{}{}{}", host, port, path))
+        .finish()
+}
+
+// `[::1]:80` is `[::1]`, the colons of IPv6 addresses are kept
+fn strip_port(host: &str) -> &str {
+    let start = if host.starts_with('[') {
+        host.find(']').unwrap_or(host.len())
+    } else {
+        0
+    };
+    match host[start..].find(':') {
+        Some(end) => &host[..start + end],
+        None => host,
+    }
+}
+
+#[test]
+fn test_strip_port() {
+    assert_eq!("example.com", strip_port("example.com:80"));
+    assert_eq!("example.com", strip_port("example.com"));
+    assert_eq!("[::1]", strip_port("[::1]:80"));
+    assert_eq!("[::1]", strip_port("[::1]"));
+}
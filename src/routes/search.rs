@@ -0,0 +1,69 @@
+// Message search endpoint.
+//
+// GET /search?q=<words>&room=<room>&author=<username>&from=<time>&until=<time>&limit=<n>
+// Times are RFC 3339, only messages of rooms the caller is a member of are
+// returned, newest first.
+
+use actix_web::{error, AsyncResponder, Error, FutureResponse, HttpRequest, HttpResponse};
+use actors::chatserver;
+use actors::websocket::WsChatSessionState;
+use chrono::{DateTime, Local};
+use futures::{future, Future};
+use routes::auth::authenticate;
+use search::SearchQuery;
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+fn parse_time(value: Option<String>) -> Result<Option<DateTime<Local>>, Error> {
+    match value {
+        Some(value) => DateTime::parse_from_rfc3339(&value)
+            .map(|time| Some(time.with_timezone(&Local)))
+            .map_err(|_| error::ErrorBadRequest(format!("invalid time {}", value))),
+        None => Ok(None),
+    }
+}
+
+fn parse_query(req: &HttpRequest<WsChatSessionState>) -> Result<SearchQuery, Error> {
+    let param = |name: &str| req.query().get(name).map(|value| value.to_string());
+    let text = param("q").ok_or_else(|| error::ErrorBadRequest("missing query"))?;
+    let limit = match param("limit") {
+        Some(limit) => limit
+            .parse::<usize>()
+            .map_err(|_| error::ErrorBadRequest("invalid limit"))?,
+        None => DEFAULT_LIMIT,
+    };
+
+    Ok(SearchQuery {
+        text: text,
+        room: param("room"),
+        author: param("author"),
+        from: parse_time(param("from"))?,
+        until: parse_time(param("until"))?,
+        limit: limit.min(MAX_LIMIT),
+    })
+}
+
+pub fn search(req: HttpRequest<WsChatSessionState>) -> FutureResponse<HttpResponse> {
+    let claims = match authenticate(&req) {
+        Ok(claims) => claims,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let query = match parse_query(&req) {
+        Ok(query) => query,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    req.state()
+        .addr
+        .send(chatserver::Search {
+            user_id: claims.user_id,
+            query: query,
+        })
+        .from_err()
+        .and_then(|results| match results {
+            Ok(results) => Ok(HttpResponse::Ok().json(results)),
+            Err(_) => Err(error::ErrorInternalServerError("search failed")),
+        })
+        .responder()
+}
@@ -0,0 +1,212 @@
+// Bans and mutes of room members.
+//
+// The room keeps who is banned or muted, the sanctions keep why, by whom and
+// until when. They are persisted and applied to the rooms again on startup,
+// timed ones are lifted by the chat server once they expire. Rooms and users
+// get new ids on every start, stored sanctions find them again by name.
+
+use chrono::{DateTime, Duration, Local};
+use uuid::Uuid;
+
+// longest reason kept, in characters
+pub const MAX_REASON_LENGTH: usize = 250;
+
+// longest timed sanction, in seconds, longer ones are capped
+pub const MAX_DURATION: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SanctionKind {
+    Ban,
+    Mute,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sanction {
+    pub room: Uuid,
+    pub user: Uuid,
+    // set before the sanctions are stored
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub room_name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub username: String,
+    pub kind: SanctionKind,
+    pub reason: Option<String>,
+    pub issued_by: Uuid,
+    pub issued_at: DateTime<Local>,
+    // none until lifted by hand
+    pub expires_at: Option<DateTime<Local>>,
+}
+
+impl Sanction {
+    // `duration` in seconds
+    pub fn new(
+        room: Uuid,
+        user: Uuid,
+        kind: SanctionKind,
+        issued_by: Uuid,
+        duration: Option<u64>,
+        reason: Option<String>,
+        now: DateTime<Local>,
+    ) -> Sanction {
+        Sanction {
+            room,
+            user,
+            room_name: String::new(),
+            username: String::new(),
+            kind,
+            reason: normalize_reason(reason),
+            issued_by,
+            issued_at: now,
+            expires_at: duration
+                .map(|seconds| now + Duration::seconds(seconds.min(MAX_DURATION) as i64)),
+        }
+    }
+
+    pub fn is_expired(&self, now: DateTime<Local>) -> bool {
+        self.expires_at.map_or(false, |expires_at| expires_at <= now)
+    }
+}
+
+// trimmed and shortened to `MAX_REASON_LENGTH`, an empty reason counts as none
+pub fn normalize_reason(reason: Option<String>) -> Option<String> {
+    reason
+        .map(|reason| reason.trim().chars().take(MAX_REASON_LENGTH).collect::<String>())
+        .and_then(|reason| if reason.is_empty() { None } else { Some(reason) })
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Sanctions {
+    active: Vec<Sanction>,
+}
+
+impl Sanctions {
+    pub fn new() -> Sanctions {
+        Sanctions::default()
+    }
+
+    pub fn all(&self) -> &[Sanction] {
+        &self.active
+    }
+
+    // a new sanction replaces one of the same kind for the user in the room
+    pub fn add(&mut self, sanction: Sanction) {
+        self.remove(&sanction.room, &sanction.user, sanction.kind);
+        self.active.push(sanction);
+    }
+
+    pub fn get(&self, room: &Uuid, user: &Uuid, kind: SanctionKind) -> Option<&Sanction> {
+        self.active
+            .iter()
+            .find(|s| s.room == *room && s.user == *user && s.kind == kind)
+    }
+
+    pub fn remove(&mut self, room: &Uuid, user: &Uuid, kind: SanctionKind) -> Option<Sanction> {
+        let position = self.active
+            .iter()
+            .position(|s| s.room == *room && s.user == *user && s.kind == kind)?;
+        Some(self.active.remove(position))
+    }
+
+    // removes and answers the sanctions that are over
+    pub fn expire(&mut self, now: DateTime<Local>) -> Vec<Sanction> {
+        let (expired, active) = self.active
+            .drain(..)
+            .partition(|sanction| sanction.is_expired(now));
+        self.active = active;
+        expired
+    }
+
+    // records the names of the room and user of every sanction
+    pub fn set_names<F>(&mut self, mut names: F)
+    where
+        F: FnMut(&Uuid, &Uuid) -> Option<(String, String)>,
+    {
+        for sanction in &mut self.active {
+            if let Some((room_name, username)) = names(&sanction.room, &sanction.user) {
+                sanction.room_name = room_name;
+                sanction.username = username;
+            }
+        }
+    }
+
+    // moves the sanctions to the current ids of their room and user by name,
+    // drops those whose room or user is gone
+    pub fn rebind<F>(&mut self, mut ids: F)
+    where
+        F: FnMut(&str, &str) -> Option<(Uuid, Uuid)>,
+    {
+        self.active = self.active
+            .drain(..)
+            .filter_map(|mut sanction| {
+                let (room, user) = ids(&sanction.room_name, &sanction.username)?;
+                sanction.room = room;
+                sanction.user = user;
+                Some(sanction)
+            })
+            .collect();
+    }
+
+    pub fn remove_room(&mut self, room: &Uuid) -> bool {
+        let before = self.active.len();
+        self.active.retain(|sanction| sanction.room != *room);
+        self.active.len() != before
+    }
+}
+
+#[test]
+fn test_sanctions() {
+    let room = Uuid::new_v4();
+    let user = Uuid::new_v4();
+    let moderator = Uuid::new_v4();
+    let now = Local::now();
+    let mut sanctions = Sanctions::new();
+
+    let mute = Sanction::new(
+        room,
+        user,
+        SanctionKind::Mute,
+        moderator,
+        Some(60),
+        Some("  spam ".to_string()),
+        now,
+    );
+    assert_eq!(Some("spam".to_string()), mute.reason);
+    assert_eq!(Some(now + Duration::seconds(60)), mute.expires_at);
+    sanctions.add(mute);
+
+    let ban = Sanction::new(
+        room,
+        user,
+        SanctionKind::Ban,
+        moderator,
+        None,
+        Some("".to_string()),
+        now,
+    );
+    assert_eq!(None, ban.reason);
+    sanctions.add(ban.clone());
+
+    // a new mute replaces the old one
+    sanctions.add(Sanction::new(
+        room,
+        user,
+        SanctionKind::Mute,
+        moderator,
+        Some(10),
+        None,
+        now,
+    ));
+    assert_eq!(2, sanctions.all().len());
+
+    assert!(sanctions.expire(now + Duration::seconds(5)).is_empty());
+    let expired = sanctions.expire(now + Duration::seconds(30));
+    assert_eq!(1, expired.len());
+    assert_eq!(SanctionKind::Mute, expired[0].kind);
+    assert!(sanctions.get(&room, &user, SanctionKind::Mute).is_none());
+
+    // permanent until removed
+    assert!(sanctions.expire(now + Duration::days(1000)).is_empty());
+    assert_eq!(Some(ban), sanctions.remove(&room, &user, SanctionKind::Ban));
+    assert!(!sanctions.remove_room(&room));
+}
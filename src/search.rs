@@ -0,0 +1,283 @@
+// Full text search over chat messages.
+//
+// The inverted index maps every lower cased word to the messages containing
+// it. Messages are indexed as they are stored, edits replace and deletions
+// remove their entry. Only the documents are persisted, the postings are
+// rebuilt when the index is loaded.
+
+use chrono::{DateTime, Local};
+use message::ChatMessage;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+// An indexed message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Document {
+    pub id: Uuid,
+    pub room: String,
+    pub author: Uuid,
+    pub username: String,
+    pub text: String,
+    pub sent_at: DateTime<Local>,
+}
+
+// Matched word in the text, in characters, `end` is exclusive
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Highlight {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchResult {
+    pub message: Document,
+    pub highlights: Vec<Highlight>,
+}
+
+// All words of the text must match, the filters are optional
+#[derive(Debug, Clone, Default)]
+pub struct SearchQuery {
+    pub text: String,
+    pub room: Option<String>,
+    // username
+    pub author: Option<String>,
+    pub from: Option<DateTime<Local>>,
+    pub until: Option<DateTime<Local>>,
+    pub limit: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    documents: HashMap<Uuid, Document>,
+    postings: HashMap<String, HashSet<Uuid>>,
+}
+
+// lower cased words with their character range
+fn tokenize(text: &str) -> Vec<(usize, usize, String)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut word = String::new();
+
+    for (index, c) in text.chars().chain(Some(' ')).enumerate() {
+        if c.is_alphanumeric() {
+            start = start.or(Some(index));
+            word.extend(c.to_lowercase());
+        } else if let Some(begin) = start.take() {
+            tokens.push((begin, index, word.clone()));
+            word.clear();
+        }
+    }
+    tokens
+}
+
+impl SearchIndex {
+    pub fn new() -> SearchIndex {
+        SearchIndex::default()
+    }
+
+    pub fn from_documents(documents: Vec<Document>) -> SearchIndex {
+        let mut index = SearchIndex::new();
+        for document in documents {
+            index.insert(document);
+        }
+        index
+    }
+
+    // for persistence, in no particular order
+    pub fn documents(&self) -> Vec<&Document> {
+        self.documents.values().collect()
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    // adds the message or replaces its previous text
+    pub fn index(&mut self, message: &ChatMessage) {
+        self.remove(&message.id);
+        if message.deleted || message.text.is_empty() {
+            return;
+        }
+        self.insert(Document {
+            id: message.id,
+            room: message.room.clone(),
+            author: message.author,
+            username: message.username.clone(),
+            text: message.text.clone(),
+            sent_at: message.sent_at,
+        });
+    }
+
+    fn insert(&mut self, document: Document) {
+        for (_, _, word) in tokenize(&document.text) {
+            self.postings
+                .entry(word)
+                .or_insert_with(HashSet::new)
+                .insert(document.id);
+        }
+        self.documents.insert(document.id, document);
+    }
+
+    pub fn rename_room(&mut self, from: &str, to: &str) {
+        for document in self.documents.values_mut() {
+            if document.room == from {
+                document.room = to.to_string();
+            }
+        }
+    }
+
+    // false if no message of the room was indexed
+    pub fn remove_room(&mut self, room: &str) -> bool {
+        let ids: Vec<Uuid> = self.documents
+            .values()
+            .filter(|document| document.room == room)
+            .map(|document| document.id)
+            .collect();
+        for id in &ids {
+            self.remove(id);
+        }
+        !ids.is_empty()
+    }
+
+    pub fn remove(&mut self, id: &Uuid) {
+        let document = match self.documents.remove(id) {
+            Some(document) => document,
+            None => return,
+        };
+        for (_, _, word) in tokenize(&document.text) {
+            let empty = match self.postings.get_mut(&word) {
+                Some(ids) => {
+                    ids.remove(id);
+                    ids.is_empty()
+                }
+                None => false,
+            };
+            if empty {
+                self.postings.remove(&word);
+            }
+        }
+    }
+
+    // newest matches first, only from rooms `can_read` accepts
+    pub fn search<F>(&self, query: &SearchQuery, can_read: F) -> Vec<SearchResult>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let words: HashSet<String> = tokenize(&query.text)
+            .into_iter()
+            .map(|(_, _, word)| word)
+            .collect();
+        if words.is_empty() {
+            return Vec::new();
+        }
+
+        // start with the rarest word, every other word must match as well
+        let mut postings = Vec::new();
+        for word in &words {
+            match self.postings.get(word) {
+                Some(ids) => postings.push(ids),
+                None => return Vec::new(),
+            }
+        }
+        postings.sort_by_key(|ids| ids.len());
+        let (first, rest) = postings.split_first().unwrap();
+
+        let mut documents: Vec<&Document> = first
+            .iter()
+            .filter(|id| rest.iter().all(|ids| ids.contains(id)))
+            .filter_map(|id| self.documents.get(id))
+            .filter(|document| {
+                query
+                    .room
+                    .as_ref()
+                    .map_or(true, |room| document.room == *room)
+                    && query.author.as_ref().map_or(true, |author| {
+                        document.username.to_lowercase() == author.to_lowercase()
+                    })
+                    && query.from.map_or(true, |from| document.sent_at >= from)
+                    && query.until.map_or(true, |until| document.sent_at <= until)
+                    && can_read(&document.room)
+            })
+            .collect();
+        documents.sort_by(|a, b| b.sent_at.cmp(&a.sent_at));
+        documents.truncate(query.limit);
+
+        documents
+            .into_iter()
+            .map(|document| SearchResult {
+                highlights: tokenize(&document.text)
+                    .into_iter()
+                    .filter(|&(_, _, ref word)| words.contains(word))
+                    .map(|(start, end, _)| Highlight { start, end })
+                    .collect(),
+                message: document.clone(),
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_search() {
+    let alice = Uuid::new_v4();
+    let bob = Uuid::new_v4();
+    let post = |room: &str, author: Uuid, username: &str, text: &str| {
+        ChatMessage::new(
+            room.to_string(),
+            author,
+            username.to_string(),
+            text.to_string(),
+        )
+    };
+    let query = |text: &str| SearchQuery {
+        text: text.to_string(),
+        limit: 10,
+        ..SearchQuery::default()
+    };
+
+    let mut index = SearchIndex::new();
+    let mut first = post("Main", alice, "alice", "Deploy the server today");
+    index.index(&first);
+    index.index(&post("Main", bob, "bob", "the SERVER is down"));
+    index.index(&post("Secret", bob, "bob", "server passwords"));
+
+    assert_eq!(3, index.search(&query("server"), |_| true).len());
+    assert_eq!(2, index.search(&query("server"), |room| room == "Main").len());
+    assert!(index.search(&query("server mars"), |_| true).is_empty());
+
+    let results = index.search(&query("THE server"), |_| true);
+    assert_eq!(2, results.len());
+    let deploy = results.iter().find(|r| r.message.id == first.id).unwrap();
+    assert_eq!(
+        vec![Highlight { start: 7, end: 10 }, Highlight { start: 11, end: 17 }],
+        deploy.highlights
+    );
+
+    let mut by_bob = query("server");
+    by_bob.author = Some("Bob".to_string());
+    by_bob.room = Some("Main".to_string());
+    assert_eq!(1, index.search(&by_bob, |_| true).len());
+
+    let mut later = query("server");
+    later.from = Some(Local::now() + ::chrono::Duration::hours(1));
+    assert!(index.search(&later, |_| true).is_empty());
+
+    // edits replace, deletions remove the entry
+    first.text = "Deploy tomorrow".to_string();
+    index.index(&first);
+    assert_eq!(1, index.search(&query("deploy"), |_| true).len());
+    assert_eq!(2, index.search(&query("server"), |_| true).len());
+    first.deleted = true;
+    index.index(&first);
+    assert!(index.search(&query("deploy"), |_| true).is_empty());
+
+    let restored = SearchIndex::from_documents(index.documents().into_iter().cloned().collect());
+    assert_eq!(2, restored.len());
+    assert_eq!(2, restored.search(&query("server"), |_| true).len());
+
+    // deleted rooms leave nothing behind
+    assert!(index.remove_room("Secret"));
+    assert!(!index.remove_room("Secret"));
+    assert_eq!(1, index.search(&query("server"), |_| true).len());
+    assert!(index.search(&query("passwords"), |_| true).is_empty());
+}
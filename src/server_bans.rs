@@ -0,0 +1,182 @@
+// Server wide bans issued by admins.
+//
+// A banned user is disconnected from every room and can neither log in nor
+// open a websocket until the ban expires or is lifted. A ban may also block
+// the addresses the user was connected from. The chat server changes the list,
+// the http workers check it on every request, so it is shared like the
+// `TokenService`.
+
+use chrono::{DateTime, Duration, Local};
+use sanctions::{normalize_reason, MAX_DURATION};
+use std::net::IpAddr;
+use std::sync::RwLock;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerBan {
+    pub user: Uuid,
+    // set before the bans are stored, ids change on restart
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub username: String,
+    // blocked for everyone, not only for the banned user
+    pub ips: Vec<IpAddr>,
+    pub reason: Option<String>,
+    pub issued_by: Uuid,
+    pub issued_at: DateTime<Local>,
+    // none until lifted by hand
+    pub expires_at: Option<DateTime<Local>>,
+}
+
+impl ServerBan {
+    // `duration` in seconds
+    pub fn new(
+        user: Uuid,
+        ips: Vec<IpAddr>,
+        issued_by: Uuid,
+        duration: Option<u64>,
+        reason: Option<String>,
+        now: DateTime<Local>,
+    ) -> ServerBan {
+        ServerBan {
+            user,
+            username: String::new(),
+            ips,
+            reason: normalize_reason(reason),
+            issued_by,
+            issued_at: now,
+            expires_at: duration
+                .map(|seconds| now + Duration::seconds(seconds.min(MAX_DURATION) as i64)),
+        }
+    }
+
+    pub fn is_expired(&self, now: DateTime<Local>) -> bool {
+        self.expires_at.map_or(false, |expires_at| expires_at <= now)
+    }
+
+    pub fn applies_to(&self, user: Option<&Uuid>, ip: Option<IpAddr>) -> bool {
+        user.map_or(false, |user| self.user == *user)
+            || ip.map_or(false, |ip| self.ips.contains(&ip))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ServerBans {
+    bans: RwLock<Vec<ServerBan>>,
+}
+
+impl ServerBans {
+    pub fn new() -> ServerBans {
+        ServerBans::default()
+    }
+
+    // replaces all bans, used when they are loaded from the storage
+    pub fn replace(&self, bans: Vec<ServerBan>) {
+        *self.bans.write().unwrap() = bans;
+    }
+
+    pub fn all(&self) -> Vec<ServerBan> {
+        self.bans.read().unwrap().clone()
+    }
+
+    // a new ban replaces the one of the same user
+    pub fn add(&self, ban: ServerBan) {
+        let mut bans = self.bans.write().unwrap();
+        bans.retain(|existing| existing.user != ban.user);
+        bans.push(ban);
+    }
+
+    pub fn remove(&self, user: &Uuid) -> Option<ServerBan> {
+        let mut bans = self.bans.write().unwrap();
+        let position = bans.iter().position(|ban| ban.user == *user)?;
+        Some(bans.remove(position))
+    }
+
+    pub fn set_names<F>(&self, mut names: F)
+    where
+        F: FnMut(&Uuid) -> Option<String>,
+    {
+        for ban in self.bans.write().unwrap().iter_mut() {
+            if let Some(username) = names(&ban.user) {
+                ban.username = username;
+            }
+        }
+    }
+
+    // moves the bans to the current ids of their users by name, drops those
+    // whose user is gone
+    pub fn rebind<F>(&self, mut ids: F)
+    where
+        F: FnMut(&str) -> Option<Uuid>,
+    {
+        let mut bans = self.bans.write().unwrap();
+        let rebound = bans.drain(..)
+            .filter_map(|mut ban| {
+                ban.user = ids(&ban.username)?;
+                Some(ban)
+            })
+            .collect();
+        *bans = rebound;
+    }
+
+    // the ban that keeps the user or address out, expired ones are ignored
+    pub fn check(
+        &self,
+        user: Option<&Uuid>,
+        ip: Option<IpAddr>,
+        now: DateTime<Local>,
+    ) -> Option<ServerBan> {
+        self.bans
+            .read()
+            .unwrap()
+            .iter()
+            .find(|ban| !ban.is_expired(now) && ban.applies_to(user, ip))
+            .cloned()
+    }
+
+    // removes and answers the bans that are over
+    pub fn expire(&self, now: DateTime<Local>) -> Vec<ServerBan> {
+        let mut bans = self.bans.write().unwrap();
+        let (expired, active) = bans.drain(..).partition(|ban| ban.is_expired(now));
+        *bans = active;
+        expired
+    }
+}
+
+#[test]
+fn test_server_bans() {
+    let user = Uuid::new_v4();
+    let other = Uuid::new_v4();
+    let admin = Uuid::new_v4();
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    let now = Local::now();
+    let bans = ServerBans::new();
+
+    bans.add(ServerBan::new(
+        user,
+        vec![ip],
+        admin,
+        Some(60),
+        Some(" spam ".to_string()),
+        now,
+    ));
+    let ban = bans.check(Some(&user), None, now).unwrap();
+    assert_eq!(Some("spam".to_string()), ban.reason);
+    // the address is blocked for other users too
+    assert_eq!(Some(ban.clone()), bans.check(Some(&other), Some(ip), now));
+    assert_eq!(None, bans.check(Some(&other), "10.0.0.2".parse().ok(), now));
+    assert_eq!(None, bans.check(None, None, now));
+
+    // expired bans no longer apply, even before they are removed
+    let later = now + Duration::seconds(120);
+    assert_eq!(None, bans.check(Some(&user), None, later));
+    assert_eq!(vec![ban], bans.expire(later));
+    assert!(bans.all().is_empty());
+
+    // a new ban replaces the old one
+    bans.add(ServerBan::new(user, vec![], admin, Some(60), None, now));
+    bans.add(ServerBan::new(user, vec![], admin, None, None, now));
+    assert_eq!(1, bans.all().len());
+    assert!(bans.expire(now + Duration::days(1000)).is_empty());
+    assert!(bans.remove(&user).is_some());
+    assert!(bans.remove(&user).is_none());
+}
@@ -0,0 +1,103 @@
+// Persistence of server state.
+//
+// The memory backend keeps nothing between restarts, the file backend writes
+// every document as `<key>.json` into the storage directory.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Memory,
+    File,
+}
+
+impl FromStr for StorageBackend {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<StorageBackend, ()> {
+        match value {
+            "memory" => Ok(StorageBackend::Memory),
+            "file" => Ok(StorageBackend::File),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Storage {
+    dir: Option<PathBuf>,
+}
+
+impl Storage {
+    pub fn new(backend: StorageBackend, path: &str) -> io::Result<Storage> {
+        match backend {
+            StorageBackend::Memory => Ok(Storage::memory()),
+            StorageBackend::File => {
+                fs::create_dir_all(path)?;
+                Ok(Storage {
+                    dir: Some(PathBuf::from(path)),
+                })
+            }
+        }
+    }
+
+    pub fn memory() -> Storage {
+        Storage { dir: None }
+    }
+
+    pub fn load<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let file = self.dir
+            .as_ref()
+            .and_then(|dir| File::open(dir.join(format!("{}.json", key))).ok())?;
+        match serde_json::from_reader(file) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                warn!("Cannot read stored {}: {}", key, err);
+                None
+            }
+        }
+    }
+
+    // writes to a temporary file first, a crash never leaves a half written document
+    pub fn store<T: Serialize>(&self, key: &str, value: &T) -> io::Result<()> {
+        if let Some(ref dir) = self.dir {
+            let target = dir.join(format!("{}.json", key));
+            let temp = dir.join(format!("{}.json.tmp", key));
+            serde_json::to_writer(File::create(&temp)?, value)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            fs::rename(temp, target)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_file_storage() {
+    use std::env;
+    use uuid::Uuid;
+
+    let dir = env::temp_dir().join(format!("alienchat-{}", Uuid::new_v4()));
+    let storage = Storage::new(StorageBackend::File, dir.to_str().unwrap()).unwrap();
+
+    assert_eq!(None, storage.load::<Vec<String>>("history"));
+    storage
+        .store("history", &vec!["hello".to_string()])
+        .unwrap();
+    assert_eq!(
+        Some(vec!["hello".to_string()]),
+        storage.load::<Vec<String>>("history")
+    );
+
+    let memory = Storage::memory();
+    memory.store("history", &vec!["hello".to_string()]).unwrap();
+    assert_eq!(None, memory.load::<Vec<String>>("history"));
+
+    fs::remove_dir_all(dir).unwrap();
+}
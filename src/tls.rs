@@ -0,0 +1,210 @@
+// TLS certificates for HTTPS and WSS.
+//
+// The acceptor is built once at startup. Reloaded certificates are swapped in
+// through the servername callback, so every handshake of a client sending SNI
+// picks up the current certificate without restarting the server.
+
+use openssl::error::ErrorStack;
+use openssl::ssl::{SniError, SslAcceptor, SslAcceptorBuilder, SslAlert, SslContext, SslFiletype,
+                   SslMethod, SslRef};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io(String, io::Error),
+    Ssl(ErrorStack),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TlsError::Io(ref path, ref err) => write!(f, "Cannot read {}: {}", path, err),
+            TlsError::Ssl(ref err) => write!(f, "Invalid certificate or key: {}", err),
+        }
+    }
+}
+
+impl From<ErrorStack> for TlsError {
+    fn from(err: ErrorStack) -> TlsError {
+        TlsError::Ssl(err)
+    }
+}
+
+pub struct CertificateStore {
+    cert_path: String,
+    key_path: String,
+    context: RwLock<SslContext>,
+    // modification time of the loaded files
+    modified: Mutex<SystemTime>,
+}
+
+impl CertificateStore {
+    pub fn open(cert_path: &str, key_path: &str) -> Result<Arc<CertificateStore>, TlsError> {
+        let modified = last_modified(cert_path, key_path)?;
+        let context = build_context(cert_path, key_path)?;
+        Ok(Arc::new(CertificateStore {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            context: RwLock::new(context),
+            modified: Mutex::new(modified),
+        }))
+    }
+
+    // acceptor for the http server, serves the current certificate of the store
+    pub fn acceptor(store: &Arc<CertificateStore>) -> Result<SslAcceptorBuilder, TlsError> {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        builder.set_private_key_file(&store.key_path, SslFiletype::PEM)?;
+        builder.set_certificate_chain_file(&store.cert_path)?;
+        builder.check_private_key()?;
+
+        let store = store.clone();
+        builder.set_servername_callback(move |ssl: &mut SslRef, _: &mut SslAlert| {
+            let context = store.context.read().unwrap();
+            ssl.set_ssl_context(&context)
+                .map_err(|_| SniError::ALERT_FATAL)
+        });
+        Ok(builder)
+    }
+
+    // reloads certificate and key if one of the files changed, true if reloaded
+    pub fn reload_if_changed(&self) -> Result<bool, TlsError> {
+        let modified = last_modified(&self.cert_path, &self.key_path)?;
+        if *self.modified.lock().unwrap() == modified {
+            return Ok(false);
+        }
+        self.reload()?;
+        *self.modified.lock().unwrap() = modified;
+        Ok(true)
+    }
+
+    // a broken certificate or key keeps the previous one in place
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let context = build_context(&self.cert_path, &self.key_path)?;
+        *self.context.write().unwrap() = context;
+        Ok(())
+    }
+}
+
+fn build_context(cert_path: &str, key_path: &str) -> Result<SslContext, TlsError> {
+    let mut builder = SslContext::builder(SslMethod::tls())?;
+    builder.set_private_key_file(key_path, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(cert_path)?;
+    builder.check_private_key()?;
+    Ok(builder.build())
+}
+
+fn last_modified(cert_path: &str, key_path: &str) -> Result<SystemTime, TlsError> {
+    let modified = |path: &str| {
+        fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .map_err(|err| TlsError::Io(path.to_string(), err))
+    };
+    Ok(::std::cmp::max(modified(cert_path)?, modified(key_path)?))
+}
+
+#[cfg(test)]
+fn generate_certificate(dir: &::std::path::Path, common_name: &str) -> (String, String) {
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", common_name).unwrap();
+    let name = name.build();
+
+    let mut cert = X509Builder::new().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+        .unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    let cert = cert.build();
+
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    fs::write(&cert_path, cert.to_pem().unwrap()).unwrap();
+    fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    (
+        cert_path.to_str().unwrap().to_string(),
+        key_path.to_str().unwrap().to_string(),
+    )
+}
+
+#[test]
+fn test_self_signed_handshake_and_reload() {
+    use openssl::nid::Nid;
+    use openssl::ssl::{SslConnector, SslVerifyMode};
+    use std::env;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use uuid::Uuid;
+
+    let dir = env::temp_dir().join(format!("alienchat-{}", Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = generate_certificate(&dir, "first.localhost");
+
+    let store = CertificateStore::open(&cert_path, &key_path).unwrap();
+    let acceptor = CertificateStore::acceptor(&store).unwrap().build();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        for _ in 0..2 {
+            let stream = listener.accept().unwrap().0;
+            let mut stream = acceptor.accept(stream).unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+        }
+    });
+
+    // connects and answers the common name of the served certificate
+    let connect = || {
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut stream = connector
+            .build()
+            .connect("localhost", stream)
+            .unwrap();
+        stream.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(b"ping", &buf);
+
+        let cert = stream.ssl().peer_certificate().unwrap();
+        let common_name = cert.subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .unwrap()
+            .data()
+            .as_utf8()
+            .unwrap()
+            .to_string();
+        common_name
+    };
+
+    assert_eq!("first.localhost", connect());
+
+    generate_certificate(&dir, "second.localhost");
+    store.reload().unwrap();
+    assert_eq!("second.localhost", connect());
+
+    server.join().unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
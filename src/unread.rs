@@ -0,0 +1,151 @@
+// Read markers and unread counts.
+//
+// Every user has one marker per room, the id of the last message read. All
+// later messages of others are unread, thread replies only count when they
+// mention the user.
+
+use message::ChatMessage;
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnreadCount {
+    pub room: String,
+    pub unread: usize,
+    pub mentions: usize,
+    pub last_read: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReadMarkers {
+    // user -> room -> last read message
+    markers: HashMap<Uuid, HashMap<String, Uuid>>,
+}
+
+impl ReadMarkers {
+    pub fn new() -> ReadMarkers {
+        ReadMarkers::default()
+    }
+
+    pub fn get(&self, user: &Uuid, room: &str) -> Option<Uuid> {
+        self.markers
+            .get(user)
+            .and_then(|rooms| rooms.get(room))
+            .cloned()
+    }
+
+    pub fn rename_room(&mut self, from: &str, to: &str) {
+        for rooms in self.markers.values_mut() {
+            if let Some(id) = rooms.remove(from) {
+                rooms.insert(to.to_string(), id);
+            }
+        }
+    }
+
+    // false if nobody had read the room
+    pub fn remove_room(&mut self, room: &str) -> bool {
+        let mut removed = false;
+        for rooms in self.markers.values_mut() {
+            removed |= rooms.remove(room).is_some();
+        }
+        self.markers.retain(|_, rooms| !rooms.is_empty());
+        removed
+    }
+
+    // moves the marker forward to `id`, false if the message is unknown or
+    // not newer than the current marker
+    pub fn advance(
+        &mut self,
+        user: Uuid,
+        room: &str,
+        id: Uuid,
+        history: &VecDeque<ChatMessage>,
+    ) -> bool {
+        let position = match history.iter().position(|message| message.id == id) {
+            Some(position) => position,
+            None => return false,
+        };
+        let current = self.get(&user, room)
+            .and_then(|current| history.iter().position(|message| message.id == current));
+        if current.map_or(false, |current| current >= position) {
+            return false;
+        }
+        self.markers
+            .entry(user)
+            .or_insert_with(HashMap::new)
+            .insert(room.to_string(), id);
+        true
+    }
+
+    pub fn count(&self, user: &Uuid, room: &str, history: &VecDeque<ChatMessage>) -> UnreadCount {
+        let last_read = self.get(user, room);
+        // a marker dropped from the history is older than everything in it
+        let start = last_read
+            .and_then(|id| history.iter().position(|message| message.id == id))
+            .map_or(0, |position| position + 1);
+
+        let mut unread = 0;
+        let mut mentions = 0;
+        for message in history.iter().skip(start) {
+            if message.author == *user || message.deleted {
+                continue;
+            }
+            if !message.is_reply() {
+                unread += 1;
+            }
+            if message.mentions.contains(user) {
+                mentions += 1;
+            }
+        }
+        UnreadCount {
+            room: room.to_string(),
+            unread,
+            mentions,
+            last_read,
+        }
+    }
+}
+
+#[test]
+fn test_unread_counts() {
+    let user = Uuid::new_v4();
+    let other = Uuid::new_v4();
+    let post = |author: Uuid, text: &str| {
+        ChatMessage::new(
+            "Main".to_string(),
+            author,
+            "name".to_string(),
+            text.to_string(),
+        )
+    };
+
+    let mut history = VecDeque::new();
+    history.push_back(post(other, "one"));
+    history.push_back(post(user, "two"));
+    let mut mention = post(other, "@user three");
+    mention.mentions.push(user);
+    history.push_back(mention);
+    let mut reply = post(other, "@user reply");
+    reply.parent = Some(history[0].id);
+    reply.mentions.push(user);
+    history.push_back(reply);
+
+    let mut markers = ReadMarkers::new();
+    let count = markers.count(&user, "Main", &history);
+    assert_eq!((2, 2, None), (count.unread, count.mentions, count.last_read));
+
+    let first = history[0].id;
+    let third = history[2].id;
+    assert!(markers.advance(user, "Main", third, &history));
+    // markers never move back
+    assert!(!markers.advance(user, "Main", first, &history));
+    assert!(!markers.advance(user, "Main", Uuid::new_v4(), &history));
+
+    let count = markers.count(&user, "Main", &history);
+    assert_eq!((0, 1, Some(third)), (count.unread, count.mentions, count.last_read));
+    assert_eq!(1, markers.count(&other, "Main", &history).unread);
+
+    assert!(markers.remove_room("Main"));
+    assert!(!markers.remove_room("Main"));
+    assert_eq!(None, markers.get(&user, "Main"));
+}
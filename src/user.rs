@@ -1,5 +1,6 @@
 use chrono::DateTime;
 use chrono::Local;
+use openssl::memcmp;
 use role::Role;
 use serde_json;
 use uuid::Uuid;
@@ -74,6 +75,23 @@ impl User {
     pub fn get_id(&self) -> &Uuid {
         &self.id
     }
+
+    pub fn get_username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn get_display_name(&self) -> &str {
+        &self.display_name
+    }
+
+    pub fn verify_password(&self, password: &str) -> bool {
+        memcmp_eq(self.password.as_bytes(), password.as_bytes())
+    }
+}
+
+// constant time comparison, `openssl::memcmp::eq` panics on different lengths
+fn memcmp_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && memcmp::eq(a, b)
 }
 
 impl PartialEq for User {
@@ -0,0 +1,113 @@
+// Validation and normalization of chat message text.
+
+use protocol::ErrorCode;
+use std::str::FromStr;
+
+// What happens to control characters other than newline and tab
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlCharacters {
+    Strip,
+    Reject,
+}
+
+impl FromStr for ControlCharacters {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<ControlCharacters, ()> {
+        match value {
+            "strip" => Ok(ControlCharacters::Strip),
+            "reject" => Ok(ControlCharacters::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    Empty,
+    TooLong(usize),
+    ControlCharacters,
+}
+
+impl ValidationError {
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            ValidationError::Empty => ErrorCode::EmptyMessage,
+            ValidationError::TooLong(_) => ErrorCode::MessageTooLong,
+            ValidationError::ControlCharacters => ErrorCode::InvalidCharacters,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match *self {
+            ValidationError::Empty => "Message is empty".to_string(),
+            ValidationError::TooLong(max) => {
+                format!("Message is longer than {} characters", max)
+            }
+            ValidationError::ControlCharacters => {
+                "Message contains control characters".to_string()
+            }
+        }
+    }
+}
+
+// trims the text, unifies line breaks and handles control characters,
+// `max_length` counts characters of the normalized text
+pub fn normalize_message(
+    text: &str,
+    max_length: usize,
+    control: ControlCharacters,
+) -> Result<String, ValidationError> {
+    let text = text.replace("\r\n", "\n").replace('\r', "\n");
+    let mut normalized = String::with_capacity(text.len());
+
+    for c in text.trim().chars() {
+        if c == '\n' || c == '\t' || !c.is_control() {
+            normalized.push(c);
+        } else if control == ControlCharacters::Reject {
+            return Err(ValidationError::ControlCharacters);
+        }
+    }
+
+    let normalized = normalized.trim();
+    if normalized.is_empty() {
+        return Err(ValidationError::Empty);
+    }
+    if normalized.chars().count() > max_length {
+        return Err(ValidationError::TooLong(max_length));
+    }
+    Ok(normalized.to_string())
+}
+
+#[test]
+fn test_normalize_message() {
+    assert_eq!(
+        Ok("hello\nworld".to_string()),
+        normalize_message("  hello\r\nworld \n", 100, ControlCharacters::Strip)
+    );
+    assert_eq!(
+        Ok("bell".to_string()),
+        normalize_message("be\u{7}ll", 100, ControlCharacters::Strip)
+    );
+    assert_eq!(
+        Err(ValidationError::ControlCharacters),
+        normalize_message("be\u{7}ll", 100, ControlCharacters::Reject)
+    );
+    assert_eq!(
+        Err(ValidationError::Empty),
+        normalize_message(" \t\n ", 100, ControlCharacters::Strip)
+    );
+    assert_eq!(
+        Err(ValidationError::Empty),
+        normalize_message("\u{0}", 100, ControlCharacters::Strip)
+    );
+    assert_eq!(
+        Err(ValidationError::TooLong(3)),
+        normalize_message("four", 3, ControlCharacters::Strip)
+    );
+    assert_eq!(
+        Ok("äöü".to_string()),
+        normalize_message("äöü", 3, ControlCharacters::Strip)
+    );
+}